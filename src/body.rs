@@ -1,3 +1,15 @@
+use std::{
+	fmt,
+	convert::{ TryFrom, TryInto },
+	sync::{ Arc, Mutex },
+	io::{
+		Read, Cursor,
+		Result as IoResult,
+		Error as IoError,
+	},
+};
+
+use crate::urlencoded;
 #[cfg(feature = "multipart")]
 use crate::multipart::Multipart;
 
@@ -13,12 +25,63 @@ pub enum Body {
 	Text(String),
 	/// Converted bytes
 	Bytes(Vec<u8>),
+	/// URL encoded form (name, value), in order
+	Form(Vec<(String, String)>),
 	/// Multipart
 	#[cfg(feature = "multipart")]
 	Multipart(Multipart),
 	/// JSON
 	#[cfg(feature = "json")]
 	Json(Value),
+	/// Read as it's sent, without being held in memory (see `Body::from_reader`)
+	Reader(BodyReader),
+}
+
+/// Reader supplying a body as it's sent. It can only be read once.
+///
+/// Clones share the reader, whichever is sent (or converted) first gets it.
+#[derive(Clone)]
+pub struct BodyReader(Arc<Mutex<Option<Box<dyn Read + Send>>>>);
+
+impl BodyReader {
+	pub(crate) fn new<R: Read + Send + 'static>(reader: R) -> BodyReader {
+		BodyReader(Arc::new(Mutex::new(Some(Box::new(reader)))))
+	}
+
+	pub(crate) fn take(&self) -> IoResult<Box<dyn Read + Send>> {
+		self.0
+			.lock()
+			.unwrap()
+			.take()
+			.ok_or_else(|| IoError::other("Body reader was already read"))
+	}
+
+	/// Whether it was already read (or handed out to be).
+	#[cfg(feature = "client")]
+	pub(crate) fn is_read(&self) -> bool {
+		self.0.lock().unwrap().is_none()
+	}
+
+	/// Reads everything, for conversions that need the whole body. Fails if it was already read.
+	fn read_all(&self) -> IoResult<Vec<u8>> {
+		let mut bytes = vec![];
+
+		self.take()?.read_to_end(&mut bytes)?;
+
+		Ok(bytes)
+	}
+}
+
+impl fmt::Debug for BodyReader {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str("BodyReader")
+	}
+}
+
+impl PartialEq for BodyReader {
+	fn eq(&self, other: &BodyReader) -> bool {
+		Arc::ptr_eq(&self.0, &other.0)
+	}
 }
 
 impl From<&str> for Body {
@@ -39,6 +102,17 @@ impl From<&Vec<u8>> for Body {
 	}
 }
 
+impl From<&[(&str, &str)]> for Body {
+	fn from(pairs: &[(&str, &str)]) -> Body {
+		Body::Form(
+			pairs
+				.iter()
+				.map(|(key, value)| (key.to_string(), value.to_string()))
+				.collect()
+		)
+	}
+}
+
 #[cfg(feature = "multipart")]
 impl From<Multipart> for Body {
 	fn from(body: Multipart) -> Body {
@@ -53,76 +127,128 @@ impl From<Value> for Body {
 	}
 }
 
-impl Into<Vec<u8>> for Body {
-	fn into(self) -> Vec<u8> {
-		match self {
-			Body::Text(text) => text.as_bytes().to_vec(),
-			Body::Bytes(bytes) => bytes,
-			#[cfg(feature = "multipart")]
-			Body::Multipart(multipart) => multipart.into(),
-			#[cfg(feature = "json")]
-			Body::Json(value) => serde_json::to_vec(&value).expect("Bad JSON value"),
+impl TryFrom<Body> for Vec<u8> {
+	type Error = IoError;
+
+	fn try_from(body: Body) -> IoResult<Vec<u8>> {
+		match body {
+			Body::Text(text) => Ok(text.into_bytes()),
+			Body::Bytes(bytes) => Ok(bytes),
+			body => Vec::try_from(&body),
 		}
 	}
 }
 
-impl Into<Vec<u8>> for &Body {
-	fn into(self) -> Vec<u8> {
-		match self {
-			Body::Text(text) => text.as_bytes().to_vec(),
-			Body::Bytes(bytes) => bytes.to_owned(),
+impl TryFrom<&Body> for Vec<u8> {
+	type Error = IoError;
+
+	fn try_from(body: &Body) -> IoResult<Vec<u8>> {
+		match body {
+			Body::Text(text) => Ok(text.as_bytes().to_vec()),
+			Body::Bytes(bytes) => Ok(bytes.to_owned()),
+			Body::Form(pairs) => Ok(urlencoded::encode(pairs).into_bytes()),
 			#[cfg(feature = "multipart")]
-			Body::Multipart(multipart) => multipart.into(),
+			Body::Multipart(multipart) => multipart.read_all(),
 			#[cfg(feature = "json")]
-			Body::Json(value) => serde_json::to_vec(&value).expect("Bad JSON value"),
+			Body::Json(value) => Ok(serde_json::to_vec(&value)?),
+			Body::Reader(reader) => reader.read_all(),
 		}
 	}
 }
 
-impl Into<String> for &Body {
-	fn into(self) -> String {
-		match self {
-			Body::Text(text) => text.to_string(),
-			Body::Bytes(bytes) => String::from_utf8_lossy(&bytes).to_string(),
-			#[cfg(feature = "multipart")]
-			Body::Multipart(multipart) => {
-				let multipart_bytes: Vec<u8> = multipart.into();
+impl TryFrom<&Body> for String {
+	type Error = IoError;
 
-				String::from_utf8_lossy(&multipart_bytes).to_string()
-			},
+	fn try_from(body: &Body) -> IoResult<String> {
+		match body {
+			Body::Text(text) => Ok(text.to_string()),
+			Body::Form(pairs) => Ok(urlencoded::encode(pairs)),
 			#[cfg(feature = "json")]
-			Body::Json(value) => value.to_string(),
+			Body::Json(value) => Ok(value.to_string()),
+			body => Ok(String::from_utf8_lossy(&Vec::try_from(body)?).into_owned()),
 		}
 	}
 }
 
 #[cfg(feature = "json")]
-impl Into<Value> for &Body {
-	fn into(self) -> Value {
+impl TryFrom<&Body> for Value {
+	type Error = IoError;
+
+	fn try_from(body: &Body) -> IoResult<Value> {
+		match body {
+			Body::Text(text) => Ok(serde_json::from_str(text)?),
+			Body::Form(pairs) => Ok(serde_json::json!(pairs)),
+			Body::Json(value) => Ok(value.to_owned()),
+			body => Ok(serde_json::from_slice(&Vec::try_from(body)?)?),
+		}
+	}
+}
+
+impl Body {
+	/// Body read from `reader` as it's sent, so it never has to fit in memory.
+	/// Its size isn't known up front, so it's sent chunked.
+	pub fn from_reader<R: Read + Send + 'static>(reader: R) -> Body {
+		Body::Reader(BodyReader::new(reader))
+	}
+
+	/// Whether any of it was read as it was sent, so it can't be sent again.
+	#[cfg(feature = "client")]
+	pub(crate) fn is_consumed(&self) -> bool {
 		match self {
-			Body::Text(text) => serde_json::from_str(text).unwrap(),
-			Body::Bytes(bytes) => serde_json::from_slice(&bytes).unwrap(),
 			#[cfg(feature = "multipart")]
-			Body::Multipart(multipart) => {
-				let multipart_bytes: Vec<u8> = multipart.into();
+			Body::Multipart(multipart) => multipart.is_consumed(),
+			Body::Reader(reader) => reader.is_read(),
+			_ => false,
+		}
+	}
 
-				serde_json::from_slice(&multipart_bytes).unwrap()
-			},
+	/// Encoded size, if it's known before sending.
+	pub(crate) fn size(&self) -> Option<u64> {
+		match self {
+			Body::Text(text) => Some(text.len() as u64),
+			Body::Bytes(bytes) => Some(bytes.len() as u64),
+			Body::Form(pairs) => Some(urlencoded::encode(pairs).len() as u64),
+			#[cfg(feature = "multipart")]
+			Body::Multipart(multipart) => multipart.size(),
 			#[cfg(feature = "json")]
-			Body::Json(value) => value.to_owned(),
+			Body::Json(value) => Some(serde_json::to_vec(value).expect("Bad JSON value").len() as u64),
+			Body::Reader(_) => None,
 		}
 	}
-}
 
-impl Body {
+	/// Encoded body, without loading parts kept elsewhere into memory, or copying those that aren't.
+	pub(crate) fn reader(&self) -> IoResult<Box<dyn Read + Send + '_>> {
+		match self {
+			Body::Text(text) => Ok(Box::new(text.as_bytes())),
+			Body::Bytes(bytes) => Ok(Box::new(&bytes[..])),
+			#[cfg(feature = "multipart")]
+			Body::Multipart(multipart) => multipart.reader(),
+			Body::Reader(reader) => reader.take(),
+			body => Ok(Box::new(Cursor::new(body.bytes()?))),
+		}
+	}
+
+	/// Convert body into bytes. Parts kept elsewhere (files, readers) are read, which can fail.
+	pub fn bytes(&self) -> IoResult<Vec<u8>> {
+		self.try_into()
+	}
+
 	/// Convert body into text.
-	pub fn text(&self) -> String {
-		self.into()
+	pub fn text(&self) -> IoResult<String> {
+		self.try_into()
+	}
+
+	/// Convert body into URL encoded form pairs.
+	pub fn form(&self) -> IoResult<Vec<(String, String)>> {
+		match self {
+			Body::Form(pairs) => Ok(pairs.to_owned()),
+			body => Ok(urlencoded::parse(&body.text()?)),
+		}
 	}
 
 	/// Convert body into JSON.
 	#[cfg(feature = "json")]
-	pub fn json(&self) -> Value {
-		self.into()
+	pub fn json(&self) -> IoResult<Value> {
+		self.try_into()
 	}
 }
@@ -0,0 +1,242 @@
+use std::{
+	fmt,
+	time::{ Instant, Duration },
+	io::ErrorKind,
+	sync::{ Arc, Mutex },
+	collections::{ HashMap, VecDeque },
+};
+
+use crate::{
+	stream::Stream,
+	error::Error,
+};
+use super::request::ClientRequest;
+
+
+
+/// Idle connections keyed by (https, host).
+pub(crate) struct Pool {
+	idle: HashMap<(bool, String), VecDeque<(Stream, Instant)>>,
+	max_idle_per_host: usize,
+	max_idle: usize,
+	idle_timeout: Duration,
+}
+
+impl Pool {
+	fn new() -> Pool {
+		Pool {
+			idle: HashMap::new(),
+			max_idle_per_host: 8,
+			max_idle: 64,
+			idle_timeout: Duration::from_secs(30),
+		}
+	}
+
+	fn take(&mut self, https: bool, host: &str) -> Option<Stream> {
+		let key = (https, host.to_string());
+		let idle_timeout = self.idle_timeout;
+
+		let streams = self.idle.get_mut(&key)?;
+		let mut found = None;
+
+		// Most recently returned connections are the least likely to be stale.
+		while let Some((stream, since)) = streams.pop_back() {
+			if since.elapsed() < idle_timeout && is_open(&stream) {
+				found = Some(stream);
+
+				break;
+			}
+		}
+
+		if streams.is_empty() {
+			self.idle.remove(&key);
+		}
+
+		found
+	}
+
+	fn put(&mut self, https: bool, host: &str, stream: Stream) {
+		if self.max_idle == 0 || self.max_idle_per_host == 0 {
+			return;
+		}
+
+		// Timeouts were set for the request that's done, not whichever gets the connection next.
+		let tcp = stream.get_ref();
+
+		if tcp.set_read_timeout(None).is_err() || tcp.set_write_timeout(None).is_err() {
+			return;
+		}
+
+		let idle_timeout = self.idle_timeout;
+
+		// Drop expired connections for every host before counting.
+		for streams in self.idle.values_mut() {
+			streams.retain(|(_, since)| since.elapsed() < idle_timeout);
+		}
+		self.idle.retain(|_, streams| !streams.is_empty());
+
+		let streams = self.idle
+			.entry((https, host.to_string()))
+			.or_default();
+
+		if streams.len() >= self.max_idle_per_host {
+			streams.pop_front();
+		}
+
+		streams.push_back((stream, Instant::now()));
+
+		while self.len() > self.max_idle {
+			self.evict_oldest();
+		}
+	}
+
+	fn len(&self) -> usize {
+		self.idle.values().map(|streams| streams.len()).sum()
+	}
+
+	fn evict_oldest(&mut self) {
+		let oldest = self.idle
+			.iter()
+			.filter_map(|(key, streams)| streams.front().map(|(_, since)| (key.clone(), *since)))
+			.min_by_key(|(_, since)| *since)
+			.map(|(key, _)| key);
+
+		if let Some(key) = oldest {
+			if let Some(streams) = self.idle.get_mut(&key) {
+				streams.pop_front();
+
+				if streams.is_empty() {
+					self.idle.remove(&key);
+				}
+			}
+		}
+	}
+}
+
+/// An idle connection should have nothing to read. Data or EOF means the peer is done with it.
+fn is_open(stream: &Stream) -> bool {
+	let tcp = stream.get_ref();
+
+	if tcp.set_nonblocking(true).is_err() {
+		return false;
+	}
+
+	let open = match tcp.peek(&mut [0]) {
+		Err(ref e) => e.kind() == ErrorKind::WouldBlock,
+		Ok(_) => false,
+	};
+
+	tcp.set_nonblocking(false).is_ok() && open
+}
+
+
+
+/// Client that keeps connections alive and reuses them across requests (including redirects).
+///
+/// Clones share the same connection pool.
+#[derive(Clone)]
+pub struct Agent {
+	pool: Arc<Mutex<Pool>>,
+}
+
+impl fmt::Debug for Agent {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let pool = self.pool.lock().unwrap();
+
+		f.debug_struct("Agent")
+			.field("idle", &pool.len())
+			.field("max_idle_per_host", &pool.max_idle_per_host)
+			.field("max_idle", &pool.max_idle)
+			.field("idle_timeout", &pool.idle_timeout)
+			.finish()
+	}
+}
+
+impl Default for Agent {
+	fn default() -> Agent {
+		Agent::new()
+	}
+}
+
+impl Agent {
+	/// Creates new Agent with an empty connection pool.
+	pub fn new() -> Agent {
+		Agent {
+			pool: Arc::new(Mutex::new(Pool::new())),
+		}
+	}
+
+	/// Sets max idle connections kept per scheme and host (default 8).
+	pub fn set_max_idle_per_host(self, max: usize) -> Agent {
+		self.pool.lock().unwrap().max_idle_per_host = max;
+
+		self
+	}
+
+	/// Sets max idle connections kept across all hosts (default 64).
+	pub fn set_max_idle(self, max: usize) -> Agent {
+		self.pool.lock().unwrap().max_idle = max;
+
+		self
+	}
+
+	/// Sets how long (in seconds) a connection can sit idle before it's evicted (default 30).
+	pub fn set_idle_timeout(self, time: u64) -> Agent {
+		self.pool.lock().unwrap().idle_timeout = Duration::from_secs(time);
+
+		self
+	}
+
+	/// Create a Request with any method, using this Agent's connections.
+	pub fn request(&self, method: &str, url: &str) -> Result<ClientRequest, Error> {
+		Ok(ClientRequest::new(method, url)?.with_agent(self.clone()))
+	}
+
+	/// Create a GET Request.
+	pub fn get(&self, url: &str) -> Result<ClientRequest, Error> {
+		self.request("GET", url)
+	}
+
+	/// Create a POST Request.
+	pub fn post(&self, url: &str) -> Result<ClientRequest, Error> {
+		self.request("POST", url)
+	}
+
+	/// Create a PUT Request.
+	pub fn put(&self, url: &str) -> Result<ClientRequest, Error> {
+		self.request("PUT", url)
+	}
+
+	/// Create a PATCH Request.
+	pub fn patch(&self, url: &str) -> Result<ClientRequest, Error> {
+		self.request("PATCH", url)
+	}
+
+	/// Create a DELETE Request.
+	pub fn delete(&self, url: &str) -> Result<ClientRequest, Error> {
+		self.request("DELETE", url)
+	}
+
+	/// Create a HEAD Request.
+	pub fn head(&self, url: &str) -> Result<ClientRequest, Error> {
+		self.request("HEAD", url)
+	}
+
+	/// Create a OPTIONS Request.
+	pub fn options(&self, url: &str) -> Result<ClientRequest, Error> {
+		self.request("OPTIONS", url)
+	}
+
+	/// Number of idle connections currently pooled.
+	pub fn idle_connections(&self) -> usize {
+		self.pool.lock().unwrap().len()
+	}
+
+	pub(crate) fn checkout(&self, https: bool, host: &str) -> Option<Stream> {
+		self.pool.lock().unwrap().take(https, host)
+	}
+
+	pub(crate) fn checkin(&self, https: bool, host: &str, stream: Stream) {
+		self.pool.lock().unwrap().put(https, host, stream)
+	}
+}
@@ -2,8 +2,11 @@ use crate::error::Error;
 
 mod request;
 mod response;
+mod agent;
 #[cfg(test)] mod tests;
 
+pub use agent::Agent;
+
 
 
 /// Create a GET Request.
@@ -1,7 +1,6 @@
 use std::{
-	collections::HashMap,
 	time::{ Instant, Duration },
-	io::{ BufReader, Write },
+	io::{ BufReader, BufRead, Write, ErrorKind },
 };
 #[cfg(feature = "tls")]
 use std::sync::Arc;
@@ -14,12 +13,16 @@ use webpki::DNSNameRef;
 use webpki_roots::TLS_SERVER_ROOTS;
 
 use crate::{
-	stream::{ self, Stream, Compressed, Chunked },
-	error::Error,
+	stream::{ self, Stream, Compressed, Chunked, Coding, Deadline, HeadRules },
+	error::{ Error, Malformed },
 	body::Body,
+	headers::Headers,
 	StatusInfo,
 };
-use super::response::ClientResponse;
+use super::{
+	response::ClientResponse,
+	agent::Agent,
+};
 
 
 
@@ -49,7 +52,7 @@ struct Url {
 pub struct ClientRequest {
 	method: String,
 	url: Url,
-	headers: HashMap<String, String>,
+	headers: Headers,
 	body: Option<Body>,
 
 	max_redirects: usize,
@@ -57,9 +60,20 @@ pub struct ClientRequest {
 
 	compression_level: Option<u32>,
 	chunk_size: Option<usize>,
-
-	// deadline, reset
-	deadline: Option<(Instant, Instant)>,
+	streaming: bool,
+	decompression: bool,
+	head_rules: HeadRules,
+	max_response_size: Option<usize>,
+
+	connect_timeout: Option<Duration>,
+	first_byte_timeout: Option<Duration>,
+	read_timeout: Option<Duration>,
+	write_timeout: Option<Duration>,
+	timeout: Option<Duration>,
+	// Spans redirects, set when the first request is sent.
+	deadline: Option<Deadline>,
+
+	agent: Option<Agent>,
 }
 
 impl ClientRequest {
@@ -70,7 +84,7 @@ impl ClientRequest {
 		Ok(ClientRequest {
 			method: method.into(),
 			url,
-			headers: HashMap::new(),
+			headers: Headers::new(),
 			body: None,
 
 			max_redirects: 5,
@@ -78,14 +92,42 @@ impl ClientRequest {
 
 			compression_level: None,
 			chunk_size: None,
+			streaming: false,
+			decompression: true,
+			head_rules: HeadRules {
+				max_size: Some(64 * 1024),
+				..HeadRules::default()
+			},
+			max_response_size: None,
 
+			connect_timeout: None,
+			first_byte_timeout: None,
+			read_timeout: None,
+			write_timeout: None,
+			timeout: None,
 			deadline: None,
+
+			agent: None,
 		})
 	}
 
+	/// Reuse connections from (and return them to) an Agent's pool.
+	pub(crate) fn with_agent(mut self, agent: Agent) -> ClientRequest {
+		self.agent = Some(agent);
+
+		self
+	}
+
 	/// Sets/replaces individual header for request.
 	pub fn set_header<S: Into<String>>(mut self, key: S, value: S) -> ClientRequest {
-		self.headers.insert(key.into(), value.into());
+		self.headers.insert(key, value);
+
+		self
+	}
+
+	/// Adds another value for a header, keeping any it already has.
+	pub fn append_header<S: Into<String>>(mut self, key: S, value: S) -> ClientRequest {
+		self.headers.append(key, value);
 
 		self
 	}
@@ -96,13 +138,23 @@ impl ClientRequest {
 		let body = body.into();
 
 		// Set Content-Type header based on Body type.
-		match body {
+		match &body {
+			#[cfg(feature = "multipart")]
+			Body::Multipart(multipart) => {
+				self.headers.insert("Content-Type", format!("multipart/form-data; boundary={}", multipart.boundary()));
+			},
+			Body::Form(_) => {
+				self.headers.insert("Content-Type", "application/x-www-form-urlencoded");
+			},
 			#[cfg(feature = "json")]
 			Body::Json(_) => {
-				self.headers.insert("Content-Type".into(), "application/json;charset=UTF-8".into());
+				self.headers.insert("Content-Type", "application/json;charset=UTF-8");
+			},
+			Body::Reader(_) => {
+				self.headers.insert("Content-Type", "application/octet-stream");
 			},
 			_ => {
-				self.headers.insert("Content-Type".into(), "text/plain;".into());
+				self.headers.insert("Content-Type", "text/plain;");
 			},
 		}
 
@@ -112,9 +164,52 @@ impl ClientRequest {
 		self
 	}
 
-	/// Sets deadline for request.
-	pub fn set_deadline(mut self, time: u64) -> ClientRequest {
-		self.deadline = Some((Instant::now() + Duration::from_secs(time), Instant::now()));
+	/// Sets request body to a URL encoded form.
+	pub fn set_form(self, pairs: &[(&str, &str)]) -> ClientRequest {
+		self.set_body(pairs)
+	}
+
+	/// Sets deadline (in seconds) for the whole request, including redirects.
+	pub fn set_deadline(self, time: u64) -> ClientRequest {
+		self.set_timeout(Duration::from_secs(time))
+	}
+
+	/// Sets how long the whole request (including redirects) may take.
+	/// Fails with `Error::DeadlineExceeded` once it's up.
+	pub fn set_timeout(mut self, timeout: Duration) -> ClientRequest {
+		self.timeout = Some(timeout);
+
+		self
+	}
+
+	/// Sets how long establishing a connection may take.
+	/// Fails with `Error::ConnectTimeout`.
+	pub fn set_connect_timeout(mut self, timeout: Duration) -> ClientRequest {
+		self.connect_timeout = Some(timeout);
+
+		self
+	}
+
+	/// Sets how long to wait for the response to start once the request is sent.
+	/// Fails with `Error::FirstByteTimeout`.
+	pub fn set_first_byte_timeout(mut self, timeout: Duration) -> ClientRequest {
+		self.first_byte_timeout = Some(timeout);
+
+		self
+	}
+
+	/// Sets how long any single read may wait for more data.
+	/// Fails with `Error::ReadTimeout`.
+	pub fn set_read_timeout(mut self, timeout: Duration) -> ClientRequest {
+		self.read_timeout = Some(timeout);
+
+		self
+	}
+
+	/// Sets how long any single write may wait for the server to take more of the request.
+	/// Fails with `Error::WriteTimeout`.
+	pub fn set_write_timeout(mut self, timeout: Duration) -> ClientRequest {
+		self.write_timeout = Some(timeout);
 
 		self
 	}
@@ -141,96 +236,86 @@ impl ClientRequest {
 		self
 	}
 
-	/// Sends request.
-	pub fn send(mut self) -> Result<ClientResponse, Error> {
-		self.url.host = ensure_ascii(self.url.host)?;
+	/// Leaves the response body on the connection, to be read through `ClientResponse::into_reader`
+	/// rather than buffered into `body`. The connection isn't returned to an Agent afterwards.
+	pub fn set_streaming(mut self, streaming: bool) -> ClientRequest {
+		self.streaming = streaming;
 
-		let tcp = stream::connect(&self.url.host, self.deadline)?;
+		self
+	}
 
-		let mut req_stream;
-		if self.url.https {
-			#[cfg(not(feature = "tls"))]
-			{ return Err(Error::TLSNotEnabled); }
+	/// Sets whether compressed responses are decoded (default true), in which case Content-Encoding and
+	/// Content-Length are dropped from their headers, as they describe the body as it was sent.
+	/// When off, bodies are left as sent. Accept-Encoding is still sent, unless it's set by hand.
+	pub fn set_decompression(mut self, decompression: bool) -> ClientRequest {
+		self.decompression = decompression;
 
-			#[cfg(feature = "tls")]
-			{
-				let mut name = self.url.host.clone();
+		self
+	}
 
-				// Ditch the port. Safe due to added port above.
-				name = name.split(':').next().unwrap().to_string();
+	/// Sets whether response lines, chunk framing included, may end with a bare LF, rather than CRLF (default false).
+	/// Responses breaking any other rule of RFC 7230 that could desync the connection fail with `Error::MalformedHead`.
+	pub fn set_allow_bare_lf(mut self, allow: bool) -> ClientRequest {
+		self.head_rules.bare_lf = allow;
 
-				// Safe unwrap due to ASCII check above.
-				let name = DNSNameRef::try_from_ascii_str(&name).unwrap();
+		self
+	}
 
-				req_stream = Stream::HttpsClient(BufReader::new(Box::new(StreamOwned::new(ClientSession::new(&TLS_CONFIG, name), tcp))));
-			}
-		} else {
-			req_stream = Stream::Http(BufReader::new(tcp));
-		}
+	/// Sets max size (in bytes) of a response body, both as sent and once decompressed (unlimited by default).
+	/// Reading stops with `Error::ResponseTooLarge` as soon as either grows past it, so a small compressed body
+	/// can't expand to fill memory. Streamed responses are left to their reader.
+	pub fn set_max_response_size(mut self, size: usize) -> ClientRequest {
+		self.max_response_size = Some(size);
 
-		let req = gen_head(&self)?;
+		self
+	}
 
-		// Write head to stream. Clear req.
-		if let Some((line, _)) = &mut self.deadline {
-			let now = Instant::now();
+	/// Sets max size (in bytes) of a response head, from its status line to the empty line closing it (default 64 KiB).
+	/// Larger ones fail with `Error::HeadersTooLarge`. Single lines are also capped at 8 KiB.
+	pub fn set_max_header_size(mut self, size: usize) -> ClientRequest {
+		self.head_rules.max_size = Some(size);
 
-			req_stream
-				.get_ref()
-				.set_write_timeout(Some(*line - now))?;
+		self
+	}
 
-			self.deadline = Some((*line, Instant::now()));
-		}
+	/// Sends request.
+	pub fn send(mut self) -> Result<ClientResponse, Error> {
+		self.url.host = ensure_ascii(self.url.host)?;
 
-		// TODO: This isn't following deadlines. That needs to change.
-		req_stream.write_all(&req)?;
-		req_stream.flush()?;
+		// Redirects share the deadline of the original request.
+		if self.redirects.is_empty() {
+			self.deadline = Deadline::new(self.timeout.map(|timeout| Instant::now() + timeout), self.read_timeout, self.write_timeout);
+		}
 
-		// Check for 100-Continue
-		if self.headers.get("Expect").is_some() {
-			let resp = ClientResponse::new(&mut req_stream, &mut self.deadline)?;
+		let pooled = self.agent
+			.as_ref()
+			.and_then(|agent| agent.checkout(self.url.https, &self.url.host));
 
-			if let StatusInfo::Response(code, _) = resp.info.status {
-				if code != 100 {
-					return Ok(resp);
-				}
-			}
-		}
+		let (resp, req_stream) = match pooled {
+			Some(req_stream) => match self.exchange(req_stream) {
+				// The peer can close an idle connection right as it's picked up again.
+				// Bodies read as they were sent are gone, though.
+				Err(ref e) if is_stale(e) && is_idempotent(&self.method) && !self.body.as_ref().is_some_and(Body::is_consumed) => {
+					let req_stream = self.open()?;
 
-		if let Some(body) = &self.body {
-			let mut chunked = Chunked::new(
-				&mut req_stream,
-				self.chunk_size,
-				self.chunk_size.is_some()
-			);
+					self.exchange(req_stream)?
+				},
+				res => res?,
+			},
+			None => {
+				let req_stream = self.open()?;
 
-			let body: Vec<u8> = body.into();
+				self.exchange(req_stream)?
+			},
+		};
 
-			let mut compressed = if self.compression_level.is_some() {
-				Compressed::new(
-					&mut chunked,
-					self.compression_level,
-					Some(&body),
-					true
-				)
-			} else {
-				Compressed::new(
-					&mut chunked,
-					None,
-					Some(&body),
-					false
-				)
-			};
-
-			// Write body to stream.
-			stream::write_all_until(&mut compressed, &body, &mut self.deadline)?;
+		if let (Some(agent), Some(req_stream)) = (&self.agent, req_stream) {
+			agent.checkin(self.url.https, &self.url.host, req_stream);
 		}
 
-		// Get response from Stream.
-		let resp = ClientResponse::new(&mut req_stream, &mut self.deadline)?;
-
 		// Grab status code from response.
 		let mut status_code = 0;
-		if let StatusInfo::Response(code, _) = resp.info.status {
+		if let StatusInfo::Response(_, code, _) = resp.info.status {
 			status_code = code;
 		}
 
@@ -239,6 +324,10 @@ impl ClientRequest {
 			if self.redirects.len() == self.max_redirects {
 				Err(Error::MaxRedirectsHit)
 			} else if let Some(location) = resp.info.headers.get("Location") {
+				if self.body.as_ref().is_some_and(Body::is_consumed) {
+					return Err(Error::BodyAlreadySent);
+				}
+
 				self.redirects.push((self.url.https, self.url.host.clone(), self.url.resource));
 
 				let method = match self.method.as_str() {
@@ -262,6 +351,167 @@ impl ClientRequest {
 			Ok(resp)
 		}
 	}
+
+	/// Blocks until the response starts arriving, within the first byte timeout.
+	fn wait_for_response(&mut self, req_stream: &mut Stream) -> Result<(), Error> {
+		let ttfb = match self.first_byte_timeout {
+			Some(ttfb) => ttfb,
+			None => return Ok(()),
+		};
+
+		let timeout = match self.deadline.and_then(|deadline| deadline.remaining()) {
+			Some(remaining) if remaining == Duration::from_secs(0) => return Err(Error::DeadlineExceeded),
+			Some(remaining) => ttfb.min(remaining),
+			None => ttfb,
+		};
+
+		req_stream.get_ref().set_read_timeout(Some(timeout))?;
+
+		if let Err(e) = req_stream.fill_buf() {
+			return Err(if is_timeout(e.kind()) {
+				self.timeout_error(Error::FirstByteTimeout)
+			} else {
+				e.into()
+			});
+		}
+
+		// Hand the socket timeout back to the deadline.
+		match &mut self.deadline {
+			Some(deadline) => deadline.rearm(),
+			None => req_stream.get_ref().set_read_timeout(None)?,
+		}
+
+		Ok(())
+	}
+
+	/// Chunk size the body is sent with, if it's sent chunked.
+	/// Bodies of unknown (or compressed) size can't be sent any other way.
+	fn transfer_chunk_size(&self) -> Option<usize> {
+		match &self.body {
+			Some(body) if self.chunk_size.is_none() && (body.size().is_none() || self.compression_level.is_some()) => Some(stream::CHUNK_SIZE),
+			_ => self.chunk_size,
+		}
+	}
+
+	/// Blames the overall deadline over the phase timeout when it's what ran out.
+	fn timeout_error(&self, phase: Error) -> Error {
+		if self.deadline.is_some_and(|deadline| deadline.passed()) {
+			Error::DeadlineExceeded
+		} else {
+			phase
+		}
+	}
+
+	/// Reports socket timeouts while the request is being sent as such, rather than as stalled reads.
+	fn write_error(&self, err: Error) -> Error {
+		match err {
+			Error::Io(ref io) if is_timeout(io.kind()) => self.timeout_error(Error::WriteTimeout),
+			err => err,
+		}
+	}
+
+	/// Opens a new connection to the request's host.
+	fn open(&self) -> Result<Stream, Error> {
+		let remaining = self.deadline.and_then(|deadline| deadline.remaining());
+
+		if remaining == Some(Duration::from_secs(0)) {
+			return Err(Error::DeadlineExceeded);
+		}
+
+		let timeout = match (self.connect_timeout, remaining) {
+			(Some(timeout), Some(remaining)) => Some(timeout.min(remaining)),
+			(timeout, remaining) => timeout.or(remaining),
+		};
+
+		let tcp = stream::connect(&self.url.host, timeout)
+			.map_err(|e| match e {
+				Error::Io(ref io) if is_timeout(io.kind()) => self.timeout_error(Error::ConnectTimeout),
+				e => e,
+			})?;
+
+		if self.url.https {
+			#[cfg(not(feature = "tls"))]
+			{ Err(Error::TLSNotEnabled) }
+
+			#[cfg(feature = "tls")]
+			{
+				let mut name = self.url.host.clone();
+
+				// Ditch the port. Safe due to added port above.
+				name = name.split(':').next().unwrap().to_string();
+
+				// Safe unwrap due to ASCII check above.
+				let name = DNSNameRef::try_from_ascii_str(&name).unwrap();
+
+				Ok(Stream::HttpsClient(BufReader::new(Box::new(StreamOwned::new(ClientSession::new(&TLS_CONFIG, name), tcp)))))
+			}
+		} else {
+			Ok(Stream::Http(BufReader::new(tcp)))
+		}
+	}
+
+	/// Writes request to stream and reads the response.
+	/// Hands the stream back if it can carry another request.
+	fn exchange(&mut self, req_stream: Stream) -> Result<(ClientResponse, Option<Stream>), Error> {
+		self.try_exchange(req_stream)
+			.map_err(|e| match e {
+				Error::Io(ref io) if is_timeout(io.kind()) => self.timeout_error(Error::ReadTimeout),
+				e => e,
+			})
+	}
+
+	fn try_exchange(&mut self, mut req_stream: Stream) -> Result<(ClientResponse, Option<Stream>), Error> {
+		let req = gen_head(self)?;
+		let head = self.method == "HEAD";
+
+		// Write head to stream.
+		stream::write_head_until(&mut req_stream, &req, &mut self.deadline).map_err(|e| self.write_error(e))?;
+
+		// Check for 100-Continue
+		if self.headers.get("Expect").is_some() {
+			self.wait_for_response(&mut req_stream)?;
+
+			let resp = ClientResponse::new(&mut req_stream, head, self.decompression, self.head_rules, self.max_response_size, &mut self.deadline)?;
+
+			if let StatusInfo::Response(_, code, _) = resp.info.status {
+				if code != 100 {
+					// Body was never sent, the server can't tell where the next request starts.
+					return Ok((resp, None));
+				}
+			}
+		}
+
+		if let Some(body) = &self.body {
+			let chunk_size = self.transfer_chunk_size();
+
+			let chunked = Chunked::new(
+				&mut req_stream,
+				chunk_size,
+				chunk_size.is_some()
+			);
+			let mut compressed = Compressed::encoder(
+				chunked,
+				self.compression_level.map(|level| (Coding::Gzip, level))
+			)?;
+
+			// Stream body (compressing it on the way), so parts kept on disk never have to fit in memory.
+			stream::copy_until(&mut body.reader()?, &mut compressed, &mut self.deadline)
+				.and_then(|_| compressed.finish().map_err(Error::from))
+				.map_err(|e| self.write_error(e))?;
+		}
+
+		// Get response from Stream.
+		self.wait_for_response(&mut req_stream)?;
+
+		if self.streaming {
+			return ClientResponse::streamed(req_stream, head, self.decompression, self.head_rules, self.deadline);
+		}
+
+		let resp = ClientResponse::new(&mut req_stream, head, self.decompression, self.head_rules, self.max_response_size, &mut self.deadline)?;
+		let reusable = resp.keep_alive;
+
+		Ok((resp, Some(req_stream).filter(|_| reusable)))
+	}
 }
 
 
@@ -330,6 +580,25 @@ fn parse_url(url: String) -> Result<Url, Error> {
 	})
 }
 
+/// Whether a failure on a reused connection means the peer had already closed it.
+fn is_stale(err: &Error) -> bool {
+	match err {
+		Error::MalformedHead(Malformed::InvalidStartLine) => true,
+		Error::Io(e) => matches!(e.kind(), ErrorKind::UnexpectedEof | ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted | ErrorKind::BrokenPipe),
+		_ => false,
+	}
+}
+
+/// Socket timeouts surface as WouldBlock on some platforms.
+fn is_timeout(kind: ErrorKind) -> bool {
+	matches!(kind, ErrorKind::TimedOut | ErrorKind::WouldBlock)
+}
+
+/// Only idempotent requests are safe to send twice.
+fn is_idempotent(method: &str) -> bool {
+	matches!(method, "GET" | "HEAD" | "PUT" | "DELETE" | "OPTIONS" | "TRACE")
+}
+
 /// This just ensures host is ASCII.
 fn ensure_ascii(host: String) -> Result<String, Error> {
 	if host.is_ascii() {
@@ -375,6 +644,14 @@ fn gen_head(request: &ClientRequest) -> Result<Vec<u8>, Error> {
 		writeln!(head, "User-Agent: slimweb\r")?;
 	}
 
+	// Advertise whatever can be decoded.
+	#[cfg(feature = "compress")]
+	{
+		if !request.headers.contains_key("Accept-Encoding") {
+			writeln!(head, "Accept-Encoding: {}\r", stream::accept_encoding())?;
+		}
+	}
+
 	// Write custom headers prior to necessary headers (based on provided info).
 	for (k, v) in &request.headers {
 		writeln!(head, "{}: {}\r", k, v)?;
@@ -388,18 +665,21 @@ fn gen_head(request: &ClientRequest) -> Result<Vec<u8>, Error> {
 		}
 	}
 
-	if request.chunk_size.is_none() {
-		if let Some(body) = &request.body {
-			let body_vec: Vec<u8> = body.into();
-
-			writeln!(head, "Content-Length: {}\r", body_vec.len())?;
+	if request.transfer_chunk_size().is_none() {
+		if let Some(size) = request.body.as_ref().and_then(Body::size) {
+			writeln!(head, "Content-Length: {}\r", size)?;
 		}
 	} else {
 		writeln!(head, "Transfer-Encoding: chunked\r")?;
 	}
 
 	if request.compression_level.is_some() {
-		writeln!(head, "Content-Encoding: gzip\r")?;
+		writeln!(head, "Content-Encoding: {}\r", Coding::Gzip.name())?;
+	}
+
+	// Without an Agent the connection is dropped after this request, so let the server know.
+	if request.agent.is_none() && !request.headers.contains_key("Connection") {
+		writeln!(head, "Connection: close\r")?;
 	}
 
 	writeln!(head, "\r")?;
@@ -1,10 +1,17 @@
-use std::time::Instant;
+use std::{
+	fmt,
+	convert::TryInto,
+	io::{
+		Read, Cursor,
+		Result as IoResult,
+	},
+};
 
 #[cfg(feature = "json")]
 use serde_json::Value;
 
 use crate::{
-	stream::{ self, Stream, Compressed, Chunked },
+	stream::{ self, Stream, Compressed, Chunked, Coding, Deadline, HeadRules, Pending, Transport },
 	error::Error,
 	body::Body,
 	StatusInfo, GeneralInfo,
@@ -15,16 +22,88 @@ use crate::{
 pub struct ClientResponse {
 	pub info: GeneralInfo,
 	pub body: Body,
+
+	// Whether the connection is left ready for another request.
+	pub(crate) keep_alive: bool,
+	// Body still on the connection, for streamed responses.
+	pending: Option<ResponseReader>,
+}
+
+/// How the body following a response head is delimited.
+struct Framing {
+	has_body: bool,
+	chunked: bool,
+	codings: Vec<Coding>,
+	content_length: Option<usize>,
+	rules: HeadRules,
+}
+
+impl Framing {
+	/// Decoding layers over the body, chunked ones capped at `max` bytes as sent.
+	fn layers<T: Transport>(&self, stream: T, max: Option<usize>) -> IoResult<Compressed<T>> {
+		let chunked = match self.content_length {
+			Some(length) if !self.chunked => Chunked::sized(stream, length),
+			_ => Chunked::new(stream, None, self.chunked).set_limits(max, stream::MAX_CHUNK_EXTENSION).set_rules(self.rules),
+		};
+
+		Compressed::decoder(chunked, &self.codings)
+	}
 }
 
 impl ClientResponse {
 	/// Create a new ClientResponse using Stream (either http or https), and a deadline (if one is set).
-	pub(crate) fn new(stream: &mut Stream, deadline: &mut Option<(Instant, Instant)>) -> Result<ClientResponse, Error> {
-		let mut info = stream::process_lines(stream)?;
-		let (compressed, chunked) = stream::check_encodings(&info.headers);
+	/// Responses to HEAD requests never carry a body, whatever their headers say.
+	/// Content codings are undone if `decode` is set.
+	/// Bodies larger than `max` bytes, as sent or once decoded, are abandoned.
+	pub(crate) fn new(stream: &mut Stream, head: bool, decode: bool, rules: HeadRules, max: Option<usize>, deadline: &mut Option<Deadline>) -> Result<ClientResponse, Error> {
+		let (mut resp, framing) = ClientResponse::head(stream, head, decode, rules, deadline)?;
+
+		if framing.has_body {
+			// Declared length is checked before anything is read.
+			if max.is_some_and(|max| framing.content_length.is_some_and(|length| length > max)) {
+				return Err(Error::ResponseTooLarge);
+			}
+
+			let mut compressed = framing.layers(&mut *stream, max)?;
+			let mut body = vec![];
+
+			stream::read_to_end_until(&mut compressed, &mut body, max, deadline)
+				.and_then(|_| stream::drain_until(&mut compressed, deadline))
+				.map_err(|e| match e {
+					Error::PayloadTooLarge => Error::ResponseTooLarge,
+					e => e,
+				})?;
 
-		let mut chunked = Chunked::new(stream, None, chunked);
-		let mut compressed = Compressed::new(&mut chunked, None, None, compressed);
+			resp.body = body.into();
+		}
+
+		Ok(resp)
+	}
+
+	/// Like `new`, but leaves the body on the connection, to be read through `into_reader`.
+	/// Hands the stream back if there's no body, and it can carry another request.
+	pub(crate) fn streamed(mut stream: Stream, head: bool, decode: bool, rules: HeadRules, mut deadline: Option<Deadline>) -> Result<(ClientResponse, Option<Stream>), Error> {
+		let (mut resp, framing) = ClientResponse::head(&mut stream, head, decode, rules, &mut deadline)?;
+
+		if !framing.has_body {
+			let reusable = resp.keep_alive;
+
+			return Ok((resp, Some(stream).filter(|_| reusable)));
+		}
+
+		resp.keep_alive = false;
+		resp.pending = Some(ResponseReader(Source::Stream(Box::new(Pending::new(framing.layers(stream, None)?, deadline, None)))));
+
+		Ok((resp, None))
+	}
+
+	fn head(stream: &mut Stream, head: bool, decode: bool, rules: HeadRules, deadline: &mut Option<Deadline>) -> Result<(ClientResponse, Framing), Error> {
+		let mut info = stream::process_lines(stream, rules, deadline)?;
+		let (mut codings, chunked) = stream::check_encodings(&info.headers);
+
+		if !decode {
+			codings.clear();
+		}
 
 		let mut content_length: Option<usize> = None;
 
@@ -34,28 +113,88 @@ impl ClientResponse {
 			}
 		}
 
-		let mut body = vec![];
-		if let StatusInfo::Response(code, _) = info.status {
-			if code <= 300 || code >= 308 {
-				stream::read_to_end_until(&mut compressed, &mut body, content_length, deadline)?;
-			}
+		let has_body = match info.status {
+			StatusInfo::Response(_, code, _) => !head && code >= 200 && code != 204 && code != 304,
+			_ => false,
+		};
+
+		// Without a length or chunking, the body only ends when the server closes the connection.
+		let mut keep_alive = !has_body || chunked || content_length.is_some();
+
+		if info.headers.list("Connection").any(|s| s.eq_ignore_ascii_case("close")) {
+			keep_alive = false;
+		}
+
+		// HTTP/1.0 connections close after the response, unless the server says otherwise.
+		if matches!(&info.status, StatusInfo::Response(version, _, _) if version == "HTTP/1.0") && !info.headers.list("Connection").any(|s| s.eq_ignore_ascii_case("keep-alive")) {
+			keep_alive = false;
 		}
 
 		// Remove hop-by-hop.
 		info.headers.remove("Transfer-Encoding");
 
-		Ok(ClientResponse {
+		// These describe the body as it was sent, not as it's handed over.
+		if !codings.is_empty() {
+			info.headers.remove("Content-Encoding");
+			info.headers.remove("Content-Length");
+		}
+
+		let resp = ClientResponse {
 			info,
-			body: body.into(),
-		})
+			body: Body::Bytes(vec![]),
+
+			keep_alive,
+			pending: None,
+		};
+
+		Ok((resp, Framing {
+			has_body,
+			chunked,
+			codings,
+			content_length,
+			rules,
+		}))
+	}
+
+	/// Reader over the body. Streamed responses (see `ClientRequest::set_streaming`) are read
+	/// straight off the connection, within the request's deadline, others from `body`.
+	pub fn into_reader(self) -> IoResult<ResponseReader> {
+		match self.pending {
+			Some(pending) => Ok(pending),
+			None => Ok(ResponseReader(Source::Memory(Cursor::new(self.body.try_into()?)))),
+		}
 	}
 
 	/// Convert entire response into JSON.
 	#[cfg(feature = "json")]
-	pub fn json(&self) -> Value {
-		serde_json::json!({
+	pub fn json(&self) -> IoResult<Value> {
+		Ok(serde_json::json!({
 			"info": self.info.json(),
-			"body": self.body.json()
-		})
+			"body": self.body.json()?
+		}))
+	}
+}
+
+/// Reader over a response body, decoding its transfer and content codings as it goes.
+pub struct ResponseReader(Source);
+
+enum Source {
+	Memory(Cursor<Vec<u8>>),
+	// Boxing for size variation.
+	Stream(Box<Pending<Stream>>),
+}
+
+impl Read for ResponseReader {
+	fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+		match &mut self.0 {
+			Source::Memory(s) => s.read(buf),
+			Source::Stream(s) => s.read(buf),
+		}
+	}
+}
+
+impl fmt::Debug for ResponseReader {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str("ResponseReader")
 	}
 }
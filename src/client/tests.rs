@@ -1,9 +1,24 @@
-use std::io::Result as IoResult;
+use std::{
+	thread,
+	time::Duration,
+	net::TcpListener,
+	sync::{
+		Arc,
+		atomic::{ AtomicUsize, Ordering },
+	},
+	io::{
+		Read, BufRead, BufReader, Write,
+		ErrorKind,
+		Result as IoResult,
+	},
+};
 
 use crate::{
 	get, post, put, patch, delete,
-	StatusInfo,
+	Agent, Body, StatusInfo, Error, Malformed,
 };
+#[cfg(feature = "multipart")]
+use crate::Multipart;
 
 
 
@@ -14,7 +29,7 @@ fn test_https() -> IoResult<()> {
 		.send()?;
 
 	match resp.info.status {
-		StatusInfo::Response(code, _) => assert_eq!(code, 200),
+		StatusInfo::Response(_, code, _) => assert_eq!(code, 200),
 		_ => (),
 	}
 
@@ -27,7 +42,7 @@ fn test_get() -> IoResult<()> {
 		.send()?;
 
 	match resp.info.status {
-		StatusInfo::Response(code, _) => assert_eq!(code, 200),
+		StatusInfo::Response(_, code, _) => assert_eq!(code, 200),
 		_ => (),
 	}
 
@@ -40,7 +55,7 @@ fn test_post() -> IoResult<()> {
 		.send()?;
 
 	match resp.info.status {
-		StatusInfo::Response(code, _) => assert_eq!(code, 200),
+		StatusInfo::Response(_, code, _) => assert_eq!(code, 200),
 		_ => (),
 	}
 
@@ -53,7 +68,7 @@ fn test_put() -> IoResult<()> {
 		.send()?;
 
 	match resp.info.status {
-		StatusInfo::Response(code, _) => assert_eq!(code, 200),
+		StatusInfo::Response(_, code, _) => assert_eq!(code, 200),
 		_ => (),
 	}
 
@@ -66,7 +81,7 @@ fn test_patch() -> IoResult<()> {
 		.send()?;
 
 	match resp.info.status {
-		StatusInfo::Response(code, _) => assert_eq!(code, 200),
+		StatusInfo::Response(_, code, _) => assert_eq!(code, 200),
 		_ => (),
 	}
 
@@ -79,7 +94,7 @@ fn test_delete() -> IoResult<()> {
 		.send()?;
 
 	match resp.info.status {
-		StatusInfo::Response(code, _) => assert_eq!(code, 200),
+		StatusInfo::Response(_, code, _) => assert_eq!(code, 200),
 		_ => (),
 	}
 
@@ -94,7 +109,7 @@ fn test_set_header() -> IoResult<()> {
 
 	assert!(
 		resp.body
-			.text()
+			.text()?
 			.contains("\"Some-Random-Header\": \"test\"")
 	);
 
@@ -109,7 +124,7 @@ fn test_set_body() -> IoResult<()> {
 
 	assert!(
 		resp.body
-			.text()
+			.text()?
 			.contains("\"data\": \"Testing\"")
 	);
 
@@ -122,7 +137,7 @@ fn test_max_redirects() -> IoResult<()> {
 		.send()?;
 
 	match resp.info.status {
-		StatusInfo::Response(code, _) => assert_eq!(code, 200),
+		StatusInfo::Response(_, code, _) => assert_eq!(code, 200),
 		_ => (),
 	}
 
@@ -141,15 +156,994 @@ fn test_decompression() -> IoResult<()> {
 		.send()?;
 
 	match resp.info.status {
-		StatusInfo::Response(code, _) => assert_eq!(code, 200),
+		StatusInfo::Response(_, code, _) => assert_eq!(code, 200),
 		_ => (),
 	}
 
 	assert!(
 		resp.body
-			.text()
+			.text()?
 			.contains("\"gzipped\": true")
 	);
 
 	Ok(())
 }
+
+#[test]
+fn test_agent_reuse() -> IoResult<()> {
+	let listener = TcpListener::bind("localhost:8090")?;
+	let connections = Arc::new(AtomicUsize::new(0));
+	let accepted = connections.clone();
+
+	thread::spawn(move || {
+		for stream in listener.incoming() {
+			accepted.fetch_add(1, Ordering::SeqCst);
+
+			let mut reader = BufReader::new(stream.unwrap());
+			let mut line = String::new();
+
+			// Answer every request on this connection until the client hangs up.
+			loop {
+				line.clear();
+
+				if reader.read_line(&mut line).unwrap_or(0) == 0 {
+					break;
+				}
+
+				if line == "\r\n" {
+					reader.get_mut().write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok").unwrap();
+				}
+			}
+		}
+	});
+
+	let agent = Agent::new();
+
+	for _ in 0..3 {
+		let resp = agent.get("http://localhost:8090/")?.send()?;
+
+		assert_eq!(resp.body.text()?, "ok");
+	}
+
+	assert_eq!(connections.load(Ordering::SeqCst), 1);
+	assert_eq!(agent.idle_connections(), 1);
+
+	Ok(())
+}
+
+#[test]
+fn test_agent_http_10() -> IoResult<()> {
+	let listener = TcpListener::bind("localhost:8124")?;
+
+	thread::spawn(move || {
+		let responses: [&[u8]; 2] = [
+			b"HTTP/1.0 200 OK\r\nContent-Length: 2\r\n\r\nok",
+			b"HTTP/1.0 200 OK\r\nConnection: keep-alive\r\nContent-Length: 2\r\n\r\nok",
+		];
+
+		for response in responses.iter() {
+			let mut reader = BufReader::new(listener.accept().unwrap().0);
+			let mut line = String::new();
+
+			while line != "\r\n" {
+				line.clear();
+				reader.read_line(&mut line).unwrap();
+			}
+
+			reader.get_mut().write_all(response).unwrap();
+		}
+	});
+
+	let agent = Agent::new();
+
+	// HTTP/1.0 connections close after the response, so aren't pooled...
+	let resp = agent.get("http://localhost:8124/")?.send()?;
+
+	assert_eq!(resp.body.text()?, "ok");
+	assert_eq!(agent.idle_connections(), 0);
+
+	// ...unless the server asks for keep-alive.
+	let resp = agent.post("http://localhost:8124/")?.send()?;
+
+	assert_eq!(resp.body.text()?, "ok");
+	assert_eq!(agent.idle_connections(), 1);
+
+	Ok(())
+}
+
+#[test]
+fn test_agent_reuse_timeouts() -> IoResult<()> {
+	let listener = TcpListener::bind("localhost:8117")?;
+
+	thread::spawn(move || {
+		for stream in listener.incoming() {
+			let mut reader = BufReader::new(stream.unwrap());
+			let mut line = String::new();
+			let mut served = 0;
+
+			loop {
+				line.clear();
+
+				if reader.read_line(&mut line).unwrap_or(0) == 0 {
+					break;
+				}
+
+				if line == "\r\n" {
+					// Second request is slower than the first one's timeout.
+					if served > 0 {
+						thread::sleep(Duration::from_millis(1500));
+					}
+
+					reader.get_mut().write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok").unwrap();
+					served += 1;
+				}
+			}
+		}
+	});
+
+	let agent = Agent::new();
+
+	let resp = agent.get("http://localhost:8117/")?.set_timeout(Duration::from_secs(1)).send()?;
+
+	assert_eq!(resp.body.text()?, "ok");
+
+	// Same connection, without the first request's timeout.
+	let resp = agent.get("http://localhost:8117/")?.send()?;
+
+	assert_eq!(resp.body.text()?, "ok");
+	assert_eq!(agent.idle_connections(), 1);
+
+	Ok(())
+}
+
+#[test]
+fn test_timeouts() -> IoResult<()> {
+	let listener = TcpListener::bind("localhost:8091")?;
+
+	thread::spawn(move || {
+		for stream in listener.incoming() {
+			thread::spawn(move || {
+				let mut reader = BufReader::new(stream.unwrap());
+				let mut line = String::new();
+				let mut path = String::new();
+
+				loop {
+					line.clear();
+
+					if reader.read_line(&mut line).unwrap_or(0) == 0 {
+						return;
+					}
+
+					if path.is_empty() {
+						path = line.split(' ').nth(1).unwrap_or("").to_string();
+					} else if line == "\r\n" {
+						break;
+					}
+				}
+
+				// Start a response that never finishes.
+				if path == "/stall" {
+					reader.get_mut().write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nabc").unwrap();
+				}
+
+				thread::sleep(Duration::from_secs(2));
+			});
+		}
+	});
+
+	let resp = get("http://localhost:8091/silent")?
+		.set_first_byte_timeout(Duration::from_millis(200))
+		.send();
+	assert!(matches!(resp, Err(Error::FirstByteTimeout)));
+
+	let resp = get("http://localhost:8091/stall")?
+		.set_first_byte_timeout(Duration::from_millis(500))
+		.set_read_timeout(Duration::from_millis(200))
+		.send();
+	assert!(matches!(resp, Err(Error::ReadTimeout)));
+
+	let resp = get("http://localhost:8091/stall")?
+		.set_timeout(Duration::from_millis(300))
+		.send();
+	assert!(matches!(resp, Err(Error::DeadlineExceeded)));
+
+	let resp = get("http://localhost:8091/silent")?
+		.set_first_byte_timeout(Duration::from_secs(1))
+		.set_timeout(Duration::from_millis(300))
+		.send();
+	assert!(matches!(resp, Err(Error::DeadlineExceeded)));
+
+	// A server that never reads the body stalls the write, not a read.
+	let body = vec![0; 16 * 1024 * 1024];
+
+	let resp = post("http://localhost:8091/silent")?
+		.set_body(&body)
+		.set_write_timeout(Duration::from_millis(200))
+		.set_read_timeout(Duration::from_millis(100))
+		.send();
+	assert!(matches!(resp, Err(Error::WriteTimeout)), "{:?}", resp);
+
+	let resp = post("http://localhost:8091/silent")?
+		.set_body(&body)
+		.set_timeout(Duration::from_millis(300))
+		.send();
+	assert!(matches!(resp, Err(Error::DeadlineExceeded)), "{:?}", resp);
+
+	Ok(())
+}
+
+#[test]
+fn test_form() -> IoResult<()> {
+	let listener = TcpListener::bind("localhost:8092")?;
+
+	let server = thread::spawn(move || {
+		let mut reader = BufReader::new(listener.accept().unwrap().0);
+		let mut line = String::new();
+		let mut content_type = String::new();
+
+		loop {
+			line.clear();
+			reader.read_line(&mut line).unwrap();
+
+			if line.starts_with("Content-Type:") {
+				content_type = line.trim_end().to_string();
+			} else if line == "\r\n" {
+				break;
+			}
+		}
+
+		let mut body = vec![0; 30];
+		reader.read_exact(&mut body).unwrap();
+		let body = String::from_utf8(body).unwrap();
+		reader.get_mut().write_all(b"HTTP/1.1 204 No Content\r\n\r\n").unwrap();
+
+		(content_type, body)
+	});
+
+	post("http://localhost:8092/")?
+		.set_form(&[("name", "Jürgen M"), ("a&b", "1=2")])
+		.send()?;
+
+	let (content_type, body) = server.join().unwrap();
+
+	assert_eq!(content_type, "Content-Type: application/x-www-form-urlencoded");
+	assert_eq!(body, "name=J%C3%BCrgen+M&a%26b=1%3D2");
+
+	Ok(())
+}
+
+#[test]
+#[cfg(feature = "multipart")]
+fn test_multipart() -> IoResult<()> {
+	let listener = TcpListener::bind("localhost:8093")?;
+
+	let server = thread::spawn(move || {
+		let mut reader = BufReader::new(listener.accept().unwrap().0);
+		let mut line = String::new();
+		let mut content_type = String::new();
+		let mut length = 0;
+
+		loop {
+			line.clear();
+			reader.read_line(&mut line).unwrap();
+
+			if line.starts_with("Content-Type:") {
+				content_type = line.trim_end().to_string();
+			} else if let Some(value) = line.strip_prefix("Content-Length:") {
+				length = value.trim().parse().unwrap();
+			} else if line == "\r\n" {
+				break;
+			}
+		}
+
+		let mut body = vec![0; length];
+		reader.read_exact(&mut body).unwrap();
+		reader.get_mut().write_all(b"HTTP/1.1 204 No Content\r\n\r\n").unwrap();
+
+		(content_type, String::from_utf8(body).unwrap())
+	});
+
+	let multipart = Multipart::with_boundary("xyz")
+		.text_part("say \"hi\"", "line one\r\nline two");
+
+	post("http://localhost:8093/")?
+		.set_body(multipart)
+		.send()?;
+
+	let (content_type, body) = server.join().unwrap();
+
+	assert_eq!(content_type, "Content-Type: multipart/form-data; boundary=xyz");
+	assert_eq!(body, "--xyz\r\nContent-Disposition: form-data; name=\"say %22hi%22\"\r\n\r\nline one\r\nline two\r\n--xyz--\r\n");
+
+	// Random boundaries differ, and never appear inside a part.
+	let first = Multipart::new();
+	let second = Multipart::new().text_part("a", first.boundary());
+
+	assert_ne!(first.boundary(), second.boundary());
+	assert!(!second.boundary().is_empty());
+
+	// Non-ASCII filenames get an RFC 5987 filename* next to an ASCII fallback.
+	let path = std::env::temp_dir().join("slimweb-naïve.txt");
+	std::fs::write(&path, "data")?;
+
+	let multipart = Multipart::with_boundary("xyz").file_part("file", &path)?;
+	let encoded = multipart.read_all()?;
+
+	std::fs::remove_file(&path)?;
+
+	assert!(String::from_utf8_lossy(&encoded).contains(
+		"name=\"file\"; filename=\"slimweb-na_ve.txt\"; filename*=UTF-8''slimweb-na%C3%AFve.txt\r\nContent-Type: text/plain\r\n\r\ndata\r\n"
+	));
+
+	Ok(())
+}
+
+#[test]
+#[cfg(feature = "multipart")]
+fn test_multipart_streaming() -> IoResult<()> {
+	let listener = TcpListener::bind("localhost:8094")?;
+
+	let server = thread::spawn(move || {
+		let mut requests = vec![];
+
+		for _ in 0..2 {
+			let mut reader = BufReader::new(listener.accept().unwrap().0);
+			let mut line = String::new();
+			let mut length = None;
+			let mut chunked = false;
+
+			loop {
+				line.clear();
+				reader.read_line(&mut line).unwrap();
+
+				if let Some(value) = line.strip_prefix("Content-Length:") {
+					length = Some(value.trim().parse().unwrap());
+				} else if line == "Transfer-Encoding: chunked\r\n" {
+					chunked = true;
+				} else if line == "\r\n" {
+					break;
+				}
+			}
+
+			let mut body = vec![];
+
+			if let Some(length) = length {
+				body.resize(length, 0);
+				reader.read_exact(&mut body).unwrap();
+			} else {
+				loop {
+					line.clear();
+					reader.read_line(&mut line).unwrap();
+
+					let size = usize::from_str_radix(line.trim_end(), 16).unwrap();
+					let mut chunk = vec![0; size + 2];
+					reader.read_exact(&mut chunk).unwrap();
+
+					if size == 0 {
+						break;
+					}
+
+					body.extend_from_slice(&chunk[..size]);
+				}
+			}
+
+			reader.get_mut().write_all(b"HTTP/1.1 204 No Content\r\n\r\n").unwrap();
+			requests.push((length, chunked, body));
+		}
+
+		requests
+	});
+
+	// Files are read from disk as they're sent, with their size known up front.
+	let data: Vec<u8> = (0..100_000u32).map(|idx| (idx % 251) as u8).collect();
+	let path = std::env::temp_dir().join("slimweb-streamed.bin");
+	std::fs::write(&path, &data)?;
+
+	let multipart = Multipart::with_boundary("xyz").file_part("file", &path)?;
+	let expected = multipart.read_all()?;
+
+	post("http://localhost:8094/")?
+		.set_body(multipart)
+		.send()?;
+
+	// Parts that can't be read any more are errors, not panics.
+	let removed = Multipart::with_boundary("xyz").file_part("file", &path)?;
+
+	std::fs::remove_file(&path)?;
+
+	assert!(removed.read_all().is_err());
+	assert!(Body::from(removed).text().is_err());
+
+	let taken = Multipart::with_boundary("xyz").reader_part("data", "data.bin", std::io::Cursor::new(data.clone()));
+
+	taken.read_all()?;
+
+	assert!(taken.read_all().is_err());
+
+	// Readers have no known size, so they're sent chunked.
+	let multipart = Multipart::with_boundary("xyz")
+		.reader_part("data", "data.bin", std::io::Cursor::new(data.clone()));
+
+	post("http://localhost:8094/")?
+		.set_body(multipart)
+		.send()?;
+
+	let requests = server.join().unwrap();
+
+	assert_eq!(requests[0].0, Some(expected.len()));
+	assert!(!requests[0].1);
+	assert_eq!(requests[0].2, expected);
+
+	assert_eq!(requests[1].0, None);
+	assert!(requests[1].1);
+	assert!(requests[1].2.windows(data.len()).any(|window| window == &data[..]));
+
+	Ok(())
+}
+
+#[test]
+fn test_streaming() -> IoResult<()> {
+	let listener = TcpListener::bind("localhost:8095")?;
+	let data: Vec<u8> = (0..1_000_000u32).map(|idx| (idx % 251) as u8).collect();
+	let sent = data.clone();
+
+	let server = thread::spawn(move || {
+		let mut reader = BufReader::new(listener.accept().unwrap().0);
+		let mut line = String::new();
+		let mut head = vec![];
+
+		loop {
+			line.clear();
+			reader.read_line(&mut line).unwrap();
+
+			if line == "\r\n" {
+				break;
+			}
+
+			head.push(line.trim_end().to_string());
+		}
+
+		// Upload comes chunked, echo it back the same way.
+		let mut upload = vec![];
+
+		loop {
+			line.clear();
+			reader.read_line(&mut line).unwrap();
+
+			let size = usize::from_str_radix(line.trim_end(), 16).unwrap();
+			let mut chunk = vec![0; size + 2];
+			reader.read_exact(&mut chunk).unwrap();
+
+			if size == 0 {
+				break;
+			}
+
+			upload.extend_from_slice(&chunk[..size]);
+		}
+
+		let stream = reader.get_mut();
+		stream.write_all(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n").unwrap();
+
+		for chunk in sent.chunks(100_000) {
+			write!(stream, "{:x}\r\n", chunk.len()).unwrap();
+			stream.write_all(chunk).unwrap();
+			write!(stream, "\r\n").unwrap();
+		}
+
+		stream.write_all(b"0\r\n\r\n").unwrap();
+
+		(head, upload)
+	});
+
+	let resp = post("http://localhost:8095/")?
+		.set_body(crate::Body::from_reader(std::io::Cursor::new(b"streamed upload".to_vec())))
+		.set_streaming(true)
+		.send()?;
+
+	// Nothing is read until asked for.
+	assert_eq!(resp.body.text()?, "");
+
+	let mut body = vec![];
+	resp.into_reader()?.read_to_end(&mut body)?;
+
+	let (head, upload) = server.join().unwrap();
+
+	assert!(head.contains(&"Transfer-Encoding: chunked".to_string()));
+	assert!(head.contains(&"Content-Type: application/octet-stream".to_string()));
+	assert_eq!(upload, b"streamed upload");
+	assert_eq!(body, data);
+
+	Ok(())
+}
+
+#[test]
+#[cfg(feature = "compress")]
+fn test_compression() -> IoResult<()> {
+	use flate2::{ read::GzDecoder, write::GzEncoder, Compression };
+
+	let listener = TcpListener::bind("localhost:8096")?;
+	let text = "compress me, ".repeat(10_000);
+	let expected = text.clone();
+
+	let server = thread::spawn(move || {
+		let mut reader = BufReader::new(listener.accept().unwrap().0);
+		let mut line = String::new();
+		let mut head = vec![];
+
+		loop {
+			line.clear();
+			reader.read_line(&mut line).unwrap();
+
+			if line == "\r\n" {
+				break;
+			}
+
+			head.push(line.trim_end().to_string());
+		}
+
+		let mut upload = vec![];
+
+		loop {
+			line.clear();
+			reader.read_line(&mut line).unwrap();
+
+			let size = usize::from_str_radix(line.trim_end(), 16).unwrap();
+			let mut chunk = vec![0; size + 2];
+			reader.read_exact(&mut chunk).unwrap();
+
+			if size == 0 {
+				break;
+			}
+
+			upload.extend_from_slice(&chunk[..size]);
+		}
+
+		let mut decoded = String::new();
+		GzDecoder::new(&upload[..]).read_to_string(&mut decoded).unwrap();
+
+		// Answer with the upload, compressed again.
+		let mut encoder = GzEncoder::new(vec![], Compression::default());
+		encoder.write_all(decoded.as_bytes()).unwrap();
+		let body = encoder.finish().unwrap();
+
+		let stream = reader.get_mut();
+		write!(stream, "HTTP/1.1 200 OK\r\nContent-Encoding: gzip\r\nContent-Length: {}\r\n\r\n", body.len()).unwrap();
+		stream.write_all(&body).unwrap();
+
+		(head, upload.len(), decoded)
+	});
+
+	let resp = post("http://localhost:8096/")?
+		.set_body(text.as_str())
+		.set_compression_level(6)
+		.send()?;
+
+	let (head, compressed_size, decoded) = server.join().unwrap();
+
+	// Compressed size isn't known up front.
+	assert!(head.contains(&"Content-Encoding: gzip".to_string()));
+	assert!(head.contains(&"Transfer-Encoding: chunked".to_string()));
+	assert!(!head.iter().any(|line| line.starts_with("Content-Length")));
+	assert!(compressed_size < expected.len() / 10);
+	assert_eq!(decoded, expected);
+	assert_eq!(resp.body.text()?, expected);
+
+	Ok(())
+}
+
+#[test]
+#[cfg(feature = "compress")]
+fn test_stacked_codings() -> IoResult<()> {
+	fn encode(coding: &str, data: &[u8]) -> Vec<u8> {
+		match coding {
+			"gzip" => {
+				let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+				encoder.write_all(data).unwrap();
+				encoder.finish().unwrap()
+			},
+			#[cfg(feature = "deflate")]
+			"deflate" => {
+				let mut encoder = flate2::write::ZlibEncoder::new(vec![], flate2::Compression::default());
+				encoder.write_all(data).unwrap();
+				encoder.finish().unwrap()
+			},
+			#[cfg(feature = "brotli")]
+			"br" => {
+				let mut encoder = brotli::CompressorWriter::new(vec![], 4096, 5, 22);
+				encoder.write_all(data).unwrap();
+				encoder.into_inner()
+			},
+			#[cfg(feature = "zstd")]
+			"zstd" => zstd::encode_all(data, 0).unwrap(),
+			_ => unreachable!(),
+		}
+	}
+
+	let codings: &[&str] = &[
+		#[cfg(feature = "zstd")]
+		"zstd",
+		"gzip",
+		#[cfg(feature = "brotli")]
+		"br",
+		#[cfg(feature = "deflate")]
+		"deflate",
+	];
+
+	let listener = TcpListener::bind("localhost:8097")?;
+	let text = "stack me, ".repeat(10_000);
+	let expected = text.clone();
+
+	let server = thread::spawn(move || {
+		for header in &[codings.join(", "), "gzip, unknown".to_string()] {
+			let mut reader = BufReader::new(listener.accept().unwrap().0);
+			let mut line = String::new();
+
+			while line != "\r\n" {
+				line.clear();
+				reader.read_line(&mut line).unwrap();
+			}
+
+			// Applied in the order they're listed.
+			let body = header
+				.split(", ")
+				.filter(|coding| *coding != "unknown")
+				.fold(text.as_bytes().to_vec(), |body, coding| encode(coding, &body));
+
+			let stream = reader.get_mut();
+			write!(stream, "HTTP/1.1 200 OK\r\nContent-Encoding: {}\r\nContent-Length: {}\r\n\r\n", header, body.len()).unwrap();
+			stream.write_all(&body).unwrap();
+		}
+	});
+
+	let resp = get("http://localhost:8097/")?.send()?;
+
+	assert_eq!(resp.body.text()?, expected);
+
+	// Left alone if any coding can't be undone.
+	let resp = get("http://localhost:8097/")?.send()?;
+
+	assert_eq!(resp.body, encode("gzip", expected.as_bytes()).into());
+
+	server.join().unwrap();
+
+	Ok(())
+}
+
+#[test]
+#[cfg(feature = "compress")]
+fn test_accept_encoding() -> IoResult<()> {
+	use flate2::{ write::GzEncoder, Compression };
+
+	let listener = TcpListener::bind("localhost:8098")?;
+	let text = "decompress me, ".repeat(1_000);
+
+	let mut encoder = GzEncoder::new(vec![], Compression::default());
+	encoder.write_all(text.as_bytes())?;
+	let body = encoder.finish()?;
+	let sent = body.clone();
+
+	let server = thread::spawn(move || {
+		let mut accepts = vec![];
+
+		for _ in 0..3 {
+			let mut reader = BufReader::new(listener.accept().unwrap().0);
+			let mut line = String::new();
+
+			while line != "\r\n" {
+				line.clear();
+				reader.read_line(&mut line).unwrap();
+
+				if let Some(value) = line.strip_prefix("Accept-Encoding:") {
+					accepts.push(value.trim().to_string());
+				}
+			}
+
+			let stream = reader.get_mut();
+			write!(stream, "HTTP/1.1 200 OK\r\nContent-Encoding: gzip\r\nContent-Length: {}\r\n\r\n", body.len()).unwrap();
+			stream.write_all(&body).unwrap();
+		}
+
+		accepts
+	});
+
+	// Decoded, without the headers describing the encoded body.
+	let resp = get("http://localhost:8098/")?.send()?;
+
+	assert_eq!(resp.body.text()?, text);
+	assert!(!resp.info.headers.contains_key("Content-Encoding"));
+	assert!(!resp.info.headers.contains_key("Content-Length"));
+
+	let mut streamed = String::new();
+	get("http://localhost:8098/")?
+		.set_streaming(true)
+		.send()?
+		.into_reader()?
+		.read_to_string(&mut streamed)?;
+
+	assert_eq!(streamed, text);
+
+	// Left as sent.
+	let resp = get("http://localhost:8098/")?
+		.set_decompression(false)
+		.set_header("Accept-Encoding", "gzip")
+		.send()?;
+
+	assert_eq!(resp.body, sent.into());
+	assert_eq!(resp.info.headers["Content-Encoding"], "gzip");
+
+	let accepts = server.join().unwrap();
+
+	assert!(accepts[0].split(", ").any(|coding| coding == "gzip"));
+	assert_eq!(accepts[0], accepts[1]);
+	assert_eq!(accepts[2], "gzip");
+
+	Ok(())
+}
+
+#[test]
+fn test_headers() -> IoResult<()> {
+	let listener = TcpListener::bind("localhost:8099")?;
+
+	let server = thread::spawn(move || {
+		let mut reader = BufReader::new(listener.accept().unwrap().0);
+		let mut line = String::new();
+		let mut head = vec![];
+
+		while line != "\r\n" {
+			line.clear();
+			reader.read_line(&mut line).unwrap();
+			head.push(line.trim_end().to_string());
+		}
+
+		let stream = reader.get_mut();
+		write!(stream, "HTTP/1.1 200 OK\r\nset-cookie: a=1\r\nSet-Cookie: b=2\r\ncontent-length: 2\r\n\r\nok").unwrap();
+
+		head
+	});
+
+	let resp = get("http://localhost:8099/")?
+		.set_header("x-custom", "first")
+		.set_header("X-Custom", "second")
+		.append_header("X-Tag", "one")
+		.append_header("X-Tag", "two")
+		.send()?;
+
+	let head = server.join().unwrap();
+
+	// Repeated fields are all kept, and found whatever their case.
+	assert_eq!(resp.info.headers.get_all("Set-Cookie").collect::<Vec<&String>>(), vec!["a=1", "b=2"]);
+	assert_eq!(resp.info.headers["Content-Length"], "2");
+	assert_eq!(resp.body.text()?, "ok");
+
+	assert!(head.contains(&"X-Custom: second".to_string()));
+	assert!(!head.iter().any(|line| line.starts_with("x-custom")));
+	assert!(head.contains(&"X-Tag: one".to_string()));
+	assert!(head.contains(&"X-Tag: two".to_string()));
+
+	Ok(())
+}
+
+#[test]
+fn test_smuggling() -> IoResult<()> {
+	let listener = TcpListener::bind("localhost:8109")?;
+
+	let responses = [
+		"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nContent-Length: 3\r\n\r\nok",
+		"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nok\r\n0\r\n\r\n",
+		"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked, identity\r\n\r\nok",
+		"HTTP/1.1 200 OK\r\nX-Folded: a\r\n\tb\r\nContent-Length: 2\r\n\r\nok",
+		"HTTP/1.1 200 OK\r\nContent-Length : 2\r\n\r\nok",
+		"HTTP/1.1 200 OK\nContent-Length: 2\n\nok",
+		"HTTP/1.1 200 OK\nContent-Length: 2\n\nok",
+		"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n2\nok\r\n0\r\n\r\n",
+	];
+
+	thread::spawn(move || {
+		for response in responses.iter() {
+			let mut reader = BufReader::new(listener.accept().unwrap().0);
+			let mut line = String::new();
+
+			while line != "\r\n" {
+				line.clear();
+				reader.read_line(&mut line).unwrap();
+			}
+
+			reader.get_mut().write_all(response.as_bytes()).unwrap();
+		}
+	});
+
+	let expected = [
+		Malformed::DuplicateContentLength,
+		Malformed::ConflictingLength,
+		Malformed::InvalidTransferEncoding,
+		Malformed::FoldedHeader,
+		Malformed::WhitespaceBeforeColon,
+		Malformed::BareLineFeed,
+	];
+
+	for reason in expected.iter() {
+		match get("http://localhost:8109/")?.send() {
+			Err(Error::MalformedHead(found)) => assert_eq!(found, *reason),
+			res => panic!("Expected {:?}, got {:?}", reason, res),
+		}
+	}
+
+	// Bare LF can be allowed.
+	let resp = get("http://localhost:8109/")?
+		.set_allow_bare_lf(true)
+		.send()?;
+
+	assert_eq!(resp.body.text()?, "ok");
+
+	// Chunk framing keeps to CRLF as well.
+	match get("http://localhost:8109/")?.send() {
+		Err(Error::ChunkError) => (),
+		res => panic!("Expected ChunkError, got {:?}", res),
+	}
+
+	Ok(())
+}
+
+#[test]
+fn test_max_response_size() -> IoResult<()> {
+	let listener = TcpListener::bind("localhost:8111")?;
+
+	#[cfg_attr(not(feature = "compress"), allow(unused_mut))]
+	let mut responses: Vec<Vec<u8>> = vec![
+		b"HTTP/1.1 200 OK\r\nContent-Length: 16\r\n\r\n0123456789abcdef".to_vec(),
+		b"HTTP/1.1 200 OK\r\nContent-Length: 17\r\n\r\n0123456789abcdefg".to_vec(),
+		b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n8\r\n01234567\r\n9\r\n89abcdefg\r\n0\r\n\r\n".to_vec(),
+		format!("HTTP/1.1 200 OK\r\nX-Padding: {}\r\nContent-Length: 2\r\n\r\nok", "x".repeat(64)).into_bytes(),
+	];
+
+	// Tiny when compressed, but far past the limit once decoded.
+	#[cfg(feature = "compress")]
+	{
+		use flate2::{ write::GzEncoder, Compression };
+
+		let mut encoder = GzEncoder::new(vec![], Compression::best());
+		encoder.write_all(&[0; 1024 * 1024]).unwrap();
+		let bomb = encoder.finish().unwrap();
+
+		assert!(bomb.len() < 4096);
+
+		let mut response = format!("HTTP/1.1 200 OK\r\nContent-Encoding: gzip\r\nContent-Length: {}\r\n\r\n", bomb.len()).into_bytes();
+		response.extend(bomb);
+		responses.push(response);
+	}
+
+	// Small, but asking for a window far larger than any decoder should set aside.
+	#[cfg(feature = "zstd")]
+	{
+		let mut encoder = zstd::Encoder::new(vec![], 0).unwrap();
+		encoder.window_log(24).unwrap();
+		encoder.write_all(b"ok").unwrap();
+		let frame = encoder.finish().unwrap();
+
+		let mut response = format!("HTTP/1.1 200 OK\r\nContent-Encoding: zstd\r\nContent-Length: {}\r\n\r\n", frame.len()).into_bytes();
+		response.extend(frame);
+		responses.push(response);
+	}
+
+	thread::spawn(move || {
+		for response in responses {
+			let mut reader = BufReader::new(listener.accept().unwrap().0);
+			let mut line = String::new();
+
+			while line != "\r\n" {
+				line.clear();
+				reader.read_line(&mut line).unwrap();
+			}
+
+			reader.get_mut().write_all(&response).unwrap();
+		}
+	});
+
+	let send = |max_header: usize| get("http://localhost:8111/").unwrap()
+		.set_max_response_size(16)
+		.set_max_header_size(max_header)
+		.send();
+
+	assert_eq!(send(1024).unwrap().body.text()?, "0123456789abcdef");
+	assert!(matches!(send(1024), Err(Error::ResponseTooLarge)));
+	assert!(matches!(send(1024), Err(Error::ResponseTooLarge)));
+	assert!(matches!(send(64), Err(Error::HeadersTooLarge)));
+
+	#[cfg(feature = "compress")]
+	assert!(matches!(get("http://localhost:8111/")?.set_max_response_size(4096).send(), Err(Error::ResponseTooLarge)));
+
+	#[cfg(feature = "zstd")]
+	assert!(matches!(get("http://localhost:8111/")?.send(), Err(Error::Io(_))));
+
+	Ok(())
+}
+
+#[test]
+fn test_body_resend() -> IoResult<()> {
+	let listener = TcpListener::bind("localhost:8121")?;
+
+	thread::spawn(move || {
+		for stream in listener.incoming() {
+			thread::spawn(move || {
+				let mut reader = BufReader::new(stream.unwrap());
+				let mut line = String::new();
+
+				for served in 0.. {
+					let mut path = String::new();
+					let mut chunked = false;
+
+					loop {
+						line.clear();
+
+						if reader.read_line(&mut line).unwrap_or(0) == 0 {
+							return;
+						}
+
+						if path.is_empty() {
+							path = line.split(' ').nth(1).unwrap_or("").to_string();
+						} else if line == "Transfer-Encoding: chunked\r\n" {
+							chunked = true;
+						} else if line == "\r\n" {
+							break;
+						}
+					}
+
+					// Read the body through, so only the response decides what the client sees.
+					while chunked {
+						line.clear();
+						reader.read_line(&mut line).unwrap();
+
+						let size = usize::from_str_radix(line.trim_end(), 16).unwrap();
+						let mut chunk = vec![0; size + 2];
+						reader.read_exact(&mut chunk).unwrap();
+
+						chunked = size > 0;
+					}
+
+					// The connection is closed as it's picked up again, after some garbage if asked.
+					if served > 0 {
+						if path == "/garbled" {
+							let _ = reader.get_mut().write_all(b"\0\r\n");
+						}
+
+						return;
+					}
+
+					if path == "/redirect" {
+						reader.get_mut().write_all(b"HTTP/1.1 307 Temporary Redirect\r\nLocation: /\r\nContent-Length: 0\r\n\r\n").unwrap();
+					} else {
+						reader.get_mut().write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok").unwrap();
+					}
+				}
+			});
+		}
+	});
+
+	let data = || Body::from_reader(std::io::Cursor::new(b"data".to_vec()));
+
+	// Redirects can't send a reader again.
+	let res = post("http://localhost:8121/redirect")?.set_body(data()).send();
+
+	assert!(matches!(res, Err(Error::BodyAlreadySent)), "{:?}", res);
+
+	// Connections closed as they're picked up again are retried on a new one.
+	let agent = Agent::new();
+
+	assert_eq!(agent.get("http://localhost:8121/")?.send()?.body.text()?, "ok");
+	assert_eq!(agent.get("http://localhost:8121/")?.send()?.body.text()?, "ok");
+	assert_eq!(agent.get("http://localhost:8121/garbled")?.send()?.body.text()?, "ok");
+
+	// Unless the body can't be sent again, in which case they fail as the connection did.
+
+	let res = agent.put("http://localhost:8121/")?.set_body(data()).send();
+
+	assert!(matches!(&res, Err(Error::Io(e)) if matches!(e.kind(), ErrorKind::UnexpectedEof | ErrorKind::ConnectionReset)), "{:?}", res);
+
+	// Converting a reader twice fails rather than coming out empty.
+	let body = data();
+
+	assert_eq!(body.text()?, "data");
+	assert!(body.text().is_err());
+
+	Ok(())
+}
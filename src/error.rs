@@ -25,14 +25,37 @@ pub enum Error {
 	NoStatusLineInResponse,
 	/// Problem decoding chunk of response.
 	ChunkError,
+	/// Problem decoding multipart body.
+	#[cfg(feature = "multipart")]
+	MultipartError,
 	/// The set number of max redirects (default 5) has been reached.
 	MaxRedirectsHit,
 	/// Redirect location header missing.
 	NoLocationHeader,
+	/// Body was read as it was sent, so the request can't be sent again (on a redirect).
+	BodyAlreadySent,
 	/// Occurs when host cannot be converted to SockAddr.
 	ConnectionFailed(String),
 	/// HTTP Status Code not recognized.
 	HTTPStatusCodeNotRecognized,
+	/// Connection couldn't be established within the connect timeout.
+	ConnectTimeout,
+	/// Response didn't start arriving within the first byte timeout.
+	FirstByteTimeout,
+	/// A single read stalled for longer than the read timeout.
+	ReadTimeout,
+	/// A single write stalled for longer than the write timeout.
+	WriteTimeout,
+	/// Request didn't complete before its overall deadline.
+	DeadlineExceeded,
+	/// Body (or part of it) is larger than allowed.
+	PayloadTooLarge,
+	/// Response body is larger than allowed (see `ClientRequest::set_max_response_size`).
+	ResponseTooLarge,
+	/// Message head has more fields, or bytes, than allowed.
+	HeadersTooLarge,
+	/// Message head breaks RFC 7230, or delimits its body ambiguously.
+	MalformedHead(Malformed),
 	/// Any generic IO error.
 	Io(IoError),
 }
@@ -48,15 +71,66 @@ impl fmt::Display for Error {
 			Error::TLSNotEnabled => write!(f, "Attempting to connect to secure URL without tls feature enabled"),
 			Error::NoStatusLineInResponse => write!(f, "Response does not contain a status line"),
 			Error::ChunkError => write!(f, "Problem decoding chunk of response"),
+			#[cfg(feature = "multipart")]
+			Error::MultipartError => write!(f, "Problem decoding multipart body"),
 			Error::MaxRedirectsHit => write!(f, "Your request hit maximum number of redirects. You can increase this limit by using .set_max_redirects(usize)"),
 			Error::NoLocationHeader => write!(f, "Redirect location header missing"),
+			Error::BodyAlreadySent => write!(f, "Body was already sent and can't be sent again"),
 			Error::ConnectionFailed(msg) => write!(f, "{}", msg),
 			Error::HTTPStatusCodeNotRecognized => write!(f, "HTTP status code supplied is not supported or does not exist."),
+			Error::ConnectTimeout => write!(f, "Connection timed out while connecting"),
+			Error::FirstByteTimeout => write!(f, "Connection timed out waiting for the response to start"),
+			Error::ReadTimeout => write!(f, "Connection timed out waiting for more data"),
+			Error::WriteTimeout => write!(f, "Connection timed out sending the request"),
+			Error::DeadlineExceeded => write!(f, "Request did not complete before its deadline"),
+			Error::PayloadTooLarge => write!(f, "Payload is larger than allowed"),
+			Error::ResponseTooLarge => write!(f, "Response is larger than allowed. You can increase this limit by using .set_max_response_size(usize)"),
+			Error::HeadersTooLarge => write!(f, "Message head is larger than allowed"),
+			Error::MalformedHead(reason) => write!(f, "Malformed message head: {}", reason),
 			Error::Io(ioe) => write!(f, "Network error: {}", ioe),
 		}
 	}
 }
 
+/// Why a message head was rejected.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Malformed {
+	/// Request or status line doesn't have the parts it should.
+	InvalidStartLine,
+	/// Content-Length given more than once.
+	DuplicateContentLength,
+	/// Content-Length isn't a plain decimal number.
+	InvalidContentLength,
+	/// Both Transfer-Encoding and Content-Length are given.
+	ConflictingLength,
+	/// Transfer-Encoding doesn't end with a single chunked.
+	InvalidTransferEncoding,
+	/// Header continued on the next line (obs-fold).
+	FoldedHeader,
+	/// Whitespace between a header name and its colon.
+	WhitespaceBeforeColon,
+	/// Header line without a colon, or with a name that isn't a token.
+	InvalidHeader,
+	/// Line ended with LF rather than CRLF.
+	BareLineFeed,
+}
+
+impl fmt::Display for Malformed {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Malformed::InvalidStartLine => write!(f, "invalid request or status line"),
+			Malformed::DuplicateContentLength => write!(f, "Content-Length given more than once"),
+			Malformed::InvalidContentLength => write!(f, "Content-Length is not a number"),
+			Malformed::ConflictingLength => write!(f, "both Transfer-Encoding and Content-Length given"),
+			Malformed::InvalidTransferEncoding => write!(f, "Transfer-Encoding does not end with chunked"),
+			Malformed::FoldedHeader => write!(f, "header folded over several lines"),
+			Malformed::WhitespaceBeforeColon => write!(f, "whitespace before header colon"),
+			Malformed::InvalidHeader => write!(f, "invalid header line"),
+			Malformed::BareLineFeed => write!(f, "line not ended with CRLF"),
+		}
+	}
+}
+
 impl error::Error for Error {
 	fn source(&self) -> Option<&(dyn error::Error + 'static)> {
 		match self {
@@ -68,7 +142,11 @@ impl error::Error for Error {
 
 impl From<IoError> for Error {
 	fn from(err: IoError) -> Error {
-		Error::Io(err)
+		// Our own errors, passed through a reader, come back out as they went in.
+		match err.get_ref().map(|inner| inner.is::<Error>()) {
+			Some(true) => *err.into_inner().unwrap().downcast::<Error>().unwrap(),
+			_ => Error::Io(err),
+		}
 	}
 }
 
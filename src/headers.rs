@@ -0,0 +1,175 @@
+use std::{
+	ops::Index,
+	slice,
+};
+
+#[cfg(feature = "json")]
+use serde_json::{ Map, Value };
+
+
+
+/// Header fields, in the order they were added.
+///
+/// Names are matched case-insensitively, but kept (and sent) as they were given.
+/// A name can carry several values (e.g. `Set-Cookie`), each kept as its own field.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Headers {
+	entries: Vec<(String, String)>,
+}
+
+impl Headers {
+	/// Creates an empty set of headers.
+	pub fn new() -> Headers {
+		Headers::default()
+	}
+
+	/// First value of a header.
+	pub fn get(&self, name: &str) -> Option<&String> {
+		self.entries
+			.iter()
+			.find(|(key, _)| key.eq_ignore_ascii_case(name))
+			.map(|(_, value)| value)
+	}
+
+	/// Every value of a header, in order.
+	pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a String> + 'a {
+		self.entries
+			.iter()
+			.filter(move |(key, _)| key.eq_ignore_ascii_case(name))
+			.map(|(_, value)| value)
+	}
+
+	/// Elements of a comma separated header, across every field it came in.
+	pub(crate) fn list<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+		self.get_all(name)
+			.flat_map(|value| value.split(','))
+			.map(str::trim)
+			.filter(|element| !element.is_empty())
+	}
+
+	/// Whether a header is set.
+	pub fn contains_key(&self, name: &str) -> bool {
+		self.get(name).is_some()
+	}
+
+	/// Sets a header, replacing every value it had.
+	/// Keeps the position of its first value, if it had one.
+	pub fn insert<N: Into<String>, V: Into<String>>(&mut self, name: N, value: V) {
+		let name = name.into();
+		let mut value = Some(value.into());
+
+		self.entries.retain_mut(|(key, old)| {
+			if !key.eq_ignore_ascii_case(&name) {
+				return true;
+			}
+
+			// First value is replaced, the rest dropped.
+			match value.take() {
+				Some(value) => {
+					*key = name.clone();
+					*old = value;
+
+					true
+				},
+				None => false,
+			}
+		});
+
+		if let Some(value) = value {
+			self.entries.push((name, value));
+		}
+	}
+
+	/// Adds another value for a header, after any it already has.
+	pub fn append<N: Into<String>, V: Into<String>>(&mut self, name: N, value: V) {
+		self.entries.push((name.into(), value.into()));
+	}
+
+	/// Removes every value of a header, returning the first.
+	pub fn remove(&mut self, name: &str) -> Option<String> {
+		let first = self.get(name).cloned();
+
+		self.entries.retain(|(key, _)| !key.eq_ignore_ascii_case(name));
+
+		first
+	}
+
+	/// Iterates over every field (name, value), in order.
+	pub fn iter(&self) -> HeaderIter<'_> {
+		HeaderIter(self.entries.iter())
+	}
+
+	/// Number of fields, counting every value of a header.
+	pub fn len(&self) -> usize {
+		self.entries.len()
+	}
+
+	/// Whether there are no fields.
+	pub fn is_empty(&self) -> bool {
+		self.entries.is_empty()
+	}
+
+	/// Convert headers into a JSON object, repeated ones into arrays.
+	#[cfg(feature = "json")]
+	pub fn json(&self) -> Value {
+		let mut map = Map::new();
+
+		for (name, _) in &self.entries {
+			if map.keys().any(|key| key.eq_ignore_ascii_case(name)) {
+				continue;
+			}
+
+			let mut values: Vec<Value> = self.get_all(name).map(|value| Value::from(value.as_str())).collect();
+
+			let value = if values.len() == 1 {
+				values.remove(0)
+			} else {
+				Value::Array(values)
+			};
+
+			map.insert(name.clone(), value);
+		}
+
+		Value::Object(map)
+	}
+}
+
+/// Iterator over header fields (name, value), in order.
+#[derive(Debug)]
+pub struct HeaderIter<'a>(slice::Iter<'a, (String, String)>);
+
+impl<'a> Iterator for HeaderIter<'a> {
+	type Item = (&'a String, &'a String);
+
+	fn next(&mut self) -> Option<(&'a String, &'a String)> {
+		self.0.next().map(|(name, value)| (name, value))
+	}
+}
+
+impl<'a> IntoIterator for &'a Headers {
+	type Item = (&'a String, &'a String);
+	type IntoIter = HeaderIter<'a>;
+
+	fn into_iter(self) -> HeaderIter<'a> {
+		self.iter()
+	}
+}
+
+impl Index<&str> for Headers {
+	type Output = String;
+
+	/// First value of a header.
+	///
+	/// Panics if it isn't set.
+	fn index(&self, name: &str) -> &String {
+		self.get(name).expect("No header with that name")
+	}
+}
+
+impl<N: Into<String>, V: Into<String>> Extend<(N, V)> for Headers {
+	fn extend<I: IntoIterator<Item = (N, V)>>(&mut self, iter: I) {
+		for (name, value) in iter {
+			self.append(name, value);
+		}
+	}
+}
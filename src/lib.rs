@@ -10,13 +10,12 @@
 //! No async functionality.
 //! Decisively using deadlines for DoS prevention (didn't want to deal with leaky thread racing).
 //! Using Rustls for SSL/TLS encryption.
-//! Using flate2 for compression/decompression (GZip only).
+//! Using flate2 for gzip (`compress` feature) and deflate (`deflate` feature) compression/decompression,
+//! the brotli crate for Brotli (`brotli` feature) and the zstd crate for Zstandard (`zstd` feature).
 
 #![deny(clippy::all, missing_docs)]
 #![forbid(unsafe_code)]
 
-use std::collections::HashMap;
-
 #[cfg(feature = "server")]
 #[macro_use] extern crate log;
 
@@ -26,12 +25,16 @@ use serde_json::Value;
 #[macro_use] mod macros;
 mod error;
 mod body;
+mod headers;
 #[cfg(feature = "multipart")] mod multipart;
 mod stream;
+mod urlencoded;
 #[cfg(feature = "client")] mod client;
 #[cfg(feature = "server")] mod server;
 
 pub use error::*;
+pub use body::{ Body, BodyReader };
+pub use headers::{ Headers, HeaderIter };
 #[cfg(feature = "multipart")] pub use multipart::*;
 #[cfg(feature = "client")] pub use client::*;
 #[cfg(feature = "server")] pub use server::*;
@@ -41,10 +44,10 @@ pub use error::*;
 /// General status info.
 #[derive(Debug, Clone, PartialEq)]
 pub enum StatusInfo {
-	/// General response status information (code, reason).
-	Response(i32, String), // server response
-	/// General request status information (method, resource).
-	Request(String, String), // client request
+	/// General response status information (version, code, reason).
+	Response(String, i32, String), // server response
+	/// General request status information (method, resource, version).
+	Request(String, String, String), // client request
 }
 
 /// General response info.
@@ -53,7 +56,7 @@ pub struct GeneralInfo {
 	/// HTTP Status Line.
 	pub status: StatusInfo,
 	/// Response headers.
-	pub headers: HashMap<String, String>,
+	pub headers: Headers,
 }
 
 #[cfg(feature = "json")]
@@ -61,22 +64,24 @@ impl GeneralInfo {
 	/// Convert response information into JSON.
 	pub fn json(&self) -> Value {
 		match &self.status {
-			StatusInfo::Response(code, reason) => {
+			StatusInfo::Response(version, code, reason) => {
 				serde_json::json!({
 					"status": {
+						"version": version,
 						"code": code,
 						"reason": reason
 					},
-					"headers": self.headers
+					"headers": self.headers.json()
 				})
 			},
-			StatusInfo::Request(method, resource) => {
+			StatusInfo::Request(method, resource, version) => {
 				serde_json::json!({
 					"status": {
 						"method": method,
-						"resource": resource
+						"resource": resource,
+						"version": version
 					},
-					"headers": self.headers
+					"headers": self.headers.json()
 				})
 			},
 		}
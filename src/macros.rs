@@ -27,8 +27,8 @@ macro_rules! implread {
 		}
 	};
 
-	($name:ident, $life:lifetime, ($($(#[cfg($cfg:ident = $val:tt)])? $op:ident),*)) => {
-		impl<$life> Read for $name<$life> {
+	($name:ident, $t:ident, ($($(#[cfg($cfg:ident = $val:tt)])? $op:ident),*)) => {
+		impl<$t: Transport> Read for $name<$t> {
 			fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
 				match self {
 					$($(#[cfg($cfg = $val)])? $name::$op(s) => s.read(buf),)*
@@ -80,8 +80,8 @@ macro_rules! implbufread {
 		}
 	};
 
-	($name:ident, $life:lifetime, ($($(#[cfg($cfg:ident = $val:tt)])? $op:ident),*)) => {
-		impl<$life> BufRead for $name<$life> {
+	($name:ident, $t:ident, ($($(#[cfg($cfg:ident = $val:tt)])? $op:ident),*)) => {
+		impl<$t: Transport> BufRead for $name<$t> {
 			fn fill_buf(&mut self) -> IoResult<&[u8]> {
 				match self {
 					$($(#[cfg($cfg = $val)])? $name::$op(s) => s.fill_buf(),)*
@@ -139,8 +139,8 @@ macro_rules! implwrite {
 		}
 	};
 
-	($name:ident, $life:lifetime, ($($(#[cfg($cfg:ident = $val:tt)])? $op:ident $(.$ex:ident())?),*)) => {
-		impl<$life> Write for $name<$life> {
+	($name:ident, $t:ident, ($($(#[cfg($cfg:ident = $val:tt)])? $op:ident $(.$ex:ident())?),*)) => {
+		impl<$t: Transport> Write for $name<$t> {
 			fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
 				match self {
 					$($(#[cfg($cfg = $val)])? $name::$op(s) => s$(.$ex())?.write(buf),)*
@@ -157,62 +157,43 @@ macro_rules! implwrite {
 }
 
 #[macro_export]
-/// Impl get_ref and get_mut.
+/// Impl get_ref.
 ///
-/// There are only lifetime affected enums that this applies to currently.
+/// There are only enums generic over their transport that this applies to currently.
 ///
 /// Example:
 /// ```ignore
-/// implgets!(Chunky, 'r, (Read, Write));
+/// implgets!(Chunky, T, (Read, Write));
 /// ```
 /// Becomes:
 /// ```ignore
-/// impl<'r> Chunky<'r> {
+/// impl<T: Transport> GetRef for Chunky<T> {
 ///		fn get_ref(&self) -> &Stream {
 ///			match self {
 ///				Chunky::Read(s) => s.get_ref(),
 /// 			Chunky::Write(s) => s.get_ref(),
 ///			}
 ///		}
-///
-///		fn get_mut(&mut self) -> &mut Stream {
-///			match self {
-///				Chunky::Read(s) => s.get_mut(),
-/// 			Chunky::Write(s) => s.get_mut(),
-///			}
-///		}
 ///	}
 /// ```
 macro_rules! implgets {
 	($name:ident, ($($(#[cfg($cfg:ident = $val:tt)])? $op:ident),*)) => {
-		impl GetRefMut for $name {
+		impl GetRef for $name {
 			fn get_ref(&self) -> &Stream {
 				match self {
 					$($(#[cfg($cfg = $val)])? $name::$op(s) => s.get_ref(),)*
 				}
 			}
-
-			fn get_mut(&mut self) -> &mut Stream {
-				match self {
-					$($(#[cfg($cfg = $val)])? $name::$op(s) => s.get_mut(),)*
-				}
-			}
 		}
 	};
 
-	($name:ident, $life:lifetime, ($($(#[cfg($cfg:ident = $val:tt)])? $op:ident),*)) => {
-		impl<$life> GetRefMut for $name<$life> {
+	($name:ident, $t:ident, ($($(#[cfg($cfg:ident = $val:tt)])? $op:ident),*)) => {
+		impl<$t: Transport> GetRef for $name<$t> {
 			fn get_ref(&self) -> &Stream {
 				match self {
 					$($(#[cfg($cfg = $val)])? $name::$op(s) => s.get_ref(),)*
 				}
 			}
-
-			fn get_mut(&mut self) -> &mut Stream {
-				match self {
-					$($(#[cfg($cfg = $val)])? $name::$op(s) => s.get_mut(),)*
-				}
-			}
 		}
 	};
 }
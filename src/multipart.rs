@@ -1,85 +1,182 @@
-use std::io::Write;
-
-#[cfg(feature = "client")]
 use std::{
+	fs::File,
 	io::{
-		Read, BufReader,
+		Read, Write, Cursor,
 		Result as IoResult,
 	},
-	fs::File,
 	path::Path,
+	collections::VecDeque,
+};
+
+#[cfg(any(feature = "client", feature = "server"))]
+use std::{
+	collections::hash_map::RandomState,
+	hash::{ BuildHasher, Hasher },
+	path::PathBuf,
+	sync::atomic::{ AtomicUsize, Ordering },
+	time::{ SystemTime, UNIX_EPOCH },
 };
 
 #[cfg(feature = "server")]
-use crate::Error;
+use std::{
+	env, fs,
+	sync::Arc,
+	io::{ BufWriter, ErrorKind },
+};
+#[cfg(all(feature = "server", unix))]
+use std::os::unix::fs::OpenOptionsExt;
+
+#[cfg(feature = "client")]
+use crate::body::BodyReader;
+#[cfg(feature = "server")]
+use crate::{
+	urlencoded,
+	Error,
+};
 
 
 
+/// Single field of a multipart body.
 #[derive(Debug, Clone, PartialEq)]
-struct Part {
+pub struct Part {
 	name: String,
 	filename: Option<String>,
 	content_type: Option<String>,
-	data: Vec<u8>,
+	data: Data,
 }
 
-impl Into<Vec<u8>> for Part {
-	fn into(self) -> Vec<u8> {
-		let mut part_data = Vec::new();
-
-		write!(part_data, "Content-Disposition: form-data; name={}", self.name).unwrap();
+#[derive(Debug, Clone, PartialEq)]
+enum Data {
+	Memory(Vec<u8>),
+	// Shared by clones, the file goes away with the last of them.
+	#[cfg(feature = "server")]
+	File(Arc<TempFile>),
+	// File (and its size) read from disk as the body is sent.
+	#[cfg(feature = "client")]
+	Path(PathBuf, u64),
+	// Read once, as the body is sent.
+	#[cfg(feature = "client")]
+	Reader(BodyReader),
+}
 
-		if let Some(filename) = self.filename {
-			writeln!(part_data, "; filename={}\r", filename).unwrap();
-		} else {
-			writeln!(part_data, "\r").unwrap();
+impl Data {
+	/// Size, if it's known without reading.
+	fn size(&self) -> Option<u64> {
+		match self {
+			Data::Memory(data) => Some(data.len() as u64),
+			#[cfg(feature = "server")]
+			Data::File(file) => fs::metadata(&file.path).ok().map(|metadata| metadata.len()),
+			#[cfg(feature = "client")]
+			Data::Path(_, size) => Some(*size),
+			#[cfg(feature = "client")]
+			Data::Reader(_) => None,
 		}
+	}
+
+	fn reader(&self) -> IoResult<Box<dyn Read + Send + '_>> {
+		Ok(match self {
+			Data::Memory(data) => Box::new(&data[..]),
+			#[cfg(feature = "server")]
+			Data::File(file) => Box::new(File::open(&file.path)?),
+			// Never send more than the size the body was announced with.
+			#[cfg(feature = "client")]
+			Data::Path(path, size) => Box::new(File::open(path)?.take(*size)),
+			#[cfg(feature = "client")]
+			Data::Reader(source) => source.take()?,
+		})
+	}
+}
 
-		if let Some(ref content_type) = self.content_type {
-			writeln!(part_data, "Content-Type: {}\r", content_type).unwrap();
+/// Reads from each reader in turn.
+struct Concat<'a> {
+	readers: VecDeque<Box<dyn Read + Send + 'a>>,
+}
+
+impl<'a> Read for Concat<'a> {
+	fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+		while let Some(reader) = self.readers.front_mut() {
+			match reader.read(buf)? {
+				0 => {
+					self.readers.pop_front();
+				},
+				n => return Ok(n),
+			}
 		}
 
-		writeln!(part_data, "\r").unwrap();
+		Ok(0)
+	}
+}
+
+impl Part {
+	/// Field name.
+	pub fn name(&self) -> &str {
+		&self.name
+	}
 
-		part_data.extend::<Vec<u8>>(self.data);
+	/// Filename, for file fields.
+	pub fn filename(&self) -> Option<&str> {
+		self.filename.as_deref()
+	}
 
-		writeln!(part_data, "\r").unwrap();
+	/// Declared content type.
+	pub fn content_type(&self) -> Option<&str> {
+		self.content_type.as_deref()
+	}
 
-		if self.content_type.is_some() {
-			writeln!(part_data, "\r").unwrap();
+	/// Field data, exactly as sent, if it's kept in memory.
+	pub fn bytes(&self) -> Option<&[u8]> {
+		match &self.data {
+			Data::Memory(data) => Some(data),
+			_ => None,
 		}
+	}
+
+	/// File holding field data, if it isn't kept in memory.
+	/// Files that uploads were moved to are removed once the part is dropped.
+	pub fn path(&self) -> Option<&Path> {
+		match &self.data {
+			#[cfg(feature = "server")]
+			Data::File(file) => Some(&file.path),
+			#[cfg(feature = "client")]
+			Data::Path(path, _) => Some(path),
+			_ => None,
+		}
+	}
 
-		part_data
+	/// Reads field data, wherever it's kept.
+	/// Parts added with a reader can only be read once.
+	pub fn reader(&self) -> IoResult<Box<dyn Read + Send + '_>> {
+		self.data.reader()
 	}
 }
 
-impl Into<Vec<u8>> for &Part {
-	fn into(self) -> Vec<u8> {
-		let mut part_data = Vec::new();
+impl Part {
+	/// Headers introducing the part, up to and including the blank line.
+	fn head(&self) -> Vec<u8> {
+		let mut head = Vec::new();
 
-		write!(part_data, "Content-Disposition: form-data; name={}", self.name).unwrap();
+		write!(head, "Content-Disposition: form-data; name=\"{}\"", escape(&self.name)).unwrap();
 
 		if let Some(filename) = &self.filename {
-			writeln!(part_data, "; filename={}\r", filename).unwrap();
-		} else {
-			writeln!(part_data, "\r").unwrap();
-		}
+			if filename.is_ascii() {
+				write!(head, "; filename=\"{}\"", escape(filename)).unwrap();
+			} else {
+				// Plain filename is a fallback for receivers that don't understand filename*.
+				let fallback: String = filename.chars().map(|c| if c.is_ascii() { c } else { '_' }).collect();
 
-		if let Some(ref content_type) = self.content_type {
-			writeln!(part_data, "Content-Type: {}\r", content_type).unwrap();
+				write!(head, "; filename=\"{}\"; filename*=UTF-8''{}", escape(&fallback), ext_value(filename)).unwrap();
+			}
 		}
 
-		writeln!(part_data, "\r").unwrap();
+		write!(head, "\r\n").unwrap();
 
-		part_data.extend::<Vec<u8>>(self.data.clone());
-
-		writeln!(part_data, "\r").unwrap();
-
-		if self.content_type.is_some() {
-			writeln!(part_data, "\r").unwrap();
+		if let Some(content_type) = &self.content_type {
+			write!(head, "Content-Type: {}\r\n", content_type).unwrap();
 		}
 
-		part_data
+		write!(head, "\r\n").unwrap();
+
+		head
 	}
 }
 
@@ -87,173 +184,575 @@ impl Into<Vec<u8>> for &Part {
 #[derive(Debug, Clone, PartialEq)]
 pub struct Multipart {
 	boundary: String,
+	// Random boundaries are replaced on collision, user supplied ones are left alone.
+	generated: bool,
 	parts: Vec<Part>,
 }
 
-impl Into<Vec<u8>> for Multipart {
-	fn into(self) -> Vec<u8> {
-		let mut multi_data = Vec::new();
+impl Multipart {
+	/// All parts, in order.
+	pub fn parts(&self) -> &[Part] {
+		&self.parts
+	}
 
-		for part in self.parts {
-			writeln!(multi_data, "--{}\r", self.boundary).unwrap();
+	/// First part with field name.
+	pub fn part(&self, name: &str) -> Option<&Part> {
+		self.parts.iter().find(|part| part.name == name)
+	}
 
-			multi_data.extend::<Vec<u8>>(part.into());
-		}
+	/// Boundary delimiting parts.
+	pub fn boundary(&self) -> &str {
+		&self.boundary
+	}
 
-		write!(multi_data, "--{}--", self.boundary).unwrap();
+	/// Encoded size, if the size of every part is known up front.
+	pub(crate) fn size(&self) -> Option<u64> {
+		// "--boundary\r\n" before and "\r\n" after every part, "--boundary--\r\n" at the end.
+		let framing = self.boundary.len() as u64 + 6;
+		let mut size = framing;
 
-		multi_data
+		for part in &self.parts {
+			size += framing + part.head().len() as u64 + part.data.size()?;
+		}
+
+		Some(size)
 	}
-}
 
-impl Into<Vec<u8>> for &Multipart {
-	fn into(self) -> Vec<u8> {
+	/// Encoded body, read into memory.
+	pub fn read_all(&self) -> IoResult<Vec<u8>> {
 		let mut multi_data = Vec::new();
 
+		self.reader()?.read_to_end(&mut multi_data)?;
+
+		Ok(multi_data)
+	}
+
+	/// Encoded body, streaming parts from wherever they're kept as it's read.
+	pub(crate) fn reader(&self) -> IoResult<Box<dyn Read + Send + '_>> {
+		let mut readers: VecDeque<Box<dyn Read + Send + '_>> = VecDeque::new();
+
 		for part in &self.parts {
-			writeln!(multi_data, "--{}\r", self.boundary).unwrap();
+			let mut head = format!("--{}\r\n", self.boundary).into_bytes();
+			head.extend(part.head());
 
-			multi_data.extend::<Vec<u8>>(part.into());
+			readers.push_back(Box::new(Cursor::new(head)));
+			readers.push_back(part.data.reader()?);
+			readers.push_back(Box::new(&b"\r\n"[..]));
 		}
 
-		write!(multi_data, "--{}--", self.boundary).unwrap();
+		readers.push_back(Box::new(Cursor::new(format!("--{}--\r\n", self.boundary).into_bytes())));
 
-		multi_data
+		Ok(Box::new(Concat { readers }))
 	}
 }
 
 #[cfg(feature = "client")]
 impl Multipart {
-	/// Create new multipart body.
-	pub fn new<S: Into<String>>(boundary: S) -> Multipart {
+	/// Create new multipart body with a random boundary.
+	/// The boundary is replaced if any part added later happens to contain it.
+	pub fn new() -> Multipart {
+		Multipart {
+			boundary: random_boundary(),
+			generated: true,
+			parts: Vec::new(),
+		}
+	}
+
+	/// Create new multipart body with a fixed boundary. It must not appear in any part.
+	pub fn with_boundary<S: Into<String>>(boundary: S) -> Multipart {
 		Multipart {
 			boundary: boundary.into(),
+			generated: false,
 			parts: Vec::new(),
 		}
 	}
 
 	/// Adds implied text_plain field to multipart body.
-	pub fn text_part<S: Into<String>, D: Into<Vec<u8>>>(mut self, name: S, text: D) -> Multipart {
-		self.parts.push(Part {
+	pub fn text_part<S: Into<String>, D: Into<Vec<u8>>>(self, name: S, text: D) -> Multipart {
+		self.push(Part {
 			name: name.into(),
 			filename: None,
 			content_type: None,
-			data: text.into(),
-		});
-
-		self
+			data: Data::Memory(text.into()),
+		})
 	}
 
 	/// Adds file to multipart body and guesses the MIME type.
-	pub fn file_part<S: Into<String>, F: AsRef<Path>>(mut self, name: S, path: F) -> IoResult<Multipart> {
-		let file = File::open(path.as_ref())?;
-		let mut file_reader = BufReader::new(file);
-		let mut file_data = Vec::new();
-
-		file_reader.read_to_end(&mut file_data)?;
+	/// The file is read as the request is sent, rather than loaded into memory.
+	pub fn file_part<S: Into<String>, F: AsRef<Path>>(self, name: S, path: F) -> IoResult<Multipart> {
+		let size = File::open(path.as_ref())?.metadata()?.len();
 
 		let content_type = mime_guess::from_path(path.as_ref()).first_or_octet_stream();
 		let filename = path.as_ref().file_name().and_then(|filename| Some(filename.to_str()?.to_string()));
 
-		self.parts.push(Part {
+		Ok(self.push(Part {
 			name: name.into(),
 			filename,
 			content_type: Some(content_type.to_string()),
-			data: file_data,
-		});
+			data: Data::Path(path.as_ref().to_path_buf(), size),
+		}))
+	}
+
+	/// Adds file field read from any reader as the request is sent, guessing the MIME type from filename.
+	/// Its size isn't known up front, so the request is sent chunked.
+	pub fn reader_part<S: Into<String>, R: Read + Send + 'static>(self, name: S, filename: S, reader: R) -> Multipart {
+		let filename = filename.into();
+		let content_type = mime_guess::from_path(&filename).first_or_octet_stream();
+
+		self.push(Part {
+			name: name.into(),
+			filename: Some(filename),
+			content_type: Some(content_type.to_string()),
+			data: Data::Reader(BodyReader::new(reader)),
+		})
+	}
+
+	/// Whether a part added with a reader was already read.
+	pub(crate) fn is_consumed(&self) -> bool {
+		self.parts.iter().any(|part| matches!(&part.data, Data::Reader(reader) if reader.is_read()))
+	}
+
+	fn push(mut self, part: Part) -> Multipart {
+		self.parts.push(part);
+
+		while self.generated && self.parts.iter().any(|part| part.contains(self.boundary.as_bytes())) {
+			self.boundary = random_boundary();
+		}
+
+		self
+	}
+}
+
+#[cfg(feature = "client")]
+impl Default for Multipart {
+	fn default() -> Multipart {
+		Multipart::new()
+	}
+}
+
+#[cfg(feature = "client")]
+impl Part {
+	fn contains(&self, bytes: &[u8]) -> bool {
+		// Parts that aren't in memory aren't read twice just to check.
+		match &self.data {
+			Data::Memory(data) => find(data, bytes).is_some(),
+			_ => false,
+		}
+	}
+}
+
+/// Random boundary, long enough that collisions are only a formality.
+#[cfg(feature = "client")]
+fn random_boundary() -> String {
+	format!("slimweb-{}", random_hex())
+}
+
+/// 32 random hex digits (hashing with randomly keyed state).
+#[cfg(any(feature = "client", feature = "server"))]
+fn random_hex() -> String {
+	static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+	let state = RandomState::new();
+	let mut hex = String::new();
 
-		Ok(self)
+	for _ in 0..2 {
+		let mut hasher = state.build_hasher();
+
+		hasher.write_usize(COUNTER.fetch_add(1, Ordering::Relaxed));
+		hasher.write_u128(SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_nanos()));
+
+		hex += &format!("{:016x}", hasher.finish());
 	}
+
+	hex
+}
+
+/// Makes a value safe to put in quotes: quotes and line breaks are percent-encoded.
+fn escape(value: &str) -> String {
+	value
+		.replace('"', "%22")
+		.replace('\r', "%0D")
+		.replace('\n', "%0A")
+}
+
+/// RFC 5987 value (without charset prefix), percent-encoding all but attr-chars.
+fn ext_value(value: &str) -> String {
+	let mut encoded = String::new();
+
+	for byte in value.bytes() {
+		match byte {
+			b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9'
+			| b'!' | b'#' | b'$' | b'&' | b'+' | b'-' | b'.' | b'^' | b'_' | b'`' | b'|' | b'~' => encoded.push(byte as char),
+			byte => encoded += &format!("%{:02X}", byte),
+		}
+	}
+
+	encoded
+}
+
+#[cfg(feature = "server")]
+const MAX_HEADER_LINE: usize = 8 * 1024;
+
+/// When parts leave memory for the temp directory, and how large parts may get.
+#[cfg(feature = "server")]
+#[derive(Debug, Clone)]
+pub(crate) struct Spill {
+	pub(crate) threshold: usize,
+	pub(crate) temp_dir: PathBuf,
+	pub(crate) max_part: Option<usize>,
+	pub(crate) max_total: Option<usize>,
 }
 
 #[cfg(feature = "server")]
-pub(crate) fn from_bytes(boundary: &str, bytes: Vec<u8>) -> Result<Multipart, Error> {
+impl Default for Spill {
+	fn default() -> Spill {
+		Spill {
+			threshold: 1024 * 1024,
+			temp_dir: env::temp_dir(),
+			max_part: None,
+			max_total: None,
+		}
+	}
+}
+
+/// Temporary file, removed on drop.
+#[cfg(feature = "server")]
+#[derive(Debug, PartialEq)]
+struct TempFile {
+	path: PathBuf,
+}
+
+#[cfg(feature = "server")]
+impl TempFile {
+	/// Named unpredictably, and (on unix) only readable by its owner, as the temp dir is usually shared.
+	fn create(dir: &Path) -> Result<(TempFile, File), Error> {
+		let mut options = fs::OpenOptions::new();
+		options.write(true).create_new(true);
+
+		#[cfg(unix)]
+		options.mode(0o600);
+
+		loop {
+			let path = dir.join(format!("slimweb-{}.part", random_hex()));
+
+			match options.open(&path) {
+				Ok(file) => return Ok((TempFile { path }, file)),
+				Err(ref e) if e.kind() == ErrorKind::AlreadyExists => continue,
+				Err(e) => return Err(e.into()),
+			}
+		}
+	}
+}
+
+#[cfg(feature = "server")]
+impl Drop for TempFile {
+	fn drop(&mut self) {
+		let _ = fs::remove_file(&self.path);
+	}
+}
+
+/// Collects part data, moving it to disk once it outgrows the threshold.
+/// Fields without a filename are no exception, as they can be just as large.
+#[cfg(feature = "server")]
+struct Collector<'s> {
+	spill: &'s Spill,
+	len: usize,
+	memory: Vec<u8>,
+	file: Option<(TempFile, BufWriter<File>)>,
+}
+
+#[cfg(feature = "server")]
+impl<'s> Collector<'s> {
+	fn new(spill: &'s Spill) -> Collector<'s> {
+		Collector {
+			spill,
+			len: 0,
+			memory: Vec::new(),
+			file: None,
+		}
+	}
+
+	fn write(&mut self, bytes: &[u8]) -> Result<(), Error> {
+		self.len += bytes.len();
+
+		if self.spill.max_part.is_some_and(|max| self.len > max) {
+			return Err(Error::PayloadTooLarge);
+		}
+
+		if self.file.is_none() && self.len > self.spill.threshold {
+			let (temp, file) = TempFile::create(&self.spill.temp_dir)?;
+			let mut file = BufWriter::new(file);
+
+			file.write_all(&self.memory)?;
+			self.memory = Vec::new();
+			self.file = Some((temp, file));
+		}
+
+		match &mut self.file {
+			Some((_, file)) => file.write_all(bytes)?,
+			None => self.memory.extend_from_slice(bytes),
+		}
+
+		Ok(())
+	}
+
+	fn finish(self) -> Result<Data, Error> {
+		match self.file {
+			Some((temp, mut file)) => {
+				file.flush()?;
+
+				Ok(Data::File(Arc::new(temp)))
+			},
+			None => Ok(Data::Memory(self.memory)),
+		}
+	}
+}
+
+/// Parses multipart/form-data as it's read, so the body is never buffered whole.
+/// Part data is kept byte for byte.
+#[cfg(feature = "server")]
+pub(crate) fn from_reader<R: Read>(boundary: &str, reader: R, spill: &Spill, max_body: Option<usize>) -> Result<Multipart, Error> {
+	if boundary.is_empty() || boundary.len() > 70 {
+		return Err(Error::MultipartError);
+	}
+
 	let mut multi = Multipart {
 		boundary: boundary.to_string(),
+		generated: false,
 		parts: Vec::new(),
 	};
 
-	let mut lines = Vec::new();
+	// Every delimiter is preceded by CRLF, except a leading one. Priming the buffer covers that case.
+	let delimiter = format!("\r\n--{}", boundary).into_bytes();
+	let mut scanner = Scanner {
+		reader,
+		buf: b"\r\n".to_vec(),
+		eof: false,
+		read: 0,
+		// Whichever of the body and multipart limits is lower.
+		max: spill.max_total.into_iter().chain(max_body).min(),
+	};
 
-	{ // Prepare parts for processing by line.
-		let mut line = Vec::new();
+	// Preamble is ignored.
+	scanner.copy_until(&delimiter, &mut |_| Ok(()))?;
 
-		for byte in bytes {
-			if byte == b'\n' {
-				if line.ends_with(&[b'\r']) {
-					line.truncate(line.len() - 1);
-				}
+	loop {
+		// Close delimiter ends the body, anything after it is epilogue.
+		if scanner.next_is(b"--")? {
+			return Ok(multi);
+		}
 
-				lines.push(line.clone());
+		// Transport padding may follow a delimiter.
+		if !scanner.read_line()?.iter().all(|byte| *byte == b' ' || *byte == b'\t') {
+			return Err(Error::MultipartError);
+		}
 
-				line.clear();
-			} else {
-				line.push(byte);
+		let mut part = Part {
+			name: String::new(),
+			filename: None,
+			content_type: None,
+			data: Data::Memory(Vec::new()),
+		};
+
+		loop {
+			let line = scanner.read_line()?;
+
+			if line.is_empty() {
+				break;
 			}
+
+			part.set_header(&String::from_utf8_lossy(&line));
 		}
+
+		let mut collector = Collector::new(spill);
+
+		scanner.copy_until(&delimiter, &mut |bytes| collector.write(bytes))?;
+
+		part.data = collector.finish()?;
+
+		multi.parts.push(part);
 	}
+}
 
-	let mut new_part: Option<Part> = None;
-	let mut part_body = false;
+/// Splits header parameters (e.g. `form-data; name="a;b"`), unquoting values.
+/// Items without a value (like `form-data`) are skipped.
+#[cfg(feature = "server")]
+pub(crate) fn parameters(value: &str) -> Vec<(String, String)> {
+	let mut params = Vec::new();
+	let mut chars = value.chars();
+
+	loop {
+		let mut key = String::new();
+		let mut val = String::new();
+		let mut has_value = false;
+		let mut more = false;
+
+		while let Some(c) = chars.next() {
+			match c {
+				';' => {
+					more = true;
+
+					break;
+				},
+				'=' if !has_value => has_value = true,
+				'"' if has_value && val.trim().is_empty() => {
+					val.clear();
+
+					// Quoted string, backslash escapes the next character.
+					while let Some(c) = chars.next() {
+						match c {
+							'"' => break,
+							'\\' => val.extend(chars.next()),
+							c => val.push(c),
+						}
+					}
+				},
+				c if has_value => val.push(c),
+				c => key.push(c),
+			}
+		}
 
-	for line in lines {
-		// Check for boundary and new part.
-		if &line[line.len() - boundary.len()..] == boundary.as_bytes() && new_part.is_some() {
-			multi.parts.push(new_part.clone().unwrap());
+		if has_value {
+			params.push((key.trim().to_ascii_lowercase(), val.trim().to_string()));
+		}
 
-			new_part = None;
-			part_body = false;
+		if !more {
+			return params;
+		}
+	}
+}
 
-			continue;
+#[cfg(feature = "server")]
+impl Part {
+	fn set_header(&mut self, line: &str) {
+		let mut split = line.splitn(2, ':');
+		let key = split.next().unwrap_or("").trim();
+		let value = split.next().unwrap_or("").trim();
+
+		if key.eq_ignore_ascii_case("Content-Disposition") {
+			let mut extended = None;
+
+			for (key, value) in parameters(value) {
+				match key.as_str() {
+					"name" => self.name = value,
+					"filename" => self.filename = Some(value),
+					// RFC 5987 (charset'language'value), wins over a plain filename.
+					"filename*" => extended = value.splitn(3, '\'').nth(2).map(|value| urlencoded::decode(value, false)),
+					_ => (),
+				}
+			}
+
+			if extended.is_some() {
+				self.filename = extended;
+			}
+		} else if key.eq_ignore_ascii_case("Content-Type") {
+			self.content_type = Some(value.to_string());
 		}
+	}
+}
 
-		if new_part.is_none() {
-			new_part = Some(Part {
-				name: String::new(),
-				filename: None,
-				content_type: None,
-				data: Vec::new(),
-			});
+/// Buffered reader that can stop at a delimiter anywhere in the stream.
+#[cfg(feature = "server")]
+struct Scanner<R: Read> {
+	reader: R,
+	buf: Vec<u8>,
+	eof: bool,
+	// Bytes pulled from reader so far, and how many are allowed.
+	read: usize,
+	max: Option<usize>,
+}
+
+#[cfg(feature = "server")]
+impl<R: Read> Scanner<R> {
+	/// Reads more into the buffer. Errors if the body ends first.
+	fn fill(&mut self) -> Result<(), Error> {
+		if self.eof {
+			return Err(Error::MultipartError);
 		}
 
-		if line.is_empty() {
-			part_body = true;
+		let len = self.buf.len();
+		self.buf.resize(len + 8 * 1024, 0);
+
+		let read = self.reader.read(&mut self.buf[len..]);
+		self.buf.truncate(len + *read.as_ref().unwrap_or(&0));
 
-			continue;
+		let read = read?;
+
+		if read == 0 {
+			self.eof = true;
+
+			return Err(Error::MultipartError);
 		}
 
-		if !part_body {
-			if let Ok(text) = std::str::from_utf8(&line) {
-				let split_text: Vec<&str> = text.split(':').collect();
+		self.read += read;
 
-				if split_text[0] == "Content-Disposition" {
-					let split_text: Vec<&str> = split_text[1].trim().split(';').collect();
+		if self.max.is_some_and(|max| self.read > max) {
+			return Err(Error::PayloadTooLarge);
+		}
 
-					for split in split_text {
-						let split = split.trim();
+		Ok(())
+	}
 
-						if split != "form-data" {
-							if let Some(ref mut part) = new_part {
-								if split.starts_with("name=") {
-									part.name = split[5..].to_string();
-								}
+	/// Hands everything before delimiter to sink, and consumes the delimiter.
+	fn copy_until(&mut self, delimiter: &[u8], sink: &mut dyn FnMut(&[u8]) -> Result<(), Error>) -> Result<(), Error> {
+		loop {
+			if let Some(idx) = find(&self.buf, delimiter) {
+				sink(&self.buf[..idx])?;
+				self.buf.drain(..idx + delimiter.len());
 
-								if split.starts_with("filename=") {
-									part.filename = Some(split[9..].to_string());
-								}
-							}
-						}
-					}
-				} else if split_text[0] == "Content-Type" {
-					if let Some(ref mut part) = new_part {
-						part.content_type = Some(split_text[1].trim().to_string());
-					}
-				}
+				return Ok(());
+			}
+
+			// Hold back just enough to catch a delimiter split across reads.
+			let keep = delimiter.len() - 1;
+
+			if self.buf.len() > keep {
+				let done = self.buf.len() - keep;
+
+				sink(&self.buf[..done])?;
+				self.buf.drain(..done);
+			}
+
+			self.fill()?;
+		}
+	}
+
+	/// Reads a CRLF terminated line, without the CRLF.
+	fn read_line(&mut self) -> Result<Vec<u8>, Error> {
+		loop {
+			if let Some(idx) = find(&self.buf, b"\r\n") {
+				let line = self.buf[..idx].to_vec();
+				self.buf.drain(..idx + 2);
+
+				return Ok(line);
+			}
+
+			if self.buf.len() > MAX_HEADER_LINE {
+				return Err(Error::MultipartError);
 			}
-		} else if let Some(ref mut part) = new_part {
-			part.data.extend(line);
+
+			self.fill()?;
 		}
 	}
 
-	Ok(multi)
+	/// Consumes bytes if they're next.
+	fn next_is(&mut self, bytes: &[u8]) -> Result<bool, Error> {
+		while self.buf.len() < bytes.len() {
+			self.fill()?;
+		}
+
+		if self.buf.starts_with(bytes) {
+			self.buf.drain(..bytes.len());
+
+			Ok(true)
+		} else {
+			Ok(false)
+		}
+	}
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+	haystack
+		.windows(needle.len())
+		.position(|window| window == needle)
 }
@@ -1,15 +1,25 @@
 use std::{
-	net::{ ToSocketAddrs, TcpListener },
+	net::{ ToSocketAddrs, TcpListener, IpAddr },
+	collections::HashMap,
+	convert::TryInto,
 	time::{ Instant, Duration },
 	io::{
-		BufReader, Write,
+		BufReader, BufRead, Write,
+		ErrorKind,
 		Result as IoResult,
+		Error as IoError,
 	},
-	sync::{ Arc, Mutex },
-	collections::HashMap,
+	sync::{
+		Arc, Mutex,
+		mpsc::{ self, RecvTimeoutError },
+	},
+	panic::{ self, AssertUnwindSafe },
+	thread,
 };
 #[cfg(feature = "tls")]
 use std::fs::File;
+#[cfg(feature = "multipart")]
+use std::path::PathBuf;
 
 #[cfg(feature = "tls")]
 use rustls::{
@@ -21,17 +31,27 @@ use crate::{
 	error::Error,
 	stream::{
 		self,
-		Stream, Chunked, Compressed,
+		Stream, Chunked, Compressed, Coding, Deadline, HeadRules,
 	},
 	GeneralInfo, StatusInfo,
 };
+#[cfg(feature = "multipart")]
+use crate::multipart;
 
 mod request;
 mod response;
+mod pool;
+mod router;
+mod shutdown;
 #[cfg(test)] mod tests;
 
 use request::ServerRequest;
+use pool::Pool;
+use router::Router;
+use shutdown::Shutdown;
 pub use self::response::ServerResponse;
+pub use self::pool::Saturation;
+pub use self::shutdown::ShutdownHandle;
 
 
 
@@ -43,33 +63,218 @@ pub type ServerHandler = Box<dyn Fn(&ServerRequest) -> Result<ServerResponse, Er
 /// Returns status code (should be 100 if good, or the proper status code for any error), and message if one is needed.
 pub type ExpectHandler = Box<dyn Fn(&GeneralInfo) -> Result<(i32, Option<String>), Error> + Send + Sync>;
 
+/// Handler function defined by user.
+/// Builds the response for requests no handler answered: 404 (unknown path), 405 (path exists under other methods),
+/// 500 (handler failed or panicked) and 503 (handler overran its time). Receives the status code the server would send.
+pub type FallbackHandler = Box<dyn Fn(i32, &ServerRequest) -> Result<ServerResponse, Error> + Send + Sync>;
+
+type Handlers = Arc<Mutex<Router>>;
+
 /// Generic HTTP 1.1 Server.
 pub struct Server {
 	listener: TcpListener,
-	handlers: Arc<Mutex<HashMap<(String, String), ServerHandler>>>,
+	handlers: Handlers,
 	expect_handlers: Arc<Mutex<Vec<ExpectHandler>>>,
+	fallback_handler: Option<Arc<FallbackHandler>>,
 
 	#[cfg(feature = "tls")]
 	tls_config: Option<Arc<ServerConfig>>,
 
-	// deadline, reset
-	deadline: Option<(Instant, Instant)>,
+	budgets: Budgets,
+	limits: Limits,
+	#[cfg(feature = "compress")]
+	compression: Compression,
+
+	workers: Option<usize>,
+	queue_size: usize,
+	saturation: Saturation,
+
+	max_requests: usize,
+	keep_alive_timeout: Duration,
+	max_connections_per_ip: Option<usize>,
+
+	shutdown: Arc<Shutdown>,
+	shutdown_grace: Duration,
+}
+
+/// Time budgets, armed separately for every request.
+#[derive(Debug, Clone, Copy, Default)]
+struct Budgets {
+	// Whole request, from its first header byte to the end of the response.
+	total: Option<Duration>,
+	// Counted from accept, for a connection's first request.
+	header: Option<Duration>,
+	body: Option<Duration>,
+	handler: Option<Duration>,
+	write: Option<Duration>,
+	// Slowest body upload allowed, in bytes a second.
+	min_body_rate: Option<u64>,
+}
+
+/// Accepted connection, on its way to being handled.
+struct Connection {
+	tcp: Stream,
+	accepted: Instant,
+	// Freed along with the connection.
+	_slot: Option<PeerSlot>,
+}
+
+/// Connections open per peer address.
+#[derive(Clone, Default)]
+struct Peers(Arc<Mutex<HashMap<IpAddr, usize>>>);
+
+impl Peers {
+	/// Takes a slot for the peer, unless it already holds `max` of them.
+	fn claim(&self, ip: IpAddr, max: usize) -> Option<PeerSlot> {
+		let mut peers = self.0.lock().unwrap();
+		let open = peers.entry(ip).or_insert(0);
+
+		if *open >= max {
+			return None;
+		}
+
+		*open += 1;
+
+		Some(PeerSlot {
+			peers: self.clone(),
+			ip,
+		})
+	}
+}
+
+/// One of a peer's connections, given back on drop.
+struct PeerSlot {
+	peers: Peers,
+	ip: IpAddr,
+}
+
+impl Drop for PeerSlot {
+	fn drop(&mut self) {
+		let mut peers = self.peers.0.lock().unwrap();
+
+		if let Some(open) = peers.get_mut(&self.ip) {
+			*open -= 1;
+
+			if *open == 0 {
+				peers.remove(&self.ip);
+			}
+		}
+	}
+}
+
+/// Limits on what a request may send.
+#[derive(Debug, Clone)]
+pub(crate) struct Limits {
+	head: HeadRules,
+	body: Option<usize>,
+	chunk_extension: usize,
+	#[cfg(feature = "multipart")]
+	multipart: multipart::Spill,
+}
+
+impl Default for Limits {
+	fn default() -> Limits {
+		Limits {
+			head: HeadRules {
+				max_fields: Some(100),
+				max_size: Some(64 * 1024),
+				..HeadRules::default()
+			},
+			body: None,
+			chunk_extension: stream::MAX_CHUNK_EXTENSION,
+			#[cfg(feature = "multipart")]
+			multipart: multipart::Spill::default(),
+		}
+	}
+}
+
+/// Which responses are worth compressing.
+#[cfg(feature = "compress")]
+#[derive(Debug, Clone)]
+struct Compression {
+	// Smaller bodies (in bytes) are sent as they are.
+	min_size: u64,
+	// Media types compressed, lowercase, `type/*` covering a whole type.
+	types: Vec<String>,
+}
+
+#[cfg(feature = "compress")]
+impl Default for Compression {
+	fn default() -> Compression {
+		Compression {
+			min_size: 1024,
+			types: ["text/*", "application/json", "application/javascript", "application/xml", "application/x-www-form-urlencoded", "image/svg+xml"]
+				.iter()
+				.map(|media_type| media_type.to_string())
+				.collect(),
+		}
+	}
+}
+
+#[cfg(feature = "compress")]
+impl Compression {
+	fn allows(&self, content_type: Option<&String>) -> bool {
+		let media_type = content_type
+			.and_then(|content_type| content_type.split(';').next())
+			.unwrap_or("")
+			.trim()
+			.to_ascii_lowercase();
+
+		self.types.iter().any(|allowed| match allowed.strip_suffix('*') {
+			Some(range) => range.ends_with('/') && media_type.starts_with(range),
+			None => *allowed == media_type,
+		})
+	}
+}
+
+/// Everything a connection needs, shareable across worker threads.
+#[derive(Clone)]
+struct Context {
+	handlers: Handlers,
+	expect_handlers: Arc<Mutex<Vec<ExpectHandler>>>,
+	fallback_handler: Option<Arc<FallbackHandler>>,
+	budgets: Budgets,
+	limits: Limits,
+	#[cfg(feature = "compress")]
+	compression: Compression,
+	max_requests: usize,
+	keep_alive_timeout: Duration,
+	shutdown: Arc<Shutdown>,
 }
 
 impl Server {
 	/// Creates new Server with desired listening host.
 	pub fn new<A: ToSocketAddrs>(host: A) -> Result<Server, Error> {
 		let listener = TcpListener::bind(host)?;
+		let shutdown = Shutdown::new(listener.local_addr()?);
 
 		Ok(Server {
 			listener,
-			handlers: Arc::new(Mutex::new(HashMap::new())),
+			handlers: Arc::new(Mutex::new(Router::new())),
 			expect_handlers: Arc::new(Mutex::new(Vec::new())),
+			fallback_handler: None,
 
 			#[cfg(feature = "tls")]
 			tls_config: None,
 
-			deadline: None,
+			budgets: Budgets {
+				header: Some(Duration::from_secs(30)),
+				..Budgets::default()
+			},
+			limits: Limits::default(),
+			#[cfg(feature = "compress")]
+			compression: Compression::default(),
+
+			workers: None,
+			queue_size: 32,
+			saturation: Saturation::Block,
+
+			max_requests: 100,
+			keep_alive_timeout: Duration::from_secs(5),
+			max_connections_per_ip: None,
+
+			shutdown,
+			shutdown_grace: Duration::from_secs(30),
 		})
 	}
 
@@ -111,11 +316,28 @@ impl Server {
 	}
 
 	/// Adds a user defined handler to server.
-	/// Handlers match method and resource, and call defined function.
+	/// Handlers match method and route, and call defined function.
+	///
+	/// Routes can capture segments with `:name` (e.g. `/users/:id`), and the rest of the path with a trailing `*name`,
+	/// which needs at least one more segment to match.
+	/// Captures are available through `req.param("name")`. Static segments win over captures.
+	///
+	/// Panics if a wildcard isn't the last segment of the route.
 	pub fn add_handler<S: Into<String>>(self, method: S, route: S, handler: impl Fn(&ServerRequest) -> Result<ServerResponse, Error> + 'static + Send + Sync) -> Server {
 		self.handlers
 			.lock().unwrap()
-			.insert((method.into(), route.into()), Box::new(handler));
+			.insert(method.into(), &route.into(), Box::new(handler), false);
+
+		self
+	}
+
+	/// Adds a user defined handler that reads the request body itself, through `req.body_reader()`.
+	/// The body isn't buffered (or parsed) into `req.body`, so it never has to fit in memory.
+	/// Whatever the handler leaves unread is discarded before the response is sent.
+	pub fn add_streaming_handler<S: Into<String>>(self, method: S, route: S, handler: impl Fn(&ServerRequest) -> Result<ServerResponse, Error> + 'static + Send + Sync) -> Server {
+		self.handlers
+			.lock().unwrap()
+			.insert(method.into(), &route.into(), Box::new(handler), true);
 
 		self
 	}
@@ -130,152 +352,819 @@ impl Server {
 		self
 	}
 
-	/// Sets deadline for request.
+	/// Sets a user defined fallback handler, replacing the empty 404, 405, 500 and 503 responses.
+	/// The Allow header is still added to 405 responses, unless the handler sets one.
+	pub fn set_fallback_handler(mut self, handler: impl Fn(i32, &ServerRequest) -> Result<ServerResponse, Error> + 'static + Send + Sync) -> Server {
+		self.fallback_handler = Some(Arc::new(Box::new(handler)));
+
+		self
+	}
+
+	/// Sets deadline (in seconds) for every request, from reading its head to writing the response.
+	/// Armed again for each request on a persistent connection.
 	pub fn set_deadline(mut self, time: u64) -> Server {
-		self.deadline = Some((Instant::now() + Duration::from_secs(time), Instant::now()));
+		self.budgets.total = Some(Duration::from_secs(time));
+
+		self
+	}
+
+	/// Sets time (in seconds) allowed for reading a request's head (default 30).
+	/// For a connection's first request, it's counted from the moment the connection is accepted,
+	/// so a client trickling its head in (or one left waiting for a worker) can't hold on to it.
+	pub fn set_header_timeout(mut self, time: u64) -> Server {
+		self.budgets.header = Some(Duration::from_secs(time));
+
+		self
+	}
+
+	/// Sets time (in seconds) allowed for reading a request's body.
+	pub fn set_body_timeout(mut self, time: u64) -> Server {
+		self.budgets.body = Some(Duration::from_secs(time));
+
+		self
+	}
+
+	/// Sets slowest average rate (in bytes a second) a request body may be sent at.
+	/// Bodies get a 2 second grace period, then every `rate` bytes received buys another second.
+	/// Slower ones have their connection dropped. Only time spent waiting on the client counts, so streaming handlers
+	/// reading the body themselves aren't cut off for reading it slowly.
+	pub fn set_min_body_rate(mut self, rate: u64) -> Server {
+		self.budgets.min_body_rate = Some(rate);
+
+		self
+	}
+
+	/// Sets time (in seconds) a handler may take.
+	/// Handlers aren't interrupted, but overrunning ones get a 503 in place of their response.
+	pub fn set_handler_timeout(mut self, time: u64) -> Server {
+		self.budgets.handler = Some(Duration::from_secs(time));
+
+		self
+	}
+
+	/// Sets time (in seconds) allowed for writing a response.
+	pub fn set_write_timeout(mut self, time: u64) -> Server {
+		self.budgets.write = Some(Duration::from_secs(time));
+
+		self
+	}
+
+	/// Sets number of worker threads handling connections.
+	/// Without workers, connections are handled one at a time: each gets a thread of its own, which the accept loop
+	/// waits on before taking the next, so there's never more than one (see `set_shutdown_grace`).
+	pub fn set_workers(mut self, workers: usize) -> Server {
+		self.workers = Some(workers.max(1));
+
+		self
+	}
+
+	/// Sets max number of connections a single peer address may hold open at once (unlimited by default).
+	/// Connections past it are closed straight away, without a response.
+	pub fn set_max_connections_per_ip(mut self, max: usize) -> Server {
+		self.max_connections_per_ip = Some(max.max(1));
+
+		self
+	}
+
+	/// Sets number of accepted connections that can wait for a free worker (default 32).
+	pub fn set_queue_size(mut self, queue_size: usize) -> Server {
+		self.queue_size = queue_size;
+
+		self
+	}
+
+	/// Sets what happens to new connections once workers and queue are full (default Block).
+	pub fn set_saturation(mut self, saturation: Saturation) -> Server {
+		self.saturation = saturation;
+
+		self
+	}
+
+	/// Sets max requests served over one persistent connection (default 100).
+	/// Setting this to 1 disables keep-alive.
+	pub fn set_max_requests(mut self, max_requests: usize) -> Server {
+		self.max_requests = max_requests.max(1);
+
+		self
+	}
+
+	/// Sets how long (in seconds) an idle persistent connection waits for its next request (default 5).
+	pub fn set_keep_alive_timeout(mut self, time: u64) -> Server {
+		self.keep_alive_timeout = Duration::from_secs(time);
+
+		self
+	}
+
+	/// Sets how long (in seconds) requests in flight get to finish after a shutdown (default 30).
+	/// Connections still busy after it are left behind, `run` returns without them.
+	pub fn set_shutdown_grace(mut self, time: u64) -> Server {
+		self.shutdown_grace = Duration::from_secs(time);
+
+		self
+	}
+
+	/// Handle stopping the server once it's running (see `ShutdownHandle::shutdown`).
+	/// Has to be taken before calling `run`, which blocks until then.
+	pub fn shutdown_handle(&self) -> ShutdownHandle {
+		ShutdownHandle::new(self.shutdown.clone())
+	}
+
+	/// Sets whether request lines, chunk framing included, may end with a bare LF, rather than CRLF (default false).
+	/// Requests breaking any other rule of RFC 7230 that could let them be smuggled are always answered with 400.
+	pub fn set_allow_bare_lf(mut self, allow: bool) -> Server {
+		self.limits.head.bare_lf = allow;
+
+		self
+	}
+
+	/// Sets max number of header fields a request may send (default 100). More are answered with 431.
+	pub fn set_max_header_count(mut self, count: usize) -> Server {
+		self.limits.head.max_fields = Some(count);
+
+		self
+	}
+
+	/// Sets max size (in bytes) of a request head, from its request line to the empty line closing it (default 64 KiB).
+	/// Larger ones are answered with 431. Single lines are also capped at 8 KiB.
+	pub fn set_max_header_size(mut self, size: usize) -> Server {
+		self.limits.head.max_size = Some(size);
+
+		self
+	}
+
+	/// Sets max size (in bytes) of a request body, as sent and once decoded (unlimited by default).
+	/// Larger ones are answered with 413, before they're read when their Content-Length gives them away.
+	pub fn set_max_body_size(mut self, size: usize) -> Server {
+		self.limits.body = Some(size);
+
+		self
+	}
+
+	/// Sets max length (in bytes) of the extensions of a single chunk (default 256). Longer ones are answered with 413.
+	pub fn set_max_chunk_extension(mut self, size: usize) -> Server {
+		self.limits.chunk_extension = size;
+
+		self
+	}
+
+	/// Sets size (in bytes) above which multipart fields, files or not, are moved from memory to the temp directory (default 1 MiB).
+	#[cfg(feature = "multipart")]
+	pub fn set_multipart_threshold(mut self, size: usize) -> Server {
+		self.limits.multipart.threshold = size;
+
+		self
+	}
+
+	/// Sets directory large multipart fields are moved to (default is the system's temp directory).
+	#[cfg(feature = "multipart")]
+	pub fn set_multipart_temp_dir<P: Into<PathBuf>>(mut self, dir: P) -> Server {
+		self.limits.multipart.temp_dir = dir.into();
+
+		self
+	}
+
+	/// Sets max size (in bytes) of a single multipart field. Larger ones are answered with 413.
+	#[cfg(feature = "multipart")]
+	pub fn set_max_part_size(mut self, size: usize) -> Server {
+		self.limits.multipart.max_part = Some(size);
+
+		self
+	}
+
+	/// Sets max size (in bytes) of a whole multipart body. Larger ones are answered with 413.
+	#[cfg(feature = "multipart")]
+	pub fn set_max_multipart_size(mut self, size: usize) -> Server {
+		self.limits.multipart.max_total = Some(size);
+
+		self
+	}
+
+	/// Sets size (in bytes) below which response bodies aren't compressed (default 1 KiB).
+	/// Bodies of unknown size always are.
+	#[cfg(feature = "compress")]
+	pub fn set_compression_min_size(mut self, size: u64) -> Server {
+		self.compression.min_size = size;
+
+		self
+	}
+
+	/// Sets media types of responses that are compressed, `type/*` covering a whole type.
+	/// Defaults to text, JSON, JavaScript, XML, forms and SVG, as most other media is compressed already.
+	#[cfg(feature = "compress")]
+	pub fn set_compressible_types(mut self, types: &[&str]) -> Server {
+		self.compression.types = types
+			.iter()
+			.map(|media_type| media_type.trim().to_ascii_lowercase())
+			.collect();
 
 		self
 	}
 
 	/// Start server loop, and begin handling requests.
+	/// Runs until it's stopped through a `ShutdownHandle`.
 	pub fn run(&mut self) -> IoResult<()> {
 		let local_addr = self.listener.local_addr()?;
 		info!("Server running on: {}:{}", local_addr.ip().to_string(), local_addr.port());
 
-		let handlers = self.handlers.clone();
-		let expect_handlers = self.expect_handlers.clone();
+		let context = Context {
+			handlers: self.handlers.clone(),
+			expect_handlers: self.expect_handlers.clone(),
+			fallback_handler: self.fallback_handler.clone(),
+			budgets: self.budgets,
+			limits: self.limits.clone(),
+			#[cfg(feature = "compress")]
+			compression: self.compression.clone(),
+			max_requests: self.max_requests,
+			keep_alive_timeout: self.keep_alive_timeout,
+			shutdown: self.shutdown.clone(),
+		};
+
+		let pool = self.workers.map(|workers| {
+			let context = context.clone();
+
+			Pool::new(workers, self.queue_size, move |mut conn: Connection| {
+				if let Err(e) = handle_connection(&mut conn.tcp, &context, conn.accepted) {
+					warn!("Connection failed: {}", e);
+				}
+			})
+		});
+
+		let peers = Peers::default();
 
 		for stream in self.listener.incoming() {
-			let mut tcp;
+			// Anything accepted after a shutdown, including the connection made to wake us, is dropped.
+			if self.shutdown.requested() {
+				break;
+			}
+
+			let stream = match stream {
+				Ok(stream) => stream,
+				Err(e) => {
+					accept_failed(&self.listener, e)?;
+
+					continue;
+				},
+			};
+			let accepted = Instant::now();
+			let peer = stream.peer_addr().map(|addr| addr.ip());
+
+			// Closed without a word, as answering (or a TLS handshake) could let the peer stall the accept loop.
+			let slot = match (self.max_connections_per_ip, peer) {
+				(Some(max), Ok(ip)) => match peers.claim(ip, max) {
+					Some(slot) => Some(slot),
+					None => {
+						warn!("Too many connections from {}, closing connection.", ip);
+
+						continue;
+					},
+				},
+				_ => None,
+			};
+
+			let tcp;
 
 			#[cfg(feature = "tls")]
 			{
 				if self.tls_config.is_some() {
-					tcp = Stream::HttpsServer(BufReader::new(Box::new(StreamOwned::new(ServerSession::new(&self.tls_config.as_ref().unwrap()), stream?))));
+					tcp = Stream::HttpsServer(BufReader::new(Box::new(StreamOwned::new(ServerSession::new(&self.tls_config.as_ref().unwrap()), stream))));
 				} else {
-					tcp = Stream::Http(BufReader::new(stream?));
+					tcp = Stream::Http(BufReader::new(stream));
 				}
 			}
 
 			#[cfg(not(feature = "tls"))]
 			{
-				tcp = Stream::Http(BufReader::new(stream?));
+				tcp = Stream::Http(BufReader::new(stream));
 			}
 
-			let info = stream::process_lines(&mut tcp)?;
+			let conn = Connection {
+				tcp,
+				accepted,
+				_slot: slot,
+			};
 
-			let mut continue_100 = false;
-			let mut continue_code = 100;
-			let mut continue_msg = None;
+			if let Some(pool) = &pool {
+				// Dropped unanswered, for the same reason as connections over the per-peer cap.
+				if pool.execute(conn, self.saturation).is_err() {
+					warn!("Worker pool saturated, closing connection.");
+				}
+			} else if !handle_inline(conn, &context, self.shutdown_grace) {
+				warn!("Request still in flight after the shutdown grace period, leaving it behind.");
 
-			let expect_handlers = expect_handlers.lock().unwrap();
+				break;
+			}
+		}
 
-			// Check for 100-Continue
-			if info.headers.get("Expect").is_some() && !expect_handlers.is_empty() {
-				continue_100 = true;
+		info!("Server shutting down.");
 
-				for handler in expect_handlers.iter() {
-					let (code, message) = handler(&info)?;
+		if let Some(pool) = pool {
+			if !pool.shutdown(self.shutdown_grace) {
+				warn!("Requests still in flight after the shutdown grace period, leaving them behind.");
+			}
+		}
 
-					if code != 100 {
-						continue_code = code;
-						continue_msg = message;
+		self.shutdown.reset();
 
-						break;
-					}
-				}
-			}
+		Ok(())
+	}
+}
+
+/// Decides whether a failed `accept` stops the server, which only errors of the listener itself do.
+/// Anything else (aborted connections, interrupts, running out of file descriptors) is logged and passes.
+fn accept_failed(listener: &TcpListener, err: IoError) -> IoResult<()> {
+	if listener.local_addr().is_err() {
+		return Err(err);
+	}
+
+	warn!("Failed to accept connection: {}", err);
+
+	// File descriptors only free up as connections close, so give them a moment rather than spinning.
+	if is_out_of_files(&err) {
+		thread::sleep(Duration::from_millis(100));
+	}
+
+	Ok(())
+}
 
-			if continue_100 {
-				let mut resp = ServerResponse::new(continue_code)?;
+/// EMFILE or ENFILE.
+fn is_out_of_files(err: &IoError) -> bool {
+	#[cfg(unix)]
+	{ matches!(err.raw_os_error(), Some(23) | Some(24)) }
 
-				if continue_code == 100 {
-					let resp_vec: Vec<u8> = resp.into();
+	#[cfg(windows)]
+	{ err.raw_os_error() == Some(10024) }
 
-					// TODO: This isn't following deadlines. That needs to change.
-					tcp.write_all(&resp_vec)?;
-					tcp.flush()?;
+	#[cfg(not(any(unix, windows)))]
+	{ false }
+}
+
+/// Handles a connection before accepting the next one (without workers), so only one of these threads ever runs.
+/// Returns false if it was left behind, still running once a shutdown's grace period ran out, which stops the accept loop.
+fn handle_inline(mut conn: Connection, context: &Context, grace: Duration) -> bool {
+	let (done, finished) = mpsc::channel();
+	let shared = context.clone();
+
+	// Own thread, so a handler can't hold a shutdown up for longer than a worker's could.
+	thread::spawn(move || {
+		if let Err(e) = handle_connection(&mut conn.tcp, &shared, conn.accepted) {
+			warn!("Connection failed: {}", e);
+		}
+
+		let _ = done.send(());
+	});
+
+	let mut until: Option<Instant> = None;
 
-					process_request(&mut tcp, info, &mut self.deadline, handlers.clone())?;
-				} else { // something didn't pass expectations
-					if let Some(msg) = continue_msg {
-						resp = resp.set_body(msg.as_str());
-					}
+	loop {
+		let wait = until.map_or(Duration::from_millis(100), |until| until.saturating_duration_since(Instant::now()));
 
-					let resp_vec: Vec<u8> = resp.into();
+		match finished.recv_timeout(wait) {
+			Err(RecvTimeoutError::Timeout) if until.is_some() => return false,
+			Err(RecvTimeoutError::Timeout) => {},
+			// Finished (or panicked).
+			_ => return true,
+		}
+
+		// Grace periods too large to count down never run out.
+		if until.is_none() && context.shutdown.requested() {
+			until = Instant::now().checked_add(grace);
+		}
+	}
+}
 
-					// TODO: This isn't following deadlines. That needs to change.
-					tcp.write_all(&resp_vec)?;
-					tcp.flush()?;
+fn handle_connection(tcp: &mut Stream, context: &Context, accepted: Instant) -> Result<(), Error> {
+	let mut served = 0;
+
+	loop {
+		// Connections nothing has arrived on yet don't hold up a shutdown.
+		// Budgets too large to be counted from accept are none at all.
+		let idle = if served == 0 {
+			context.budgets.header
+				.and_then(|budget| accepted.checked_add(budget))
+				.map(|until| until.saturating_duration_since(Instant::now()))
+		} else {
+			Some(context.keep_alive_timeout)
+		};
+
+		if let Some(idle) = idle {
+			if !wait_for_request(tcp, idle, &context.shutdown)? {
+				// Connection was accepted before the shutdown, so it's told why rather than just dropped.
+				if served == 0 && context.shutdown.requested() {
+					reject(tcp, 503);
 				}
-			} else {
-				process_request(&mut tcp, info, &mut self.deadline, handlers.clone())?;
+
+				break;
 			}
 		}
 
-		Ok(())
+		let started = if served == 0 {
+			accepted
+		} else {
+			Instant::now()
+		};
+
+		let keep_alive = handle_request(tcp, context, started, served + 1 < context.max_requests)?;
+
+		served += 1;
+
+		if !keep_alive {
+			break;
+		}
 	}
+
+	Ok(())
 }
 
-fn process_request(stream: &mut Stream, info: GeneralInfo, deadline: &mut Option<(Instant, Instant)>, handlers: Arc<Mutex<HashMap<(String, String), ServerHandler>>>) -> Result<(), Error> {
-	let req = ServerRequest::new(stream, info, deadline)?;
+/// Waits for a request to start arriving.
+/// Returns false if the peer closed the connection, stayed idle for too long, or the server is shutting down
+/// (and the request hadn't already started arriving).
+fn wait_for_request(tcp: &mut Stream, timeout: Duration, shutdown: &Shutdown) -> Result<bool, Error> {
+	// Timeouts too large to count down never run out.
+	let until = Instant::now().checked_add(timeout);
 
-	if let StatusInfo::Request(method, resource) = req.info.clone().status {
-		if let Some(handler) = handlers.lock().unwrap().get(&(method, resource)) {
-			let mut resp = handler(&req)?;
+	// Waits in short steps, so a shutdown isn't held up by idle connections.
+	let ready = loop {
+		let left = until.map_or(Duration::MAX, |until| until.saturating_duration_since(Instant::now()));
+		let last = shutdown.requested() || left.is_zero();
 
-			if let Some(body) = resp.body.clone() {
-				let body: Vec<u8> = body.into();
+		// Last look only catches what's already there.
+		let step = if last {
+			Duration::from_millis(1)
+		} else {
+			left.min(Duration::from_millis(100))
+		};
 
-				if resp.chunk_size.is_some() {
-					resp.info.headers.insert("Transfer-Encoding".into(), "chunked".into());
-				} else {
-					resp.info.headers.insert("Content-Length".into(), body.len().to_string());
+		tcp.get_ref().set_read_timeout(Some(step))?;
+
+		match tcp.fill_buf() {
+			Ok(buf) => break !buf.is_empty(),
+			Err(ref e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+				if last {
+					break false;
 				}
+			},
+			Err(e) => return Err(e.into()),
+		}
+	};
+
+	tcp.get_ref().set_read_timeout(None)?;
+
+	Ok(ready)
+}
+
+/// Reads and answers a single request, whose head's time budget runs from `started`.
+/// Returns whether the connection can be used for another request.
+fn handle_request(tcp: &mut Stream, context: &Context, started: Instant, mut keep_alive: bool) -> Result<bool, Error> {
+	let budgets = context.budgets;
+	// Budgets too large to be counted from now (or from accept) are none at all.
+	let overall = budgets.total.and_then(|total| Instant::now().checked_add(total));
+	let header = budgets.header
+		.and_then(|budget| started.checked_add(budget))
+		.map(|until| until.saturating_duration_since(Instant::now()));
+
+	let info = match stream::process_lines(tcp, context.limits.head, &mut stream::deadline(header, overall)) {
+		Err(Error::MalformedHead(reason)) => {
+			refuse(tcp, 400, &mut stream::deadline(budgets.write, overall))?;
+
+			return Err(Error::MalformedHead(reason));
+		},
+		Err(Error::HeadersTooLarge) => {
+			refuse(tcp, 431, &mut stream::deadline(budgets.write, overall))?;
+
+			return Err(Error::HeadersTooLarge);
+		},
+		res => res?,
+	};
+
+	// Bodies declared too large are refused before any of them is read (or asked for, with 100-continue).
+	let length = info.headers.get("Content-Length").and_then(|length| length.parse::<usize>().ok());
+
+	if context.limits.body.is_some_and(|max| length.is_some_and(|length| length > max)) {
+		refuse(tcp, 413, &mut stream::deadline(budgets.write, overall))?;
+
+		return Err(Error::PayloadTooLarge);
+	}
 
-				if stream::check_accept(&req.info.headers) && resp.compression_level.is_some() {
-					resp.info.headers.insert("Content-Encoding".into(), "gzip".into());
+	// HTTP/1.0 connections only persist when asked to.
+	if has_option(&info, "close") || (is_http_10(&info) && !has_option(&info, "keep-alive")) {
+		keep_alive = false;
+	}
+
+	let mut continue_100 = false;
+	let mut continue_code = 100;
+	let mut continue_msg = None;
+
+	// Check for 100-Continue
+	if info.headers.get("Expect").is_some() {
+		let expect_handlers = context.expect_handlers.lock().unwrap();
+
+		if !expect_handlers.is_empty() {
+			continue_100 = true;
+
+			for handler in expect_handlers.iter() {
+				let (code, message) = match panic::catch_unwind(AssertUnwindSafe(|| handler(&info))) {
+					Ok(Ok(answer)) => answer,
+					Ok(Err(e)) => {
+						warn!("Expect handler failed: {}", e);
+
+						(500, None)
+					},
+					Err(_) => {
+						warn!("Expect handler panicked.");
+
+						(500, None)
+					},
+				};
+
+				if code != 100 {
+					continue_code = code;
+					continue_msg = message;
+
+					break;
 				}
 			}
+		}
+	}
 
-			let mut head: Vec<u8> = resp.info.into();
-			writeln!(head, "\r").unwrap();
-
-			// TODO: This isn't following deadlines. That needs to change.
-			stream.write_all(&head)?;
-			stream.flush()?;
-
-			if let Some(body) = resp.body {
-				let body: Vec<u8> = body.into();
-
-				let mut chunked = Chunked::new(
-					stream,
-					resp.chunk_size,
-					resp.chunk_size.is_some()
-				);
-
-				let mut compressed = if stream::check_accept(&req.info.headers) && resp.compression_level.is_some() {
-					Compressed::new(
-						&mut chunked,
-						resp.compression_level,
-						Some(&body),
-						true
-					)
-				} else {
-					Compressed::new(
-						&mut chunked,
-						None,
-						None,
-						false
-					)
-				};
+	if continue_100 {
+		let mut resp = ServerResponse::new(continue_code)?;
+
+		if continue_code == 100 {
+			let resp_vec: Vec<u8> = resp.try_into()?;
 
-				stream::write_all_until(&mut compressed, &body, deadline)?;
+			stream::write_head_until(tcp, &resp_vec, &mut stream::deadline(budgets.write, overall))?;
+
+			keep_alive = process_request(tcp, info, overall, context, keep_alive)?;
+		} else { // something didn't pass expectations
+			// Body was never read, so nothing after it can be trusted as a request.
+			keep_alive = false;
+
+			resp = resp.set_header("Connection", "close");
+
+			if let Some(msg) = continue_msg {
+				resp = resp.set_body(msg.as_str());
 			}
-		} else {
-			warn!("No handler available for this resource.");
+
+			let resp_vec: Vec<u8> = resp.try_into()?;
+
+			stream::write_head_until(tcp, &resp_vec, &mut stream::deadline(budgets.write, overall))?;
 		}
+	} else {
+		keep_alive = process_request(tcp, info, overall, context, keep_alive)?;
 	}
 
+	Ok(keep_alive)
+}
+
+/// Whether the request's Connection header lists `option`.
+fn has_option(info: &GeneralInfo, option: &str) -> bool {
+	info.headers.list("Connection").any(|s| s.eq_ignore_ascii_case(option))
+}
+
+fn is_http_10(info: &GeneralInfo) -> bool {
+	matches!(&info.status, StatusInfo::Request(_, _, version) if version == "HTTP/1.0")
+}
+
+/// Tells a connection the server (or its share of it) is too busy, without letting a slow peer stall the accept loop.
+/// Failures are only logged, as one bad connection mustn't stop the accept loop.
+fn reject(tcp: &mut Stream, code: i32) {
+	if let Err(e) = send_rejection(tcp, code) {
+		warn!("Unable to reject connection: {}", e);
+	}
+}
+
+fn send_rejection(tcp: &mut Stream, code: i32) -> Result<(), Error> {
+	tcp.get_ref().set_write_timeout(Some(Duration::from_secs(1)))?;
+	tcp.get_ref().set_read_timeout(Some(Duration::from_secs(1)))?;
+
+	let resp: Vec<u8> = ServerResponse::new(code)?
+		.set_header("Connection", "close")
+		.try_into()?;
+
+	tcp.write_all(&resp)?;
+	tcp.flush()?;
+
 	Ok(())
 }
+
+/// Returns whether the connection can be used for another request.
+fn process_request(stream: &mut Stream, info: GeneralInfo, overall: Option<Instant>, context: &Context, keep_alive: bool) -> Result<bool, Error> {
+	match respond(stream, info, overall, context, keep_alive) {
+		Err(Error::PayloadTooLarge) => {
+			refuse(stream, 413, &mut stream::deadline(context.budgets.write, overall))?;
+
+			Err(Error::PayloadTooLarge)
+		},
+		Err(e) if is_malformed(&e) => {
+			refuse(stream, 400, &mut stream::deadline(context.budgets.write, overall))?;
+
+			Err(e)
+		},
+		res => res,
+	}
+}
+
+/// Whether a request body couldn't be parsed (as opposed to read).
+fn is_malformed(err: &Error) -> bool {
+	match err {
+		// Body can't be told apart from whatever follows it.
+		Error::ChunkError | Error::MalformedHead(_) => true,
+		#[cfg(feature = "multipart")]
+		Error::MultipartError => true,
+		_ => false,
+	}
+}
+
+/// Reads the request body, runs its handler and writes the response.
+/// Returns whether the connection can be used for another request.
+fn respond(stream: &mut Stream, info: GeneralInfo, overall: Option<Instant>, context: &Context, keep_alive: bool) -> Result<bool, Error> {
+	let budgets = context.budgets;
+
+	// Handler is found first, as streaming handlers read the body themselves.
+	// Lock is only held for the lookup, so workers don't wait on each other's handlers.
+	let found = match &info.status {
+		StatusInfo::Request(method, resource, _) => context.handlers.lock().unwrap().find(method, resource),
+		_ => None,
+	};
+	let streaming = found.as_ref().is_some_and(|(_, _, streaming)| *streaming);
+
+	// Head's read timeout is still on the socket. Deadlines re-arm it, but without one the body would be held to it.
+	stream.get_ref().set_read_timeout(None)?;
+
+	let deadline = Deadline::with_min_rate(stream::deadline(budgets.body, overall), budgets.min_body_rate);
+	let mut req = ServerRequest::new(stream, info, deadline, &context.limits, streaming)?;
+
+	let mut resp = if let StatusInfo::Request(method, resource, _) = req.info.clone().status {
+		if let Some((handler, params, _)) = found {
+			req.params = params;
+
+			let started = Instant::now();
+
+			match panic::catch_unwind(AssertUnwindSafe(|| handler(&req))) {
+				// Handlers can't be interrupted, only have their late response replaced.
+				Ok(Ok(_)) if budgets.handler.is_some_and(|budget| started.elapsed() > budget) => {
+					warn!("Handler for {} {} ran out of time.", method, resource);
+
+					fallback(503, &req, context)?
+				},
+				Ok(Ok(resp)) => resp,
+				Ok(Err(e)) => {
+					warn!("Handler for {} {} failed: {}", method, resource, e);
+
+					fallback(500, &req, context)?
+				},
+				Err(_) => {
+					warn!("Handler for {} {} panicked.", method, resource);
+
+					fallback(500, &req, context)?
+				},
+			}
+		} else {
+			let allowed = context.handlers.lock().unwrap().allowed(&resource);
+
+			if allowed.is_empty() {
+				fallback(404, &req, context)?
+			} else {
+				let mut resp = fallback(405, &req, context)?;
+
+				if !resp.info.headers.contains_key("Allow") {
+					resp.info.headers.insert("Allow", allowed.join(", "));
+				}
+
+				resp
+			}
+		}
+	} else {
+		fallback(400, &req, context)?
+	};
+
+	let info = req.finish()?;
+
+	let coding = negotiate(&info, &mut resp, context);
+
+	// Shutdown may have started while the handler ran.
+	let keep_alive = keep_alive && !context.shutdown.requested();
+
+	// HTTP/1.0 clients assume the connection closes, unless told otherwise.
+	if keep_alive && is_http_10(&info) {
+		resp.info.headers.insert("Connection", "keep-alive");
+	}
+
+	let is_head = matches!(&info.status, StatusInfo::Request(method, _, _) if method == "HEAD");
+
+	write_response(stream, resp, coding, &mut stream::deadline(budgets.write, overall), keep_alive, is_head, is_http_10(&info))
+}
+
+/// Answers a request that can't be read any further. The connection is closed afterwards.
+fn refuse(stream: &mut Stream, code: i32, deadline: &mut Option<Deadline>) -> Result<(), Error> {
+	let resp: Vec<u8> = ServerResponse::new(code)?
+		.set_header("Content-Length", "0")
+		.set_header("Connection", "close")
+		.try_into()?;
+
+	stream::write_head_until(stream, &resp, deadline)
+}
+
+/// Response used when no handler produced one.
+/// Falls back to an empty response if the user's fallback handler can't produce one either.
+fn fallback(code: i32, req: &ServerRequest<'_>, context: &Context) -> Result<ServerResponse, Error> {
+	if let Some(handler) = &context.fallback_handler {
+		match panic::catch_unwind(AssertUnwindSafe(|| handler(code, req))) {
+			Ok(Ok(resp)) => return Ok(resp),
+			Ok(Err(e)) => warn!("Fallback handler failed: {}", e),
+			Err(_) => warn!("Fallback handler panicked."),
+		}
+	}
+
+	ServerResponse::new(code)
+}
+
+/// Picks the coding (and level) a response is compressed with, if it's worth compressing.
+/// Marks the response as varying with Accept-Encoding whenever that's what decides it.
+#[cfg(feature = "compress")]
+fn negotiate(req: &GeneralInfo, resp: &mut ServerResponse, context: &Context) -> Option<(Coding, u32)> {
+	let level = resp.compression_level?;
+	let body = resp.body.as_ref()?;
+
+	if resp.info.headers.contains_key("Content-Encoding")
+		|| body.size().is_some_and(|size| size < context.compression.min_size)
+		|| !context.compression.allows(resp.info.headers.get("Content-Type")) {
+		return None;
+	}
+
+	if !resp.info.headers.list("Vary").any(|s| s == "*" || s.eq_ignore_ascii_case("Accept-Encoding")) {
+		resp.info.headers.append("Vary", "Accept-Encoding");
+	}
+
+	Some((stream::check_accept(&req.headers)?, level))
+}
+
+#[cfg(not(feature = "compress"))]
+fn negotiate(_: &GeneralInfo, _: &mut ServerResponse, _: &Context) -> Option<(Coding, u32)> {
+	None
+}
+
+/// Responses to HEAD requests (`is_head`) get the headers a GET would, but never a body, so the client
+/// doesn't read one where the next response starts.
+/// Returns whether the connection can be used for another request, which it can't after a body ended by closing it.
+fn write_response(stream: &mut Stream, mut resp: ServerResponse, coding: Option<(Coding, u32)>, deadline: &mut Option<Deadline>, mut keep_alive: bool, is_head: bool, http_10: bool) -> Result<bool, Error> {
+	let compress = coding.is_some();
+
+	// Bodies of unknown (or compressed) size can't be sent any other way than chunked.
+	// HTTP/1.0 has no chunked coding (RFC 7230, section 3.3.1), so those are ended by closing the connection instead.
+	let chunk_size = match &resp.body {
+		Some(_) if http_10 => None,
+		Some(body) if resp.chunk_size.is_none() && (body.size().is_none() || compress) => Some(stream::CHUNK_SIZE),
+		_ => resp.chunk_size,
+	};
+
+	if let Some(body) = &resp.body {
+		match (chunk_size, body.size()) {
+			(None, Some(size)) if !compress => {
+				resp.info.headers.insert("Content-Length", size.to_string());
+			},
+			// Length isn't known until the body is sent, which it never is.
+			_ if is_head => {},
+			(None, _) => {
+				keep_alive = false;
+			},
+			_ => {
+				resp.info.headers.insert("Transfer-Encoding", "chunked");
+			},
+		}
+
+		if let Some((coding, _)) = coding {
+			resp.info.headers.insert("Content-Encoding", coding.name());
+		}
+	} else if let StatusInfo::Response(_, code, _) = resp.info.status {
+		// Persistent connections need an explicit end, even for empty bodies.
+		if code >= 200 && code != 204 && code != 304 && !resp.info.headers.contains_key("Content-Length") {
+			resp.info.headers.insert("Content-Length", "0");
+		}
+	}
+
+	if !keep_alive {
+		resp.info.headers.insert("Connection", "close");
+	}
+
+	let mut head: Vec<u8> = resp.info.into();
+	writeln!(head, "\r").unwrap();
+
+	stream::write_head_until(stream, &head, deadline)?;
+
+	if let Some(body) = resp.body.filter(|_| !is_head) {
+		let chunked = Chunked::new(
+			stream,
+			chunk_size,
+			chunk_size.is_some()
+		);
+		let mut compressed = Compressed::encoder(chunked, coding)?;
+
+		// Streamed (and compressed on the way), so bodies read from elsewhere never have to fit in memory.
+		stream::copy_until(&mut body.reader()?, &mut compressed, deadline)?;
+
+		compressed.finish()?;
+	}
+
+	Ok(keep_alive)
+}
@@ -0,0 +1,109 @@
+use std::{
+	thread::{ self, JoinHandle },
+	panic::{ self, AssertUnwindSafe },
+	time::{ Instant, Duration },
+	sync::{
+		Arc, Mutex,
+		mpsc::{ self, SyncSender, Receiver, TrySendError },
+	},
+};
+
+
+
+/// Policy used when every worker is busy and the connection queue is full.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Saturation {
+	/// Block the accept loop until a queue slot frees up.
+	Block,
+	/// Close the connection straight away, without a response (which a slow peer could stall the accept loop over).
+	Reject,
+}
+
+/// Fixed set of worker threads fed by a bounded connection queue.
+pub(crate) struct Pool<C: Send + 'static> {
+	sender: Option<SyncSender<C>>,
+	workers: Vec<JoinHandle<()>>,
+}
+
+impl<C: Send + 'static> Pool<C> {
+	pub(crate) fn new<F>(size: usize, queue_size: usize, handle: F) -> Pool<C>
+	where
+		F: Fn(C) + Send + Sync + 'static,
+	{
+		let (sender, receiver) = mpsc::sync_channel(queue_size);
+		let receiver: Arc<Mutex<Receiver<C>>> = Arc::new(Mutex::new(receiver));
+		let handle = Arc::new(handle);
+
+		let workers = (0..size)
+			.map(|_| {
+				let receiver = receiver.clone();
+				let handle = handle.clone();
+
+				thread::spawn(move || loop {
+					// Guard is dropped before handling, so other workers can pick up connections.
+					let stream = receiver.lock().unwrap().recv();
+
+					match stream {
+						// A panic is the connection's problem, so the worker carries on with the next.
+						Ok(stream) => if panic::catch_unwind(AssertUnwindSafe(|| handle(stream))).is_err() {
+							warn!("Connection handler panicked, moving on to the next connection.");
+						},
+						// Sender is gone, pool is shutting down.
+						Err(_) => break,
+					}
+				})
+			})
+			.collect();
+
+		Pool {
+			sender: Some(sender),
+			workers,
+		}
+	}
+
+	/// Queues connection for the next free worker.
+	/// Hands the connection back if the pool is saturated and the policy is `Reject`.
+	pub(crate) fn execute(&self, stream: C, policy: Saturation) -> Result<(), C> {
+		// Sender only goes away on drop.
+		let sender = self.sender.as_ref().unwrap();
+
+		match policy {
+			Saturation::Block => sender.send(stream).map_err(|e| e.0),
+			Saturation::Reject => sender.try_send(stream).map_err(|e| match e {
+				TrySendError::Full(s) | TrySendError::Disconnected(s) => s,
+			}),
+		}
+	}
+
+	/// Stops taking connections, and waits (up to `grace`) for workers to finish those they have.
+	/// Returns whether they all did. Workers still busy are left to finish on their own.
+	pub(crate) fn shutdown(mut self, grace: Duration) -> bool {
+		self.sender.take();
+
+		// Grace periods too large to count down never run out.
+		let until = Instant::now().checked_add(grace);
+
+		while self.workers.iter().any(|worker| !worker.is_finished()) {
+			if until.is_some_and(|until| Instant::now() >= until) {
+				self.workers.clear();
+
+				return false;
+			}
+
+			thread::sleep(Duration::from_millis(10));
+		}
+
+		true
+	}
+}
+
+impl<C: Send + 'static> Drop for Pool<C> {
+	fn drop(&mut self) {
+		// Closing the channel lets workers finish queued connections, then exit.
+		self.sender.take();
+
+		for worker in self.workers.drain(..) {
+			let _ = worker.join();
+		}
+	}
+}
@@ -1,25 +1,43 @@
-use std::time::Instant;
+use std::{
+	fmt,
+	cell::{ RefCell, RefMut },
+	collections::HashMap,
+	io::{
+		Read,
+		Result as IoResult,
+		Error as IoError,
+	},
+};
 
+#[cfg(feature = "multipart")]
+use crate::multipart;
 use crate::{
-	multipart,
-	stream::{ self, Stream, Chunked, Compressed },
+	stream::{ self, Stream, Chunked, Compressed, Deadline, Pending },
 	error::Error,
 	body::Body,
-	GeneralInfo,
+	urlencoded,
+	GeneralInfo, StatusInfo,
 };
+use super::Limits;
 
 
 
-#[derive(Debug)]
-pub struct ServerRequest {
+pub struct ServerRequest<'s> {
 	pub info: GeneralInfo,
 	pub body: Body,
+
+	pub(crate) params: HashMap<String, String>,
+	path: String,
+	query: HashMap<String, Vec<String>>,
+	// Body still on the connection, for streaming handlers.
+	pending: RefCell<Option<Pending<&'s mut Stream>>>,
 }
 
-impl ServerRequest {
-	pub(crate) fn new(stream: &mut Stream, info: GeneralInfo, deadline: &mut Option<(Instant, Instant)>) -> Result<ServerRequest, Error> {
+impl<'s> ServerRequest<'s> {
+	/// Reads the request body, unless `streaming`, in which case it's left for `body_reader`.
+	pub(crate) fn new(stream: &'s mut Stream, info: GeneralInfo, mut deadline: Option<Deadline>, limits: &Limits, streaming: bool) -> Result<ServerRequest<'s>, Error> {
 		let headers = info.headers.clone();
-		let (check_compressed, check_chunked) = stream::check_encodings(&headers);
+		let (codings, check_chunked) = stream::check_encodings(&headers);
 
 		let mut content_length: Option<usize> = None;
 
@@ -31,28 +49,146 @@ impl ServerRequest {
 			}
 		}
 
-		let mut body_bytes = vec![];
+		let content_type = info.headers.get("Content-Type").map_or("", String::as_str);
+		let media_type = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+
+		let mut body = Body::Bytes(vec![]);
+		let mut pending = None;
 
 		if check_chunked || content_length.is_some() {
-			let mut chunked = Chunked::new(stream, None, check_chunked);
-			let mut compressed = Compressed::new(&mut chunked, None, None, check_compressed);
+			let chunked = match content_length {
+				Some(length) => Chunked::sized(stream, length),
+				None => Chunked::new(stream, None, true).set_limits(limits.body, limits.chunk_extension).set_rules(limits.head),
+			};
+			let mut compressed = Compressed::decoder(chunked, &codings)?;
 
-			stream::read_to_end_until(&mut compressed, &mut body_bytes, content_length, deadline)?;
-		}
+			if streaming {
+				pending = Some(Pending::new(compressed, deadline, limits.body));
+			} else {
+				body = match media_type.as_str() {
+					// Parts are parsed as they arrive, rather than from a buffered copy of the body.
+					#[cfg(feature = "multipart")]
+					"multipart/form-data" => {
+						let boundary = multipart::parameters(content_type)
+							.into_iter()
+							.find(|(key, _)| key == "boundary")
+							.map(|(_, value)| value)
+							.unwrap_or_default();
+
+						multipart::from_reader(&boundary, stream::ReadUntil::new(&mut compressed, &mut deadline), &limits.multipart, limits.body)?.into()
+					},
+					_ => {
+						let mut body_bytes = vec![];
 
-		let mut body: Body = body_bytes.clone().into();
+						stream::read_to_end_until(&mut compressed, &mut body_bytes, limits.body, &mut deadline)?;
 
-		if let Some(content_type) = info.headers.get("Content-Type") {
-			let type_split: Vec<&str> = content_type.split(';').collect();
+						if media_type == "application/x-www-form-urlencoded" {
+							Body::Form(urlencoded::parse(&String::from_utf8_lossy(&body_bytes)))
+						} else {
+							body_bytes.into()
+						}
+					},
+				};
 
-			if type_split[0] == "multipart/form-data" {
-				body = multipart::from_bytes(type_split[1], body_bytes)?.into();
+				// Anything left behind would be parsed as the next request on this connection.
+				stream::drain_until(&mut compressed, &mut deadline)?;
 			}
 		}
 
+		// Split request target into decoded path and query.
+		let (path, query) = match &info.status {
+			StatusInfo::Request(_, resource, _) => {
+				let mut split = resource.splitn(2, '?');
+				let path = urlencoded::decode(split.next().unwrap_or(""), false);
+				let mut query: HashMap<String, Vec<String>> = HashMap::new();
+
+				for (key, value) in urlencoded::parse(split.next().unwrap_or("")) {
+					query.entry(key).or_default().push(value);
+				}
+
+				(path, query)
+			},
+			_ => (String::new(), HashMap::new()),
+		};
+
 		Ok(ServerRequest {
 			info,
 			body,
+
+			params: HashMap::new(),
+			path,
+			query,
+			pending: RefCell::new(pending),
 		})
 	}
+
+	/// Reader over the body, decoding its transfer and content codings as it goes.
+	/// Handlers added with `add_streaming_handler` read the body straight off the connection
+	/// (within the body timeout), others from `body`.
+	pub fn body_reader(&self) -> IoResult<Box<dyn Read + '_>> {
+		let pending = self.pending
+			.try_borrow_mut()
+			.map_err(|_| IoError::other("Body is already being read"))?;
+
+		if pending.is_some() {
+			Ok(Box::new(PendingReader(pending)))
+		} else {
+			Ok(self.body.reader()?)
+		}
+	}
+
+	/// Discards whatever a streaming handler left of the body, handing back the request's info.
+	pub(crate) fn finish(self) -> Result<GeneralInfo, Error> {
+		if let Some(mut pending) = self.pending.into_inner() {
+			pending.drain()?;
+		}
+
+		Ok(self.info)
+	}
+
+	/// Gets segment captured by the matched route (e.g. `id` for `/users/:id`).
+	pub fn param(&self, name: &str) -> Option<&str> {
+		self.params.get(name).map(String::as_str)
+	}
+
+	/// Percent-decoded request path, without the query.
+	pub fn path(&self) -> &str {
+		&self.path
+	}
+
+	/// Gets first value of query parameter (e.g. `page` for `/list?page=2`).
+	pub fn query(&self, name: &str) -> Option<&str> {
+		self.query.get(name)?.first().map(String::as_str)
+	}
+
+	/// Gets every value of a repeated query parameter (e.g. `tag` for `/list?tag=a&tag=b`).
+	pub fn query_all(&self, name: &str) -> Vec<&str> {
+		self.query
+			.get(name)
+			.map(|values| values.iter().map(String::as_str).collect())
+			.unwrap_or_default()
+	}
+}
+
+impl<'s> fmt::Debug for ServerRequest<'s> {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.debug_struct("ServerRequest")
+			.field("info", &self.info)
+			.field("body", &self.body)
+			.field("params", &self.params)
+			.field("path", &self.path)
+			.field("query", &self.query)
+			.finish()
+	}
+}
+
+struct PendingReader<'a, 's>(RefMut<'a, Option<Pending<&'s mut Stream>>>);
+
+impl<'a, 's> Read for PendingReader<'a, 's> {
+	fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+		match &mut *self.0 {
+			Some(pending) => pending.read(buf),
+			None => Ok(0),
+		}
+	}
 }
@@ -1,11 +1,16 @@
 use std::{
-	io::Write,
-	collections::HashMap,
+	convert::TryFrom,
+	io::{
+		Write,
+		Result as IoResult,
+		Error as IoError,
+	},
 };
 
 use crate::{
 	error::Error,
 	body::Body,
+	headers::Headers,
 	StatusInfo, GeneralInfo,
 };
 
@@ -14,10 +19,10 @@ use crate::{
 impl Into<Vec<u8>> for StatusInfo {
 	fn into(self) -> Vec<u8> {
 		match self {
-			StatusInfo::Response(code, reason) => {
+			StatusInfo::Response(version, code, reason) => {
 				let mut head = vec![];
 
-				writeln!(head, "HTTP/1.1 {} {}\r", code, reason).unwrap();
+				writeln!(head, "{} {} {}\r", version, code, reason).unwrap();
 
 				head
 			},
@@ -49,25 +54,27 @@ pub struct ServerResponse {
 	pub info: GeneralInfo,
 	/// Response body.
 	pub body: Option<Body>,
-	/// Compression level (0-9).
+	/// Compression level (0-9, or up to 11 for brotli and 22 for zstd), with whichever coding the client accepts.
 	pub compression_level: Option<u32>,
 	/// Chunk size.
 	pub chunk_size: Option<usize>,
 }
 
-impl Into<Vec<u8>> for ServerResponse {
-	fn into(self) -> Vec<u8> {
+impl TryFrom<ServerResponse> for Vec<u8> {
+	type Error = IoError;
+
+	fn try_from(resp: ServerResponse) -> IoResult<Vec<u8>> {
 		let mut resp_data = vec![];
 
-		resp_data.extend::<Vec<u8>>(self.info.into());
+		resp_data.extend::<Vec<u8>>(resp.info.into());
 
 		writeln!(resp_data, "\r").unwrap();
 
-		if let Some(body) = self.body {
-			resp_data.extend::<Vec<u8>>(body.into());
+		if let Some(body) = resp.body {
+			resp_data.extend(Vec::try_from(body)?);
 		}
 
-		resp_data
+		Ok(resp_data)
 	}
 }
 
@@ -134,8 +141,8 @@ impl ServerResponse {
 
 		Ok(ServerResponse {
 			info: GeneralInfo {
-				status: StatusInfo::Response(status, reason.into()),
-				headers: HashMap::new(),
+				status: StatusInfo::Response("HTTP/1.1".into(), status, reason.into()),
+				headers: Headers::new(),
 			},
 			body: None,
 			compression_level: None,
@@ -145,7 +152,14 @@ impl ServerResponse {
 
 	/// Sets/replaces individual header for response.
 	pub fn set_header<S: Into<String>>(mut self, key: S, value: S) -> ServerResponse {
-		self.info.headers.insert(key.into(), value.into());
+		self.info.headers.insert(key, value);
+
+		self
+	}
+
+	/// Adds another value for a header (e.g. `Set-Cookie`), keeping any it already has.
+	pub fn append_header<S: Into<String>>(mut self, key: S, value: S) -> ServerResponse {
+		self.info.headers.append(key, value);
 
 		self
 	}
@@ -156,13 +170,23 @@ impl ServerResponse {
 		let body = body.into();
 
 		// Set Content-Type header based on Body type.
-		match body {
+		match &body {
+			#[cfg(feature = "multipart")]
+			Body::Multipart(multipart) => {
+				self.info.headers.insert("Content-Type", format!("multipart/form-data; boundary={}", multipart.boundary()));
+			},
+			Body::Form(_) => {
+				self.info.headers.insert("Content-Type", "application/x-www-form-urlencoded");
+			},
 			#[cfg(feature = "json")]
 			Body::Json(_) => {
-				self.info.headers.insert("Content-Type".into(), "application/json;charset=UTF-8".into());
+				self.info.headers.insert("Content-Type", "application/json;charset=UTF-8");
+			},
+			Body::Reader(_) => {
+				self.info.headers.insert("Content-Type", "application/octet-stream");
 			},
 			_ => {
-				self.info.headers.insert("Content-Type".into(), "text/plain;".into());
+				self.info.headers.insert("Content-Type", "text/plain;");
 			},
 		}
 
@@ -0,0 +1,173 @@
+use std::{
+	sync::Arc,
+	collections::HashMap,
+};
+
+use crate::urlencoded;
+use super::ServerHandler;
+
+
+
+#[derive(Debug, PartialEq)]
+enum Segment {
+	Static(String),
+	// :name
+	Param(String),
+	// *name, only valid as the last segment.
+	Wildcard(String),
+}
+
+impl Segment {
+	/// Lower ranks win when several routes match the same path.
+	fn rank(&self) -> u8 {
+		match self {
+			Segment::Static(_) => 0,
+			Segment::Param(_) => 1,
+			Segment::Wildcard(_) => 2,
+		}
+	}
+}
+
+struct Route {
+	method: String,
+	segments: Vec<Segment>,
+	handler: Arc<ServerHandler>,
+	// Body is left for the handler to read.
+	streaming: bool,
+}
+
+impl Route {
+	fn matches(&self, path: &[String]) -> Option<HashMap<String, String>> {
+		let mut params = HashMap::new();
+
+		for (idx, segment) in self.segments.iter().enumerate() {
+			match segment {
+				Segment::Wildcard(name) => {
+					let rest = path.get(idx..).unwrap_or_default().join("/");
+
+					// Something has to follow the prefix, `/static/*rest` doesn't match `/static` itself.
+					if rest.is_empty() {
+						return None;
+					}
+
+					params.insert(name.clone(), rest);
+
+					return Some(params);
+				},
+				Segment::Static(s) => {
+					if path.get(idx) != Some(s) {
+						return None;
+					}
+				},
+				Segment::Param(name) => {
+					match path.get(idx) {
+						Some(value) if !value.is_empty() => {
+							params.insert(name.clone(), value.clone());
+						},
+						_ => return None,
+					}
+				},
+			}
+		}
+
+		if path.len() == self.segments.len() {
+			Some(params)
+		} else {
+			None
+		}
+	}
+
+	fn ranks(&self) -> Vec<u8> {
+		self.segments.iter().map(Segment::rank).collect()
+	}
+}
+
+/// Matches request paths against routes like `/users/:id` and `/static/*rest`.
+/// Static segments take priority over parameters, which take priority over wildcards.
+pub(crate) struct Router {
+	routes: Vec<Route>,
+}
+
+impl Router {
+	pub(crate) fn new() -> Router {
+		Router {
+			routes: Vec::new(),
+		}
+	}
+
+	/// Adds route, replacing any handler previously added for the same method and route.
+	pub(crate) fn insert(&mut self, method: String, route: &str, handler: ServerHandler, streaming: bool) {
+		let segments = parse_route(route);
+
+		self.routes.retain(|r| r.method != method || r.segments != segments);
+
+		self.routes.push(Route {
+			method,
+			segments,
+			handler: Arc::new(handler),
+			streaming,
+		});
+	}
+
+	/// Finds the best handler for method and path, along with any captured parameters
+	/// and whether it reads the body itself.
+	pub(crate) fn find(&self, method: &str, path: &str) -> Option<(Arc<ServerHandler>, HashMap<String, String>, bool)> {
+		let path = split_path(path);
+
+		self.routes
+			.iter()
+			.filter(|route| route.method == method)
+			.filter_map(|route| route.matches(&path).map(|params| (route, params)))
+			.min_by_key(|(route, _)| route.ranks())
+			.map(|(route, params)| (route.handler.clone(), params, route.streaming))
+	}
+
+	/// Methods with a route matching path.
+	pub(crate) fn allowed(&self, path: &str) -> Vec<String> {
+		let path = split_path(path);
+		let mut methods: Vec<String> = self.routes
+			.iter()
+			.filter(|route| route.matches(&path).is_some())
+			.map(|route| route.method.clone())
+			.collect();
+
+		methods.sort();
+		methods.dedup();
+
+		methods
+	}
+}
+
+/// Panics if a wildcard isn't the last segment, as whatever follows it could never match.
+fn parse_route(route: &str) -> Vec<Segment> {
+	let segments: Vec<Segment> = split_path(route)
+		.into_iter()
+		.map(|segment| {
+			if let Some(name) = segment.strip_prefix(':') {
+				Segment::Param(name.to_string())
+			} else if let Some(name) = segment.strip_prefix('*') {
+				Segment::Wildcard(name.to_string())
+			} else {
+				Segment::Static(segment.to_string())
+			}
+		})
+		.collect();
+
+	if segments.iter().rev().skip(1).any(|segment| matches!(segment, Segment::Wildcard(_))) {
+		panic!("Wildcard has to be the last segment of route {:?}", route);
+	}
+
+	segments
+}
+
+/// Splits request target into percent-decoded segments.
+/// Decoding each segment separately keeps an escaped `/` inside its segment.
+fn split_path(path: &str) -> Vec<String> {
+	// Queries aren't part of the route.
+	let path = path.split('?').next().unwrap_or("");
+
+	path.trim_start_matches('/')
+		.split('/')
+		.map(|segment| urlencoded::decode(segment, false))
+		.collect()
+}
@@ -0,0 +1,69 @@
+use std::{
+	net::{ SocketAddr, TcpStream, Ipv4Addr, Ipv6Addr },
+	time::Duration,
+	sync::{
+		Arc,
+		atomic::{ AtomicBool, Ordering },
+	},
+};
+
+
+
+/// Stops a running `Server` from another thread (see `Server::shutdown_handle`).
+#[derive(Debug, Clone)]
+pub struct ShutdownHandle(Arc<Shutdown>);
+
+/// Shutdown state, shared between a server, its connections and its handles.
+#[derive(Debug)]
+pub(crate) struct Shutdown {
+	requested: AtomicBool,
+	// Where the listener can be reached, to wake it from accept.
+	addr: SocketAddr,
+}
+
+impl Shutdown {
+	pub(crate) fn new(addr: SocketAddr) -> Arc<Shutdown> {
+		Arc::new(Shutdown {
+			requested: AtomicBool::new(false),
+			addr,
+		})
+	}
+
+	pub(crate) fn requested(&self) -> bool {
+		self.requested.load(Ordering::SeqCst)
+	}
+
+	/// Lets the server run again.
+	pub(crate) fn reset(&self) {
+		self.requested.store(false, Ordering::SeqCst);
+	}
+}
+
+impl ShutdownHandle {
+	pub(crate) fn new(shutdown: Arc<Shutdown>) -> ShutdownHandle {
+		ShutdownHandle(shutdown)
+	}
+
+	/// Stops the server accepting connections. Persistent connections are closed after their current request.
+	/// `Server::run` returns once requests in flight are answered, or its grace period (see `Server::set_shutdown_grace`) runs out.
+	/// Does nothing if the server is already shutting down.
+	pub fn shutdown(&self) {
+		if self.0.requested.swap(true, Ordering::SeqCst) {
+			return;
+		}
+
+		// Accept only returns with a connection, so one is made for it.
+		let mut addr = self.0.addr;
+
+		if addr.ip().is_unspecified() {
+			addr.set_ip(match addr {
+				SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+				SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+			});
+		}
+
+		if let Err(e) = TcpStream::connect_timeout(&addr, Duration::from_secs(1)) {
+			warn!("Unable to wake server for shutdown: {}", e);
+		}
+	}
+}
@@ -1,39 +1,1665 @@
 use std::{
 	thread,
+	time::Duration,
 	io::{
-		Write, BufReader,
+		Read, Write, BufReader, ErrorKind,
 		Result as IoResult,
 	},
 	net::TcpStream,
 };
 
+use super::{
+	pool::{ Pool, Saturation },
+	router::Router,
+	accept_failed,
+};
 use crate::{
-	stream::{ self, Stream },
-	Server, ServerResponse, StatusInfo,
+	stream::{ self, Stream, Chunked, Compressed, HeadRules },
+	Server, ServerResponse, StatusInfo, GeneralInfo, Error, Body, Headers,
 };
+#[cfg(feature = "compress")]
+use crate::stream::Coding;
 
 
 
 #[test]
 fn test_basic() -> IoResult<()> {
-	thread::spawn(|| {
-		Server::new("localhost:8080").unwrap()
-			.add_handler("GET", "/", |_| {
-				ServerResponse::new(200)
-			})
-			.run()
-	});
+	// Bound before the thread starts, so the client can't beat it.
+	let mut server = Server::new("localhost:8080").unwrap()
+		.add_handler("GET", "/", |_| {
+			ServerResponse::new(200)
+		});
+	let shutdown = server.shutdown_handle();
+
+	let running = thread::spawn(move || server.run());
 
 	let mut client = TcpStream::connect("localhost:8080")?;
 
 	writeln!(client, "GET / HTTP/1.1\r\nHost: localhost\r\n\r")?;
 
-	let resp = stream::process_lines(&mut Stream::Http(BufReader::new(client)))?;
+	let resp = stream::process_lines(&mut Stream::Http(BufReader::new(client)), HeadRules::default(), &mut None)?;
 
 	match resp.status {
-		StatusInfo::Response(code, _) => assert_eq!(code, 200),
+		StatusInfo::Response(_, code, _) => assert_eq!(code, 200),
 		_ => (),
 	}
 
+	shutdown.shutdown();
+	running.join().unwrap()?;
+
+	Ok(())
+}
+
+#[test]
+fn test_workers() -> IoResult<()> {
+	let mut server = Server::new("localhost:8081").unwrap()
+		.set_workers(2)
+		.add_handler("GET", "/", |_| {
+			ServerResponse::new(200)
+		});
+	let shutdown = server.shutdown_handle();
+
+	let running = thread::spawn(move || server.run());
+
+	// Idle connection that never sends a request, hogging one worker.
+	let _idle = TcpStream::connect("localhost:8081")?;
+
+	let mut client = TcpStream::connect("localhost:8081")?;
+	client.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+	writeln!(client, "GET / HTTP/1.1\r\nHost: localhost\r\n\r")?;
+
+	let resp = stream::process_lines(&mut Stream::Http(BufReader::new(client)), HeadRules::default(), &mut None)?;
+
+	assert_eq!(resp.status, StatusInfo::Response("HTTP/1.1".into(), 200, "OK".into()));
+
+	shutdown.shutdown();
+	running.join().unwrap()?;
+
+	// Once the worker and queue are taken, connections are closed without a word.
+	let mut server = Server::new("localhost:8126").unwrap()
+		.set_workers(1)
+		.set_queue_size(1)
+		.set_saturation(Saturation::Reject)
+		.add_handler("GET", "/", |_| {
+			ServerResponse::new(200)
+		});
+	let shutdown = server.shutdown_handle();
+
+	let running = thread::spawn(move || server.run());
+
+	let _idle = TcpStream::connect("localhost:8126")?;
+	thread::sleep(Duration::from_millis(200));
+	let _queued = TcpStream::connect("localhost:8126")?;
+	thread::sleep(Duration::from_millis(200));
+
+	let mut client = TcpStream::connect("localhost:8126")?;
+	client.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+	let mut buf = vec![];
+
+	assert!(!matches!(client.read_to_end(&mut buf), Err(ref e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut));
+	assert!(buf.is_empty());
+
+	shutdown.shutdown();
+	running.join().unwrap()?;
+
+	Ok(())
+}
+
+#[test]
+fn test_accept_errors() -> IoResult<()> {
+	use std::net::TcpListener;
+
+	let listener = TcpListener::bind("localhost:0")?;
+
+	// Failures of a single connection don't stop the server.
+	assert!(accept_failed(&listener, ErrorKind::ConnectionAborted.into()).is_ok());
+	assert!(accept_failed(&listener, ErrorKind::Interrupted.into()).is_ok());
+
+	// Nor does running out of file descriptors, which waits a moment for some to close.
+	#[cfg(unix)]
+	{
+		let started = std::time::Instant::now();
+
+		assert!(accept_failed(&listener, std::io::Error::from_raw_os_error(24)).is_ok());
+		assert!(started.elapsed() >= Duration::from_millis(100));
+	}
+
+	Ok(())
+}
+
+#[test]
+fn test_pool_panic() {
+	let (sender, receiver) = std::sync::mpsc::channel();
+	let sender = std::sync::Mutex::new(sender);
+
+	let pool = Pool::new(1, 4, move |job: u32| {
+		if job == 0 {
+			panic!("bad connection");
+		}
+
+		sender.lock().unwrap().send(job).unwrap();
+	});
+
+	assert!(pool.execute(0, Saturation::Block).is_ok());
+	assert!(pool.execute(1, Saturation::Block).is_ok());
+
+	// Lone worker survived the panic.
+	assert_eq!(receiver.recv_timeout(Duration::from_secs(5)), Ok(1));
+}
+
+#[test]
+fn test_keep_alive() -> IoResult<()> {
+	let mut server = Server::new("localhost:8082").unwrap()
+		.add_handler("POST", "/", |req| {
+			Ok(ServerResponse::new(200)?.set_body(req.body.text()?.as_str()))
+		})
+		.add_handler("HEAD", "/", |_| {
+			Ok(ServerResponse::new(200)?.set_body("hello"))
+		})
+		.add_handler("HEAD", "/stream", |_| {
+			Ok(ServerResponse::new(200)?.set_body(Body::from_reader(&b"hello"[..])))
+		})
+		.add_handler("GET", "/", |_| {
+			Ok(ServerResponse::new(200)?.set_body("world"))
+		});
+	let shutdown = server.shutdown_handle();
+
+	let running = thread::spawn(move || server.run());
+
+	let client = TcpStream::connect("localhost:8082")?;
+	client.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+	let mut stream = Stream::Http(BufReader::new(client));
+
+	write!(stream, "POST / HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nfirst\r\n0\r\n\r\n")?;
+
+	let resp = stream::process_lines(&mut stream, HeadRules::default(), &mut None)?;
+	let mut body = [0; 5];
+	stream.read_exact(&mut body)?;
+
+	assert_eq!(resp.status, StatusInfo::Response("HTTP/1.1".into(), 200, "OK".into()));
+	assert_eq!(&body, b"first");
+
+	write!(stream, "POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 6\r\nConnection: close\r\n\r\nsecond")?;
+
+	let resp = stream::process_lines(&mut stream, HeadRules::default(), &mut None)?;
+	let mut body = vec![];
+	stream.read_to_end(&mut body)?;
+
+	assert_eq!(resp.headers.get("Connection").map(String::as_str), Some("close"));
+	assert_eq!(body, b"second");
+
+	// HTTP/1.0 connections only persist when asked to.
+	let client = TcpStream::connect("localhost:8082")?;
+	client.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+	let mut stream = Stream::Http(BufReader::new(client));
+
+	write!(stream, "POST / HTTP/1.0\r\nConnection: keep-alive\r\nContent-Length: 5\r\n\r\nthird")?;
+
+	let resp = stream::process_lines(&mut stream, HeadRules::default(), &mut None)?;
+	let mut body = [0; 5];
+	stream.read_exact(&mut body)?;
+
+	assert_eq!(resp.headers.get("Connection").map(String::as_str), Some("keep-alive"));
+	assert_eq!(&body, b"third");
+
+	write!(stream, "POST / HTTP/1.0\r\nContent-Length: 6\r\n\r\nfourth")?;
+
+	let resp = stream::process_lines(&mut stream, HeadRules::default(), &mut None)?;
+	let mut body = vec![];
+	stream.read_to_end(&mut body)?;
+
+	assert_eq!(resp.headers.get("Connection").map(String::as_str), Some("close"));
+	assert_eq!(body, b"fourth");
+
+	// Responses to HEAD carry no body, so the next response is read from where it starts.
+	let client = TcpStream::connect("localhost:8082")?;
+	client.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+	let mut stream = Stream::Http(BufReader::new(client));
+
+	write!(stream, "HEAD / HTTP/1.1\r\nHost: localhost\r\n\r\n")?;
+
+	let resp = stream::process_lines(&mut stream, HeadRules::default(), &mut None)?;
+
+	assert_eq!(resp.headers.get("Content-Length").map(String::as_str), Some("5"));
+
+	write!(stream, "HEAD /stream HTTP/1.1\r\nHost: localhost\r\n\r\n")?;
+
+	let resp = stream::process_lines(&mut stream, HeadRules::default(), &mut None)?;
+
+	assert!(!resp.headers.contains_key("Transfer-Encoding"));
+
+	write!(stream, "GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")?;
+
+	let resp = stream::process_lines(&mut stream, HeadRules::default(), &mut None)?;
+	let mut body = vec![];
+	stream.read_to_end(&mut body)?;
+
+	assert_eq!(resp.status, StatusInfo::Response("HTTP/1.1".into(), 200, "OK".into()));
+	assert_eq!(body, b"world");
+
+	shutdown.shutdown();
+	running.join().unwrap()?;
+
+	Ok(())
+}
+
+#[test]
+fn test_routing() -> IoResult<()> {
+	let mut server = Server::new("localhost:8083").unwrap()
+		.add_handler("GET", "/users/:id", |req| {
+			Ok(ServerResponse::new(200)?.set_body(format!("user {}", req.param("id").unwrap()).as_str()))
+		})
+		.add_handler("GET", "/users/new", |_| {
+			Ok(ServerResponse::new(200)?.set_body("new user"))
+		})
+		.add_handler("GET", "/static/*path", |req| {
+			Ok(ServerResponse::new(200)?.set_body(req.param("path").unwrap()))
+		});
+	let shutdown = server.shutdown_handle();
+
+	let running = thread::spawn(move || server.run());
+
+	let client = TcpStream::connect("localhost:8083")?;
+	client.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+	let mut stream = Stream::Http(BufReader::new(client));
+
+	for (resource, expected) in &[
+		("/users/42?full=true", "user 42"),
+		("/users/new", "new user"),
+		("/static/css/site.css", "css/site.css"),
+	] {
+		write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", resource)?;
+
+		let resp = stream::process_lines(&mut stream, HeadRules::default(), &mut None)?;
+		let length: usize = resp.headers["Content-Length"].parse().unwrap();
+		let mut body = vec![0; length];
+		stream.read_exact(&mut body)?;
+
+		assert_eq!(String::from_utf8_lossy(&body), *expected);
+	}
+
+	// Wildcards need something to capture.
+	for resource in &["/static", "/static/"] {
+		write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", resource)?;
+
+		let resp = stream::process_lines(&mut stream, HeadRules::default(), &mut None)?;
+		let length: usize = resp.headers["Content-Length"].parse().unwrap();
+		let mut body = vec![0; length];
+		stream.read_exact(&mut body)?;
+
+		assert!(matches!(resp.status, StatusInfo::Response(_, 404, _)), "{}", resource);
+	}
+
+	shutdown.shutdown();
+	running.join().unwrap()?;
+
+	Ok(())
+}
+
+#[test]
+#[should_panic(expected = "Wildcard has to be the last segment")]
+fn test_routing_wildcard() {
+	Router::new().insert("GET".into(), "/a/*rest/b", Box::new(|_| ServerResponse::new(200)), false);
+}
+
+#[test]
+fn test_fallbacks() -> IoResult<()> {
+	let mut server = Server::new("localhost:8084").unwrap()
+		.add_handler("POST", "/items", |_| {
+			ServerResponse::new(201)
+		})
+		.add_handler("GET", "/error", |_| {
+			Err(Error::HTTPStatusCodeNotRecognized)
+		})
+		.add_handler("GET", "/panic", |_| {
+			panic!("handler panicked")
+		})
+		.set_fallback_handler(|code, _| {
+			Ok(ServerResponse::new(code)?.set_header("X-Fallback", "yes"))
+		})
+		.add_expect_handler(|info| {
+			if info.headers.contains_key("X-Panic") {
+				panic!("expect handler panicked");
+			}
+
+			Ok((100, None))
+		});
+	let shutdown = server.shutdown_handle();
+
+	let running = thread::spawn(move || server.run());
+
+	let client = TcpStream::connect("localhost:8084")?;
+	client.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+	let mut stream = Stream::Http(BufReader::new(client));
+
+	for (resource, code) in &[("/missing", 404), ("/items", 405), ("/error", 500), ("/panic", 500)] {
+		write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", resource)?;
+
+		let resp = stream::process_lines(&mut stream, HeadRules::default(), &mut None)?;
+
+		match resp.status {
+			StatusInfo::Response(_, status, _) => assert_eq!(status, *code),
+			_ => panic!("Expected response"),
+		}
+
+		assert_eq!(resp.headers["X-Fallback"], "yes");
+
+		if *code == 405 {
+			assert_eq!(resp.headers["Allow"], "POST");
+		}
+	}
+
+	// Body that can't be read is refused, and the connection closed.
+	write!(stream, "POST /items HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n")?;
+
+	let resp = stream::process_lines(&mut stream, HeadRules::default(), &mut None)?;
+
+	assert_eq!(resp.status, StatusInfo::Response("HTTP/1.1".into(), 400, "Bad Request".into()));
+	assert_eq!(resp.headers["Connection"], "close");
+
+	// Expect handlers are guarded like the rest.
+	let client = TcpStream::connect("localhost:8084")?;
+	client.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+	let mut stream = Stream::Http(BufReader::new(client));
+
+	write!(stream, "POST /items HTTP/1.1\r\nHost: localhost\r\nExpect: 100-continue\r\nX-Panic: yes\r\nContent-Length: 4\r\n\r\n")?;
+
+	let resp = stream::process_lines(&mut stream, HeadRules::default(), &mut None)?;
+
+	assert_eq!(resp.status, StatusInfo::Response("HTTP/1.1".into(), 500, "Internal Server Error".into()));
+	assert_eq!(resp.headers["Connection"], "close");
+
+	shutdown.shutdown();
+	running.join().unwrap()?;
+
+	Ok(())
+}
+
+#[test]
+fn test_deadlines() -> IoResult<()> {
+	let mut server = Server::new("localhost:8085").unwrap()
+		.set_deadline(1)
+		.add_handler("GET", "/", |_| {
+			ServerResponse::new(200)
+		});
+	let shutdown = server.shutdown_handle();
+
+	let running = thread::spawn(move || server.run());
+
+	// Deadlines are armed per request, so outliving one doesn't affect later connections.
+	thread::sleep(Duration::from_millis(1100));
+
+	let client = TcpStream::connect("localhost:8085")?;
+	client.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+	let mut stream = Stream::Http(BufReader::new(client));
+
+	write!(stream, "GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")?;
+
+	assert_eq!(stream::process_lines(&mut stream, HeadRules::default(), &mut None)?.status, StatusInfo::Response("HTTP/1.1".into(), 200, "OK".into()));
+
+	shutdown.shutdown();
+	running.join().unwrap()?;
+
+	// Budgets too large to count down are no limit at all, rather than a panic.
+	let mut server = Server::new("localhost:8127").unwrap()
+		.set_deadline(u64::MAX)
+		.set_header_timeout(u64::MAX)
+		.set_body_timeout(u64::MAX)
+		.set_write_timeout(u64::MAX)
+		.set_keep_alive_timeout(u64::MAX)
+		.add_handler("POST", "/", |req| {
+			Ok(ServerResponse::new(200)?.set_body(req.body.text()?.as_str()))
+		});
+	let shutdown = server.shutdown_handle();
+
+	let running = thread::spawn(move || server.run());
+
+	let client = TcpStream::connect("localhost:8127")?;
+	client.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+	let mut stream = Stream::Http(BufReader::new(client));
+
+	for _ in 0..2 {
+		write!(stream, "POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 2\r\n\r\nok")?;
+
+		let resp = stream::process_lines(&mut stream, HeadRules::default(), &mut None)?;
+		let mut body = [0; 2];
+		stream.read_exact(&mut body)?;
+
+		assert_eq!(resp.status, StatusInfo::Response("HTTP/1.1".into(), 200, "OK".into()));
+		assert_eq!(&body, b"ok");
+	}
+
+	shutdown.shutdown();
+	running.join().unwrap()?;
+
+	Ok(())
+}
+
+#[test]
+fn test_handler_timeout() -> IoResult<()> {
+	let mut server = Server::new("localhost:8086").unwrap()
+		.set_handler_timeout(0)
+		.add_handler("GET", "/", |_| {
+			thread::sleep(Duration::from_millis(10));
+
+			ServerResponse::new(200)
+		});
+	let shutdown = server.shutdown_handle();
+
+	let running = thread::spawn(move || server.run());
+
+	let client = TcpStream::connect("localhost:8086")?;
+	client.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+	let mut stream = Stream::Http(BufReader::new(client));
+
+	write!(stream, "GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")?;
+
+	let resp = stream::process_lines(&mut stream, HeadRules::default(), &mut None)?;
+
+	assert!(matches!(resp.status, StatusInfo::Response(_, 503, _)), "{:?}", resp.status);
+
+	shutdown.shutdown();
+	running.join().unwrap()?;
+
+	Ok(())
+}
+
+#[test]
+fn test_header_deadline() -> IoResult<()> {
+	let mut server = Server::new("localhost:8087").unwrap()
+		.set_header_timeout(1)
+		.add_handler("GET", "/", |_| {
+			ServerResponse::new(200)
+		});
+	let shutdown = server.shutdown_handle();
+
+	let running = thread::spawn(move || server.run());
+
+	let mut client = TcpStream::connect("localhost:8087")?;
+	client.set_read_timeout(Some(Duration::from_secs(1)))?;
+
+	write!(client, "GET / HTTP/1.1\r\n")?;
+
+	// Each byte arrives well within a socket timeout, but the head as a whole doesn't.
+	for _ in 0..8 {
+		if client.write_all(b"X").is_err() {
+			break;
+		}
+
+		thread::sleep(Duration::from_millis(250));
+	}
+
+	let mut rest = vec![];
+	let closed = match client.read_to_end(&mut rest) {
+		Ok(_) => true,
+		Err(e) => e.kind() != ErrorKind::WouldBlock && e.kind() != ErrorKind::TimedOut,
+	};
+
+	assert!(closed);
+
+	shutdown.shutdown();
+	running.join().unwrap()?;
+
+	Ok(())
+}
+
+#[test]
+fn test_query() -> IoResult<()> {
+	let mut server = Server::new("localhost:8088").unwrap()
+		.add_handler("GET", "/files/:name", |req| {
+			let body = format!(
+				"{} {} {:?} {:?} {:?}",
+				req.path(),
+				req.param("name").unwrap(),
+				req.query("page"),
+				req.query_all("tag"),
+				req.query("q"),
+			);
+
+			Ok(ServerResponse::new(200)?.set_body(body.as_str()))
+		});
+	let shutdown = server.shutdown_handle();
+
+	let running = thread::spawn(move || server.run());
+
+	let client = TcpStream::connect("localhost:8088")?;
+	client.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+	let mut stream = Stream::Http(BufReader::new(client));
+
+	for (resource, expected) in &[
+		("/files/a%20b?page=2&tag=x&tag=y%26z", r#"/files/a b a b Some("2") ["x", "y&z"] None"#),
+		("/files/a%2Fb?q=hello+world%21", r#"/files/a/b a/b None [] Some("hello world!")"#),
+		("/files/plain", r#"/files/plain plain None [] None"#),
+		("/files/a%+f?q=%-1+%+f", r#"/files/a%+f a%+f None [] Some("%-1 % f")"#),
+	] {
+		write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", resource)?;
+
+		let resp = stream::process_lines(&mut stream, HeadRules::default(), &mut None)?;
+		let length: usize = resp.headers["Content-Length"].parse().unwrap();
+		let mut body = vec![0; length];
+		stream.read_exact(&mut body)?;
+
+		assert_eq!(String::from_utf8_lossy(&body), *expected);
+	}
+
+	shutdown.shutdown();
+	running.join().unwrap()?;
+
+	Ok(())
+}
+
+#[test]
+fn test_form() -> IoResult<()> {
+	let mut server = Server::new("localhost:8089").unwrap()
+		.add_handler("POST", "/", |req| {
+			Ok(ServerResponse::new(200)?.set_body(format!("{:?}", req.body.form()?).as_str()))
+		});
+	let shutdown = server.shutdown_handle();
+
+	let running = thread::spawn(move || server.run());
+
+	let client = TcpStream::connect("localhost:8089")?;
+	client.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+	let mut stream = Stream::Http(BufReader::new(client));
+	let form = "name=J%C3%BCrgen+M&tag=a&tag=b%26c&empty";
+
+	write!(stream, "POST / HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/x-www-form-urlencoded\r\nContent-Length: {}\r\n\r\n{}", form.len(), form)?;
+
+	let resp = stream::process_lines(&mut stream, HeadRules::default(), &mut None)?;
+	let length: usize = resp.headers["Content-Length"].parse().unwrap();
+	let mut body = vec![0; length];
+	stream.read_exact(&mut body)?;
+
+	assert_eq!(
+		String::from_utf8_lossy(&body),
+		r#"[("name", "Jürgen M"), ("tag", "a"), ("tag", "b&c"), ("empty", "")]"#
+	);
+
+	shutdown.shutdown();
+	running.join().unwrap()?;
+
+	Ok(())
+}
+
+#[test]
+#[cfg(feature = "multipart")]
+fn test_multipart() -> IoResult<()> {
+	let mut server = Server::new("localhost:8100").unwrap()
+		.add_handler("POST", "/", |req| {
+			let multipart = match &req.body {
+				Body::Multipart(multipart) => multipart,
+				_ => panic!("Expected multipart body"),
+			};
+
+			let mut body = vec![];
+
+			for part in multipart.parts() {
+				writeln!(body, "{:?} {:?} {:?} {}", part.name(), part.filename(), part.content_type(), part.bytes().unwrap().len())?;
+			}
+
+			body.extend(multipart.part("file").unwrap().bytes().unwrap());
+
+			Ok(ServerResponse::new(200)?.set_body(&body))
+		});
+	let shutdown = server.shutdown_handle();
+
+	let running = thread::spawn(move || server.run());
+
+	let client = TcpStream::connect("localhost:8100")?;
+	client.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+	let mut stream = Stream::Http(BufReader::new(client));
+
+	// Binary data containing line breaks and something close to the delimiter.
+	let file: Vec<u8> = (0..=255).chain(b"\r\n--bound\r\n--boundar".iter().copied()).collect();
+
+	let mut form = vec![];
+	write!(form, "preamble\r\n--boundary\r\nContent-Disposition: form-data; name=\"a;b\"\r\n\r\nline one\r\nline two\r\n")?;
+	write!(form, "--boundary  \r\nContent-Disposition: form-data; name=\"file\"; filename=\"x.bin\"; filename*=UTF-8''na%C3%AFve.bin\r\n")?;
+	write!(form, "Content-Type: application/octet-stream\r\n\r\n")?;
+	form.extend(&file);
+	write!(form, "\r\n--boundary--\r\nepilogue")?;
+
+	write!(stream, "POST / HTTP/1.1\r\nHost: localhost\r\nContent-Type: multipart/form-data; boundary=\"boundary\"\r\nTransfer-Encoding: chunked\r\n\r\n")?;
+
+	// Tiny chunks split delimiters across reads.
+	for chunk in form.chunks(7) {
+		write!(stream, "{:x}\r\n", chunk.len())?;
+		stream.write_all(chunk)?;
+		write!(stream, "\r\n")?;
+	}
+	write!(stream, "0\r\n\r\n")?;
+
+	let resp = stream::process_lines(&mut stream, HeadRules::default(), &mut None)?;
+	let length: usize = resp.headers["Content-Length"].parse().unwrap();
+	let mut body = vec![0; length];
+	stream.read_exact(&mut body)?;
+
+	let expected = format!(
+		"{}\n{}\n",
+		r#""a;b" None None 18"#,
+		r#""file" Some("naïve.bin") Some("application/octet-stream") 276"#,
+	);
+
+	assert_eq!(&body[..expected.len()], expected.as_bytes());
+	assert_eq!(&body[expected.len()..], &file[..]);
+
+	drop(stream);
+
+	// Missing boundary, and a body cut off before its close delimiter.
+	let rejected = [
+		"Content-Type: multipart/form-data\r\nContent-Length: 4\r\n\r\nabcd",
+		"Content-Type: multipart/form-data; boundary=b\r\nContent-Length: 14\r\n\r\n--b\r\nname: a\r\n",
+	];
+
+	for raw in rejected.iter() {
+		let client = TcpStream::connect("localhost:8100")?;
+		client.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+		let mut stream = Stream::Http(BufReader::new(client));
+
+		write!(stream, "POST / HTTP/1.1\r\nHost: localhost\r\n{}", raw)?;
+
+		let resp = stream::process_lines(&mut stream, HeadRules::default(), &mut None)?;
+
+		assert_eq!(resp.status, StatusInfo::Response("HTTP/1.1".into(), 400, "Bad Request".into()), "{:?}", raw);
+		assert_eq!(resp.headers["Connection"], "close");
+	}
+
+	shutdown.shutdown();
+	running.join().unwrap()?;
+
+	Ok(())
+}
+
+#[test]
+#[cfg(feature = "multipart")]
+fn test_multipart_spill() -> IoResult<()> {
+	let mut server = Server::new("localhost:8101").unwrap()
+		.set_multipart_threshold(16)
+		.set_max_part_size(64)
+		.add_handler("POST", "/", |req| {
+			let multipart = match &req.body {
+				Body::Multipart(multipart) => multipart,
+				_ => panic!("Expected multipart body"),
+			};
+
+			let small = multipart.part("small").unwrap();
+			let large = multipart.part("large").unwrap();
+
+			assert_eq!(small.bytes(), Some(&b"tiny"[..]));
+			assert!(large.bytes().is_none());
+
+			// Fields without a filename leave memory just the same.
+			assert!(multipart.part("text").unwrap().path().is_some());
+
+			#[cfg(unix)]
+			{
+				use std::os::unix::fs::PermissionsExt;
+
+				assert_eq!(std::fs::metadata(large.path().unwrap())?.permissions().mode() & 0o777, 0o600);
+			}
+
+			let mut data = vec![];
+			large.reader()?.read_to_end(&mut data)?;
+
+			Ok(ServerResponse::new(200)?.set_body(format!("{}\n{}", large.path().unwrap().display(), String::from_utf8_lossy(&data)).as_str()))
+		});
+	let shutdown = server.shutdown_handle();
+
+	let running = thread::spawn(move || server.run());
+
+	let client = TcpStream::connect("localhost:8101")?;
+	client.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+	let mut stream = Stream::Http(BufReader::new(client));
+
+	let send = |stream: &mut Stream, large: &str| -> IoResult<GeneralInfo> {
+		let form = format!(
+			"--b\r\nContent-Disposition: form-data; name=small; filename=s.txt\r\n\r\ntiny\r\n\
+			--b\r\nContent-Disposition: form-data; name=text\r\n\r\n{}\r\n\
+			--b\r\nContent-Disposition: form-data; name=large; filename=l.txt\r\n\r\n{}\r\n--b--",
+			"y".repeat(32),
+			large,
+		);
+
+		write!(stream, "POST / HTTP/1.1\r\nHost: localhost\r\nContent-Type: multipart/form-data; boundary=b\r\nContent-Length: {}\r\n\r\n{}", form.len(), form)?;
+
+		Ok(stream::process_lines(stream, HeadRules::default(), &mut None)?)
+	};
+
+	let large = "x".repeat(48);
+	let resp = send(&mut stream, &large)?;
+	let length: usize = resp.headers["Content-Length"].parse().unwrap();
+	let mut body = vec![0; length];
+	stream.read_exact(&mut body)?;
+
+	let body = String::from_utf8_lossy(&body);
+	let mut lines = body.lines();
+	let path = lines.next().unwrap();
+
+	assert_eq!(lines.next(), Some(large.as_str()));
+
+	let resp = send(&mut stream, &"x".repeat(65))?;
+
+	assert_eq!(resp.status, StatusInfo::Response("HTTP/1.1".into(), 413, "Payload Too Large".into()));
+
+	// Temp file went away along with the first request.
+	assert!(!std::path::Path::new(path).exists());
+
+	shutdown.shutdown();
+	running.join().unwrap()?;
+
+	Ok(())
+}
+
+#[test]
+fn test_streaming() -> IoResult<()> {
+	let mut server = Server::new("localhost:8102").unwrap()
+		.add_streaming_handler("POST", "/upload", |req| {
+			let limit = req.query("limit").map_or(u64::MAX, |limit| limit.parse().unwrap());
+			let mut size = 0;
+			let mut sum = 0u64;
+			let mut buf = [0; 4096];
+			let mut reader = req.body_reader()?.take(limit);
+
+			loop {
+				let n = reader.read(&mut buf)?;
+
+				if n == 0 {
+					break;
+				}
+
+				size += n;
+				sum += buf[..n].iter().map(|&b| b as u64).sum::<u64>();
+			}
+
+			// Nothing was buffered on the way in, nothing is buffered on the way out.
+			assert_eq!(req.body.text()?, "");
+
+			let report = format!("{} {}", size, sum);
+
+			Ok(ServerResponse::new(200)?.set_body(Body::from_reader(std::io::Cursor::new(report))))
+		});
+	let shutdown = server.shutdown_handle();
+
+	let running = thread::spawn(move || server.run());
+
+	let client = TcpStream::connect("localhost:8102")?;
+	client.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+	let mut stream = Stream::Http(BufReader::new(client));
+	let data: Vec<u8> = (0..1_000_000u32).map(|idx| (idx % 251) as u8).collect();
+	let sum: u64 = data.iter().map(|&b| b as u64).sum();
+
+	// Chunked upload, read in full by the handler.
+	write!(stream, "POST /upload HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n")?;
+
+	for chunk in data.chunks(100_000) {
+		write!(stream, "{:x}\r\n", chunk.len())?;
+		stream.write_all(chunk)?;
+		write!(stream, "\r\n")?;
+	}
+
+	write!(stream, "0\r\n\r\n")?;
+
+	// Partly read upload, the rest has to be discarded for the connection to carry on.
+	write!(stream, "POST /upload?limit=10 HTTP/1.1\r\nHost: localhost\r\nContent-Length: 1000\r\n\r\n")?;
+	stream.write_all(&data[..1000])?;
+
+	for expected in &[format!("{} {}", data.len(), sum), format!("10 {}", (0..10).sum::<u64>())] {
+		let resp = stream::process_lines(&mut stream, HeadRules::default(), &mut None)?;
+
+		assert_eq!(resp.headers["Transfer-Encoding"], "chunked");
+		assert_eq!(resp.headers["Content-Type"], "application/octet-stream");
+
+		let mut body = vec![];
+		let mut compressed = Compressed::Non(Chunked::new(&mut stream, None, true));
+		stream::read_to_end_until(&mut compressed, &mut body, None, &mut None).unwrap();
+
+		assert_eq!(String::from_utf8_lossy(&body), *expected);
+	}
+
+	// HTTP/1.0 has no chunked coding, so the body ends with the connection.
+	let client = TcpStream::connect("localhost:8102")?;
+	client.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+	let mut stream = Stream::Http(BufReader::new(client));
+
+	write!(stream, "POST /upload HTTP/1.0\r\nConnection: keep-alive\r\nContent-Length: 10\r\n\r\n")?;
+	stream.write_all(&data[..10])?;
+
+	let resp = stream::process_lines(&mut stream, HeadRules::default(), &mut None)?;
+	let mut body = vec![];
+	stream.read_to_end(&mut body)?;
+
+	assert!(!resp.headers.contains_key("Transfer-Encoding"));
+	assert_eq!(resp.headers.get("Connection").map(String::as_str), Some("close"));
+	assert_eq!(String::from_utf8_lossy(&body), format!("10 {}", (0..10).sum::<u64>()));
+
+	shutdown.shutdown();
+	running.join().unwrap()?;
+
+	Ok(())
+}
+
+#[test]
+#[cfg(feature = "compress")]
+fn test_compression() -> IoResult<()> {
+	use flate2::{ read::GzDecoder, write::GzEncoder, Compression };
+
+	let mut server = Server::new("localhost:8103").unwrap()
+		.add_handler("POST", "/", |req| {
+			Ok(ServerResponse::new(200)?
+				.set_body(req.body.text()?.as_str())
+				.set_compression_level(6))
+		});
+	let shutdown = server.shutdown_handle();
+
+	let running = thread::spawn(move || server.run());
+
+	let client = TcpStream::connect("localhost:8103")?;
+	client.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+	let mut stream = Stream::Http(BufReader::new(client));
+	let text = "compress me, ".repeat(10_000);
+
+	let mut encoder = GzEncoder::new(vec![], Compression::default());
+	encoder.write_all(text.as_bytes())?;
+	let upload = encoder.finish()?;
+
+	// Only compressed when the client accepts it.
+	for accept in &["Accept-Encoding: gzip\r\n", ""] {
+		write!(stream, "POST / HTTP/1.1\r\nHost: localhost\r\n{}Content-Encoding: gzip\r\nContent-Length: {}\r\n\r\n", accept, upload.len())?;
+		stream.write_all(&upload)?;
+
+		let resp = stream::process_lines(&mut stream, HeadRules::default(), &mut None)?;
+		let mut body = vec![];
+
+		if accept.is_empty() {
+			assert!(!resp.headers.contains_key("Content-Encoding"));
+			assert_eq!(resp.headers["Content-Length"], text.len().to_string());
+
+			body.resize(text.len(), 0);
+			stream.read_exact(&mut body)?;
+		} else {
+			assert_eq!(resp.headers["Content-Encoding"], "gzip");
+			assert_eq!(resp.headers["Transfer-Encoding"], "chunked");
+
+			let mut compressed = Compressed::Non(Chunked::new(&mut stream, None, true));
+			stream::read_to_end_until(&mut compressed, &mut body, None, &mut None).unwrap();
+
+			assert!(body.len() < text.len() / 10);
+
+			let mut decoded = vec![];
+			GzDecoder::new(&body[..]).read_to_end(&mut decoded)?;
+			body = decoded;
+		}
+
+		assert_eq!(String::from_utf8_lossy(&body), text);
+	}
+
+	shutdown.shutdown();
+	running.join().unwrap()?;
+
+	Ok(())
+}
+
+#[test]
+#[cfg(feature = "compress")]
+fn test_accept_encoding() -> IoResult<()> {
+	let mut server = Server::new("localhost:8104").unwrap()
+		.add_handler("GET", "/", |_| {
+			Ok(ServerResponse::new(200)?
+				.set_body("compress me, ".repeat(10_000).as_str())
+				.set_compression_level(6))
+		});
+	let shutdown = server.shutdown_handle();
+
+	let running = thread::spawn(move || server.run());
+
+	let client = TcpStream::connect("localhost:8104")?;
+	client.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+	let mut stream = Stream::Http(BufReader::new(client));
+	let text = "compress me, ".repeat(10_000);
+
+	let codings: &[&str] = &[
+		"gzip",
+		#[cfg(feature = "deflate")]
+		"deflate",
+		#[cfg(feature = "brotli")]
+		"br",
+		#[cfg(feature = "zstd")]
+		"zstd",
+	];
+
+	// Whichever supported coding the client names, skipping ones it doesn't know.
+	for coding in codings {
+		write!(stream, "GET / HTTP/1.1\r\nHost: localhost\r\nAccept-Encoding: unknown, {}\r\n\r\n", coding)?;
+
+		let resp = stream::process_lines(&mut stream, HeadRules::default(), &mut None)?;
+
+		assert_eq!(resp.headers["Content-Encoding"], *coding);
+
+		let (codings, _) = stream::check_encodings(&resp.headers);
+		let mut body = vec![];
+		let mut compressed = Compressed::decoder(Chunked::new(&mut stream, None, true), &codings)?;
+		stream::read_to_end_until(&mut compressed, &mut body, None, &mut None).unwrap();
+		stream::drain_until(&mut compressed, &mut None).unwrap();
+
+		assert_eq!(String::from_utf8_lossy(&body), text);
+	}
+
+	// HTTP/1.0 has no chunked coding, so compressed bodies end with the connection.
+	write!(stream, "GET / HTTP/1.0\r\nAccept-Encoding: gzip\r\n\r\n")?;
+
+	let resp = stream::process_lines(&mut stream, HeadRules::default(), &mut None)?;
+
+	assert_eq!(resp.headers["Content-Encoding"], "gzip");
+	assert!(!resp.headers.contains_key("Transfer-Encoding"));
+	assert!(!resp.headers.contains_key("Content-Length"));
+	assert_eq!(resp.headers.get("Connection").map(String::as_str), Some("close"));
+
+	let mut body = vec![];
+	let mut compressed = Compressed::decoder(Chunked::new(&mut stream, None, false), &[Coding::Gzip])?;
+	stream::read_to_end_until(&mut compressed, &mut body, None, &mut None).unwrap();
+
+	assert_eq!(String::from_utf8_lossy(&body), text);
+
+	shutdown.shutdown();
+	running.join().unwrap()?;
+
+	Ok(())
+}
+
+#[test]
+#[cfg(feature = "compress")]
+fn test_negotiation() -> IoResult<()> {
+	let text = "compress me, ".repeat(1_000);
+
+	let mut server = Server::new("localhost:8105").unwrap()
+		.add_handler("GET", "/:size", |req| {
+			let size = req.param("size").unwrap().parse().unwrap();
+
+			Ok(ServerResponse::new(200)?
+				.set_body("compress me, ".repeat(1_000).as_bytes()[..size].to_vec())
+				.set_header("Vary", "Origin")
+				.set_compression_level(6))
+		})
+		.add_handler("GET", "/image", |_| {
+			Ok(ServerResponse::new(200)?
+				.set_body(vec![0; 10_000])
+				.set_header("Content-Type", "image/png")
+				.set_compression_level(6))
+		})
+		.set_compression_min_size(100);
+	let shutdown = server.shutdown_handle();
+
+	let running = thread::spawn(move || server.run());
+
+	let client = TcpStream::connect("localhost:8105")?;
+	client.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+	let mut stream = Stream::Http(BufReader::new(client));
+
+	// Accept-Encoding, resource, expected coding and Vary.
+	let cases = [
+		("gzip;q=0", "/10000", None, "Origin, Accept-Encoding"),
+		("identity, gzip;q=0.5", "/10000", None, "Origin, Accept-Encoding"),
+		("*;q=0, identity", "/10000", None, "Origin, Accept-Encoding"),
+		("identity;q=0.1, x-gzip;q=0.9", "/10000", Some("gzip"), "Origin, Accept-Encoding"),
+		("gzip", "/99", None, "Origin"),
+		("gzip", "/image", None, ""),
+	];
+
+	for (accept, resource, coding, vary) in &cases {
+		write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\nAccept-Encoding: {}\r\n\r\n", resource, accept)?;
+
+		let resp = stream::process_lines(&mut stream, HeadRules::default(), &mut None)?;
+
+		assert_eq!(resp.headers.get("Content-Encoding").map(String::as_str), *coding);
+		assert_eq!(resp.headers.list("Vary").collect::<Vec<&str>>().join(", "), *vary);
+
+		let (codings, chunked) = stream::check_encodings(&resp.headers);
+		let chunked = if chunked {
+			Chunked::new(&mut stream, None, true)
+		} else {
+			Chunked::sized(&mut stream, resp.headers["Content-Length"].parse().unwrap())
+		};
+		let mut compressed = Compressed::decoder(chunked, &codings)?;
+		let mut body = vec![];
+		stream::read_to_end_until(&mut compressed, &mut body, None, &mut None).unwrap();
+		stream::drain_until(&mut compressed, &mut None).unwrap();
+
+		match *resource {
+			"/image" => assert_eq!(body, vec![0; 10_000]),
+			resource => assert_eq!(String::from_utf8_lossy(&body), text[..resource[1..].parse().unwrap()]),
+		}
+	}
+
+	// Weights decide, ties go to the most compact coding.
+	let accept = |value: &str| {
+		let mut headers = Headers::new();
+		headers.insert("Accept-Encoding", value);
+
+		stream::check_accept(&headers)
+	};
+
+	assert_eq!(accept("gzip;q=bad"), None);
+	assert_eq!(accept("gzip;q=0.5, *;q=0.4, identity;q=0.6"), None);
+	assert_eq!(accept("x-gzip, gzip;q=0"), None);
+	assert_eq!(accept("gzip;q=0, x-gzip"), None);
+	assert_eq!(accept("x-gzip;q=0.5, gzip"), Some(Coding::Gzip));
+
+	#[cfg(feature = "brotli")]
+	{
+		assert_eq!(accept("gzip, br"), Some(Coding::Brotli));
+		assert_eq!(accept("gzip;q=0.5, *;q=0.8"), Some(Coding::Brotli));
+		assert_eq!(accept("br;q=0.9, gzip"), Some(Coding::Gzip));
+	}
+
+	shutdown.shutdown();
+	running.join().unwrap()?;
+
+	Ok(())
+}
+
+#[test]
+fn test_headers() -> IoResult<()> {
+	let mut server = Server::new("localhost:8106").unwrap()
+		.add_handler("GET", "/", |req| {
+			let tags: Vec<&String> = req.info.headers.get_all("x-tag").collect();
+
+			Ok(ServerResponse::new(200)?
+				.set_body(format!("{} {:?}", req.info.headers["x-token"], tags).as_str())
+				.append_header("Set-Cookie", "a=1")
+				.append_header("Set-Cookie", "b=2")
+				.set_header("X-lower-Case", "kept"))
+		});
+	let shutdown = server.shutdown_handle();
+
+	let running = thread::spawn(move || server.run());
+
+	let client = TcpStream::connect("localhost:8106")?;
+	client.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+	let mut stream = Stream::Http(BufReader::new(client));
+
+	write!(stream, "GET / HTTP/1.1\r\nHost: localhost\r\nX-TOKEN: secret\r\nX-Tag: one\r\nx-tag: two\r\nConnection: close\r\n\r\n")?;
+
+	let mut raw = String::new();
+	stream.read_to_string(&mut raw)?;
+
+	// Every field is sent, as it was named.
+	assert!(raw.contains("\r\nSet-Cookie: a=1\r\n"));
+	assert!(raw.contains("\r\nSet-Cookie: b=2\r\n"));
+	assert!(raw.contains("\r\nX-lower-Case: kept\r\n"));
+	assert!(raw.ends_with("secret [\"one\", \"two\"]"));
+
+	let mut headers = Headers::new();
+	headers.append("Accept", "text/html");
+	headers.append("Vary", "Origin");
+	headers.append("vary", "Cookie");
+	headers.insert("VARY", "*");
+
+	assert_eq!(headers.iter().collect::<Vec<_>>(), vec![(&"Accept".to_string(), &"text/html".to_string()), (&"VARY".to_string(), &"*".to_string())]);
+	assert_eq!(headers.remove("accept"), Some("text/html".to_string()));
+	assert_eq!(headers.len(), 1);
+
+	shutdown.shutdown();
+	running.join().unwrap()?;
+
+	Ok(())
+}
+
+#[test]
+fn test_smuggling() -> IoResult<()> {
+	let mut server = Server::new("localhost:8107").unwrap()
+		.add_handler("POST", "/", |req| {
+			Ok(ServerResponse::new(200)?.set_body(req.body.text()?.as_str()))
+		});
+	let shutdown = server.shutdown_handle();
+
+	let running = thread::spawn(move || server.run());
+
+	let send = |port: u16, raw: &[u8]| -> IoResult<GeneralInfo> {
+		let client = TcpStream::connect(("localhost", port))?;
+		client.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+		let mut stream = Stream::Http(BufReader::new(client));
+
+		stream.write_all(raw)?;
+
+		Ok(stream::process_lines(&mut stream, HeadRules::default(), &mut None)?)
+	};
+
+	let rejected: [&[u8]; 22] = [
+		b"GET\r\n\r\n",
+		b"GET /\r\nHost: a\r\n\r\n",
+		b"G\xffT / HTTP/1.1\r\nHost: a\r\n\r\n",
+		b"GET /\xff HTTP/1.1\r\nHost: a\r\n\r\n",
+		b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 3\r\nContent-Length: 4\r\n\r\nabcd",
+		b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 4\r\nContent-Length: 4\r\n\r\nabcd",
+		b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 4, 4\r\n\r\nabcd",
+		b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: +4\r\n\r\nabcd",
+		b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 4\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n",
+		b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked, gzip\r\n\r\n0\r\n\r\n",
+		b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n",
+		b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n+5\r\nabcde\r\n0\r\n\r\n",
+		b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n;a=b\r\nabcde\r\n0\r\n\r\n",
+		b"POST / HTTP/1.1\r\nHost: a\r\nX-Folded: one\r\n two\r\nContent-Length: 4\r\n\r\nabcd",
+		b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length : 4\r\n\r\nabcd",
+		b"POST / HTTP/1.1\r\nHost: a\r\nNo colon here\r\n\r\n",
+		b"POST / HTTP/1.1\r\nHost: a\r\nX: a\rTransfer-Encoding: chunked\r\nContent-Length: 1\r\n\r\na",
+		b"POST / HTTP/1.1\r\nHost: a\r\nX: a\0b\r\nContent-Length: 1\r\n\r\na",
+		b"POST / HTTP/1.1\nHost: a\nContent-Length: 4\n\nabcd",
+		b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n3\nabc\r\n0\r\n\r\n",
+		b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\n0\r\n\r\n",
+		b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n0\r\nX: a\n\r\n",
+	];
+
+	for raw in rejected.iter() {
+		let resp = send(8107, raw)?;
+
+		assert_eq!(resp.status, StatusInfo::Response("HTTP/1.1".into(), 400, "Bad Request".into()), "{:?}", String::from_utf8_lossy(raw));
+		// Nothing after a rejected head is read as another request.
+		assert_eq!(resp.headers.get("Connection").map(String::as_str), Some("close"));
+	}
+
+	let resp = send(8107, b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: Chunked\r\n\r\n4\r\nabcd\r\n0\r\n\r\n")?;
+
+	assert_eq!(resp.status, StatusInfo::Response("HTTP/1.1".into(), 200, "OK".into()));
+
+	// Empty lines before the request line are skipped.
+	let resp = send(8107, b"\r\n\r\nPOST / HTTP/1.1\r\nHost: a\r\nContent-Length: 4\r\n\r\nabcd")?;
+
+	assert_eq!(resp.status, StatusInfo::Response("HTTP/1.1".into(), 200, "OK".into()));
+
+	shutdown.shutdown();
+	running.join().unwrap()?;
+
+	// Bare LF can be allowed, the rest can't.
+	let mut lenient = Server::new("localhost:8108").unwrap()
+		.set_allow_bare_lf(true)
+		.add_handler("GET", "/", |_| ServerResponse::new(200))
+		.add_handler("POST", "/", |req| {
+			Ok(ServerResponse::new(200)?.set_body(req.body.text()?.as_str()))
+		});
+	let shutdown = lenient.shutdown_handle();
+
+	let running = thread::spawn(move || lenient.run());
+
+	assert_eq!(send(8108, b"GET / HTTP/1.1\nHost: a\n\n")?.status, StatusInfo::Response("HTTP/1.1".into(), 200, "OK".into()));
+	assert_eq!(send(8108, b"GET / HTTP/1.1\nHost: a\n folded\n\n")?.status, StatusInfo::Response("HTTP/1.1".into(), 400, "Bad Request".into()));
+	assert_eq!(send(8108, b"POST / HTTP/1.1\nHost: a\nTransfer-Encoding: chunked\n\n3\nabc\n0\n\n")?.status, StatusInfo::Response("HTTP/1.1".into(), 200, "OK".into()));
+
+	shutdown.shutdown();
+	running.join().unwrap()?;
+
+	Ok(())
+}
+
+#[test]
+fn test_limits() -> IoResult<()> {
+	let mut server = Server::new("localhost:8110").unwrap()
+		.set_max_header_count(3)
+		.set_max_header_size(256)
+		.set_max_body_size(16)
+		.set_max_chunk_extension(8)
+		.add_handler("POST", "/", |req| {
+			Ok(ServerResponse::new(200)?.set_body(req.body.text()?.as_str()))
+		});
+	let shutdown = server.shutdown_handle();
+
+	let running = thread::spawn(move || server.run());
+
+	let send = |port: u16, raw: &[u8]| -> IoResult<StatusInfo> {
+		let client = TcpStream::connect(("localhost", port))?;
+		client.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+		let mut stream = Stream::Http(BufReader::new(client));
+
+		stream.write_all(raw)?;
+
+		Ok(stream::process_lines(&mut stream, HeadRules::default(), &mut None)?.status)
+	};
+
+	let ok = StatusInfo::Response("HTTP/1.1".into(), 200, "OK".into());
+	let large_head = StatusInfo::Response("HTTP/1.1".into(), 431, "Request Header Fields Too Large".into());
+	let large_body = StatusInfo::Response("HTTP/1.1".into(), 413, "Payload Too Large".into());
+
+	assert_eq!(send(8110, b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 16\r\n\r\n0123456789abcdef")?, ok);
+	assert_eq!(send(8110, b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n8;a=b\r\n01234567\r\n8\r\n89abcdef\r\n0\r\n\r\n")?, ok);
+
+	assert_eq!(send(8110, b"POST / HTTP/1.1\r\nHost: a\r\nA: 1\r\nB: 2\r\nC: 3\r\n\r\n")?, large_head);
+	assert_eq!(send(8110, format!("POST / HTTP/1.1\r\nHost: a\r\nA: {}\r\n\r\n", "x".repeat(256)).as_bytes())?, large_head);
+
+	// Declared length is refused without waiting for the body.
+	assert_eq!(send(8110, b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 17\r\n\r\n")?, large_body);
+	assert_eq!(send(8110, b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n8\r\n01234567\r\n9\r\n89abcdefg\r\n0\r\n\r\n")?, large_body);
+	assert_eq!(send(8110, b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n1;name=value\r\n0\r\n0\r\n\r\n")?, large_body);
+
+	shutdown.shutdown();
+	running.join().unwrap()?;
+
+	// Limit holds for the decoded body too, however it's read.
+	#[cfg(feature = "compress")]
+	{
+		use flate2::{ write::GzEncoder, Compression };
+
+		let mut server = Server::new("localhost:8118").unwrap()
+			.set_max_body_size(1024)
+			.add_handler("POST", "/", |req| {
+				Ok(ServerResponse::new(200)?.set_body(req.body.text()?.as_str()))
+			})
+			.add_streaming_handler("POST", "/stream", |req| {
+				let mut body = vec![];
+
+				req.body_reader()?.read_to_end(&mut body)?;
+
+				Ok(ServerResponse::new(200)?.set_body(body))
+			});
+		let shutdown = server.shutdown_handle();
+
+		let running = thread::spawn(move || server.run());
+
+		let gzip = |path: &str, content_type: &str, body: &[u8]| -> Vec<u8> {
+			let mut encoder = GzEncoder::new(vec![], Compression::default());
+			encoder.write_all(body).unwrap();
+			let body = encoder.finish().unwrap();
+
+			let mut raw = format!("POST {} HTTP/1.1\r\nHost: a\r\nContent-Type: {}\r\nContent-Encoding: gzip\r\nContent-Length: {}\r\n\r\n", path, content_type, body.len()).into_bytes();
+			raw.extend_from_slice(&body);
+
+			raw
+		};
+		let bomb = vec![b'x'; 64 * 1024];
+
+		assert_eq!(send(8118, &gzip("/stream", "text/plain", b"small"))?, ok);
+		assert_eq!(send(8118, &gzip("/stream", "text/plain", &bomb))?, large_body);
+
+		#[cfg(feature = "multipart")]
+		{
+			let mut multipart = b"--b\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\n".to_vec();
+			multipart.extend_from_slice(&bomb);
+			multipart.extend_from_slice(b"\r\n--b--\r\n");
+
+			assert_eq!(send(8118, &gzip("/", "multipart/form-data; boundary=b", &multipart))?, large_body);
+		}
+
+		shutdown.shutdown();
+		running.join().unwrap()?;
+	}
+
+	Ok(())
+}
+
+#[test]
+fn test_slow_clients() -> IoResult<()> {
+	use std::time::Instant;
+
+	// Whether the server hung up on the connection within `within`.
+	let dropped = |client: &mut TcpStream, within: Duration| -> bool {
+		client.set_read_timeout(Some(within)).unwrap();
+
+		let mut buf = [0; 1024];
+
+		loop {
+			match client.read(&mut buf) {
+				Ok(0) => return true,
+				Ok(_) => continue,
+				Err(ref e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => return false,
+				Err(_) => return true,
+			}
+		}
+	};
+
+	// Head trickling in a byte at a time runs out of time, counted from accept.
+	let mut server = Server::new("localhost:8112").unwrap()
+		.set_workers(2)
+		.set_header_timeout(1)
+		.add_handler("GET", "/", |_| ServerResponse::new(200));
+	let shutdown = server.shutdown_handle();
+
+	let running = thread::spawn(move || server.run());
+
+	let mut client = TcpStream::connect("localhost:8112")?;
+	let started = Instant::now();
+
+	for byte in b"GET / HTTP/1.1\r\nHost: localhost\r\n".iter() {
+		if client.write_all(&[*byte]).is_err() || started.elapsed() > Duration::from_secs(3) {
+			break;
+		}
+
+		thread::sleep(Duration::from_millis(100));
+	}
+
+	assert!(dropped(&mut client, Duration::from_secs(3)));
+	assert!(started.elapsed() < Duration::from_secs(5));
+
+	shutdown.shutdown();
+	running.join().unwrap()?;
+
+	// Body sent too slowly is cut off once its grace period runs out.
+	let mut server = Server::new("localhost:8113").unwrap()
+		.set_min_body_rate(1024)
+		.add_handler("POST", "/", |_| ServerResponse::new(200));
+	let shutdown = server.shutdown_handle();
+
+	let running = thread::spawn(move || server.run());
+
+	let mut client = TcpStream::connect("localhost:8113")?;
+	let started = Instant::now();
+
+	write!(client, "POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 100000\r\n\r\n0123456789")?;
+
+	assert!(dropped(&mut client, Duration::from_secs(5)));
+	assert!(started.elapsed() < Duration::from_secs(5));
+
+	shutdown.shutdown();
+	running.join().unwrap()?;
+
+	// Head's timeout doesn't carry over to the body.
+	let mut server = Server::new("localhost:8125").unwrap()
+		.set_header_timeout(1)
+		.add_handler("POST", "/", |req| {
+			Ok(ServerResponse::new(200)?.set_body(req.body.text()?.as_str()))
+		});
+	let shutdown = server.shutdown_handle();
+
+	let running = thread::spawn(move || server.run());
+
+	let client = TcpStream::connect("localhost:8125")?;
+	client.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+	let mut stream = Stream::Http(BufReader::new(client));
+
+	write!(stream, "POST / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nContent-Length: 10\r\n\r\n01234")?;
+	thread::sleep(Duration::from_millis(1500));
+	write!(stream, "56789")?;
+
+	let resp = stream::process_lines(&mut stream, HeadRules::default(), &mut None)?;
+	let mut body = vec![];
+	stream.read_to_end(&mut body)?;
+
+	assert_eq!(resp.status, StatusInfo::Response("HTTP/1.1".into(), 200, "OK".into()));
+	assert_eq!(body, b"0123456789");
+
+	shutdown.shutdown();
+	running.join().unwrap()?;
+
+	// Streaming handlers can take their time over a body that has all arrived.
+	let mut server = Server::new("localhost:8123").unwrap()
+		.set_min_body_rate(100_000)
+		.add_streaming_handler("POST", "/", |req| {
+			let mut reader = req.body_reader()?;
+			let mut buf = [0; 512];
+			let mut read = 0;
+
+			loop {
+				match reader.read(&mut buf)? {
+					0 => break,
+					n => read += n,
+				}
+
+				thread::sleep(Duration::from_millis(400));
+			}
+
+			Ok(ServerResponse::new(200)?.set_body(read.to_string().as_str()))
+		});
+	let shutdown = server.shutdown_handle();
+
+	let running = thread::spawn(move || server.run());
+
+	let client = TcpStream::connect("localhost:8123")?;
+	client.set_read_timeout(Some(Duration::from_secs(10)))?;
+
+	let mut stream = Stream::Http(BufReader::new(client));
+
+	write!(stream, "POST / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nContent-Length: 4096\r\n\r\n{}", "x".repeat(4096))?;
+
+	let resp = stream::process_lines(&mut stream, HeadRules::default(), &mut None)?;
+
+	assert_eq!(resp.status, StatusInfo::Response("HTTP/1.1".into(), 200, "OK".into()));
+
+	shutdown.shutdown();
+	running.join().unwrap()?;
+
+	// Peers can only hold so many connections.
+	let mut server = Server::new("localhost:8114").unwrap()
+		.set_workers(4)
+		.set_max_connections_per_ip(2)
+		.add_handler("GET", "/", |_| ServerResponse::new(200));
+	let shutdown = server.shutdown_handle();
+
+	let running = thread::spawn(move || server.run());
+
+	let first = TcpStream::connect("localhost:8114")?;
+	let _second = TcpStream::connect("localhost:8114")?;
+
+	// Past the cap, connections are closed without being read from or written to.
+	let mut client = TcpStream::connect("localhost:8114")?;
+
+	assert!(dropped(&mut client, Duration::from_secs(5)));
+
+	let send = || -> IoResult<StatusInfo> {
+		let client = TcpStream::connect("localhost:8114")?;
+		client.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+		let mut stream = Stream::Http(BufReader::new(client));
+
+		write!(stream, "GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")?;
+
+		Ok(stream::process_lines(&mut stream, HeadRules::default(), &mut None)?.status)
+	};
+
+	// Closing one frees its slot.
+	drop(first);
+	thread::sleep(Duration::from_millis(200));
+
+	assert_eq!(send()?, StatusInfo::Response("HTTP/1.1".into(), 200, "OK".into()));
+
+	shutdown.shutdown();
+	running.join().unwrap()?;
+
+	Ok(())
+}
+
+#[test]
+fn test_inline() -> IoResult<()> {
+	use std::sync::{ Arc, atomic::{ AtomicUsize, Ordering } };
+
+	let busy = Arc::new(AtomicUsize::new(0));
+	let most = Arc::new(AtomicUsize::new(0));
+	let (counter, peak) = (busy.clone(), most.clone());
+
+	// Without workers, connections are handled one at a time, however many arrive.
+	let mut server = Server::new("localhost:8122").unwrap()
+		.add_handler("GET", "/", move |_| {
+			peak.fetch_max(counter.fetch_add(1, Ordering::SeqCst) + 1, Ordering::SeqCst);
+			thread::sleep(Duration::from_millis(100));
+			counter.fetch_sub(1, Ordering::SeqCst);
+
+			ServerResponse::new(200)
+		});
+	let shutdown = server.shutdown_handle();
+
+	let running = thread::spawn(move || server.run());
+
+	let clients: Vec<_> = (0..4).map(|_| thread::spawn(|| -> IoResult<StatusInfo> {
+		let client = TcpStream::connect("localhost:8122")?;
+		client.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+		let mut stream = Stream::Http(BufReader::new(client));
+
+		write!(stream, "GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")?;
+
+		Ok(stream::process_lines(&mut stream, HeadRules::default(), &mut None)?.status)
+	})).collect();
+
+	for client in clients {
+		assert_eq!(client.join().unwrap()?, StatusInfo::Response("HTTP/1.1".into(), 200, "OK".into()));
+	}
+
+	assert_eq!(most.load(Ordering::SeqCst), 1);
+	assert_eq!(busy.load(Ordering::SeqCst), 0);
+
+	shutdown.shutdown();
+	running.join().unwrap()?;
+
+	Ok(())
+}
+
+#[test]
+fn test_shutdown() -> IoResult<()> {
+	use std::time::Instant;
+
+	// Grace period too large to count down, which never runs out.
+	let mut server = Server::new("localhost:8115").unwrap()
+		.set_workers(2)
+		.set_shutdown_grace(u64::MAX)
+		.add_handler("GET", "/", |_| {
+			thread::sleep(Duration::from_millis(500));
+
+			ServerResponse::new(200)
+		});
+	let shutdown = server.shutdown_handle();
+
+	let running = thread::spawn(move || server.run());
+
+	// Idle persistent connection, which shouldn't hold the shutdown up.
+	let idle = TcpStream::connect("localhost:8115")?;
+
+	let client = TcpStream::connect("localhost:8115")?;
+	client.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+	let mut stream = Stream::Http(BufReader::new(client));
+
+	write!(stream, "GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")?;
+
+	thread::sleep(Duration::from_millis(100));
+
+	let started = Instant::now();
+	shutdown.shutdown();
+
+	// Request in flight is still answered, and told the connection is closing.
+	let resp = stream::process_lines(&mut stream, HeadRules::default(), &mut None)?;
+
+	assert_eq!(resp.status, StatusInfo::Response("HTTP/1.1".into(), 200, "OK".into()));
+	assert_eq!(resp.headers.get("Connection").map(String::as_str), Some("close"));
+
+	running.join().unwrap()?;
+
+	assert!(started.elapsed() < Duration::from_secs(2));
+	assert!(TcpStream::connect("localhost:8115").is_err());
+
+	drop(idle);
+
+	// Requests running past the grace period are left behind.
+	let mut server = Server::new("localhost:8116").unwrap()
+		.set_workers(1)
+		.set_shutdown_grace(1)
+		.add_handler("GET", "/", |_| {
+			thread::sleep(Duration::from_secs(5));
+
+			ServerResponse::new(200)
+		});
+	let shutdown = server.shutdown_handle();
+
+	let running = thread::spawn(move || server.run());
+
+	let mut client = TcpStream::connect("localhost:8116")?;
+	write!(client, "GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")?;
+
+	thread::sleep(Duration::from_millis(100));
+
+	let started = Instant::now();
+	shutdown.shutdown();
+
+	running.join().unwrap()?;
+
+	assert!(started.elapsed() < Duration::from_secs(3));
+
+	// Same without workers.
+	let mut server = Server::new("localhost:8119").unwrap()
+		.set_shutdown_grace(1)
+		.add_handler("GET", "/", |_| {
+			thread::sleep(Duration::from_secs(5));
+
+			ServerResponse::new(200)
+		});
+	let shutdown = server.shutdown_handle();
+
+	let running = thread::spawn(move || server.run());
+
+	let mut client = TcpStream::connect("localhost:8119")?;
+	write!(client, "GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")?;
+
+	thread::sleep(Duration::from_millis(100));
+
+	let started = Instant::now();
+	shutdown.shutdown();
+
+	running.join().unwrap()?;
+
+	assert!(started.elapsed() < Duration::from_secs(3));
+
+	// Connections waiting for a worker are still answered.
+	let mut server = Server::new("localhost:8120").unwrap()
+		.set_workers(1)
+		.set_shutdown_grace(5)
+		.add_handler("GET", "/", |_| {
+			thread::sleep(Duration::from_millis(300));
+
+			ServerResponse::new(200)
+		});
+	let shutdown = server.shutdown_handle();
+
+	let running = thread::spawn(move || server.run());
+
+	let connect = |request: &str| -> IoResult<Stream> {
+		let client = TcpStream::connect("localhost:8120")?;
+		client.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+		let mut stream = Stream::Http(BufReader::new(client));
+
+		write!(stream, "{}", request)?;
+
+		Ok(stream)
+	};
+
+	let mut busy = connect("GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")?;
+	thread::sleep(Duration::from_millis(100));
+	let mut queued = connect("GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")?;
+	let mut silent = connect("")?;
+
+	thread::sleep(Duration::from_millis(100));
+
+	shutdown.shutdown();
+
+	// Requests that had arrived are served, the rest turned away.
+	for (stream, code) in [(&mut busy, 200), (&mut queued, 200), (&mut silent, 503)] {
+		let resp = stream::process_lines(stream, HeadRules::default(), &mut None)?;
+
+		assert!(matches!(resp.status, StatusInfo::Response(_, status, _) if status == code));
+		assert_eq!(resp.headers.get("Connection").map(String::as_str), Some("close"));
+	}
+
+	running.join().unwrap()?;
+
 	Ok(())
 }
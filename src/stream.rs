@@ -1,13 +1,13 @@
 use std::{
 	net::TcpStream,
+	borrow::BorrowMut,
 	time::{ Instant, Duration },
 	io::{
-		BufReader, BufRead, Read, Write,
+		BufReader, BufRead, Read, Write, Take,
 		Result as IoResult,
 		Error as IoError,
 		ErrorKind,
 	},
-	collections::HashMap,
 };
 #[cfg(feature = "client")]
 use std::{
@@ -20,21 +20,33 @@ use rustls::{ StreamOwned, ClientSession, ServerSession };
 
 #[cfg(feature = "compress")]
 use flate2::{
-	bufread::{ GzDecoder, GzEncoder },
+	read::GzDecoder,
+	write::GzEncoder,
 	Compression,
 };
+#[cfg(feature = "deflate")]
+use flate2::{
+	read::ZlibDecoder,
+	write::ZlibEncoder,
+};
+#[cfg(feature = "brotli")]
+use brotli::{ Decompressor, CompressorWriter };
+#[cfg(feature = "zstd")]
+use zstd::stream::{
+	read::Decoder as ZstdDecoder,
+	write::Encoder as ZstdEncoder,
+};
 
 use crate::{
-	error::Error,
+	error::{ Error, Malformed },
+	headers::Headers,
 	StatusInfo, GeneralInfo,
 };
 
 
 
-trait GetRefMut {
+trait GetRef {
 	fn get_ref(&self) -> &Stream;
-
-	fn get_mut(&mut self) -> &mut Stream;
 }
 
 pub(crate) enum Stream {
@@ -65,22 +77,49 @@ impl Stream {
 
 
 
-pub(crate) struct ChunkedReader<'r> {
-	stream: &'r mut Stream,
+/// Connection a body is read from or written to, either borrowed or owned.
+pub(crate) trait Transport: BufRead + Write + BorrowMut<Stream> {}
+
+impl<T: BufRead + Write + BorrowMut<Stream>> Transport for T {}
+
+pub(crate) struct ChunkedReader<T> {
+	stream: T,
 	buffer: Vec<u8>,
 	consumed: usize,
 	remaining: usize,
 	eof: bool,
+	// Bytes of chunks (and trailers) so far, and how many are allowed.
+	read: usize,
+	max_size: Option<usize>,
+	max_extension: usize,
+	// Framing lines follow the same line ending rules as the head.
+	rules: HeadRules,
 }
 
-impl<'r> BufRead for ChunkedReader<'r> {
+impl<T: Transport> BufRead for ChunkedReader<T> {
 	fn fill_buf(&mut self) -> IoResult<&[u8]> {
 		if self.buffer.len() == self.consumed && !(self.remaining == 0 && self.eof) {
 			if self.remaining == 0 {
 				self.remaining = self.read_chunk_size()?;
+				self.count(self.remaining)?;
 
 				if self.remaining == 0 {
 					self.eof = true;
+
+					// Trailers aren't exposed, but have to be consumed up to the closing empty line.
+					loop {
+						let n = self.read_framing_line(8 * 1024)?;
+
+						if self.buffer.is_empty() {
+							break;
+						}
+
+						self.count(n)?;
+					}
+
+					self.consumed = 0;
+
+					return Ok(&[]);
 				}
 			}
 
@@ -89,11 +128,11 @@ impl<'r> BufRead for ChunkedReader<'r> {
 			self.consumed = 0;
 			self.remaining -= self.buffer.len();
 
-			if self.remaining == 0 && !read_line_ending(self.get_mut())? {
+			if self.remaining == 0 && !read_line_ending(self.stream.borrow_mut(), self.rules.bare_lf)? {
 				self.buffer.clear();
 				self.eof = true;
 
-				return Err(IoError::new(ErrorKind::Other, "Problem decoding response chunk"));
+				return Err(Error::ChunkError.into());
 			}
 		}
 
@@ -105,7 +144,7 @@ impl<'r> BufRead for ChunkedReader<'r> {
 	}
 }
 
-impl<'r> Read for ChunkedReader<'r> {
+impl<T: Transport> Read for ChunkedReader<T> {
 	fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
 		let n = self.fill_buf()?.read(buf)?;
 		self.consume(n);
@@ -114,50 +153,84 @@ impl<'r> Read for ChunkedReader<'r> {
 	}
 }
 
-impl<'r> ChunkedReader<'r> {
-	fn new(stream: &'r mut Stream) -> ChunkedReader<'r> {
+impl<T: Transport> ChunkedReader<T> {
+	fn new(stream: T) -> ChunkedReader<T> {
 		ChunkedReader {
 			stream,
 			buffer: vec![],
 			remaining: 0,
 			consumed: 0,
 			eof: false,
+			read: 0,
+			max_size: None,
+			max_extension: MAX_CHUNK_EXTENSION,
+			rules: HeadRules::default(),
 		}
 	}
 
 	fn get_ref(&self) -> &Stream {
-		&self.stream
-	}
-
-	fn get_mut(&mut self) -> &mut Stream {
-		&mut self.stream
+		self.stream.borrow()
 	}
 
 	fn read_chunk_size(&mut self) -> IoResult<usize> {
-		read_line(&mut self.stream, &mut self.buffer, 128)?;
+		// At most 16 hex digits, then any extensions.
+		self.read_framing_line(16 + self.max_extension + 2)?;
 
 		if self.buffer.is_empty() {
 			return Err(ErrorKind::UnexpectedEof.into());
 		}
 
-		self.buffer
-			.iter()
-			.position(|&b| b == b';')
-			.map_or_else(|| std::str::from_utf8(&self.buffer), |idx| std::str::from_utf8(&self.buffer[..idx]))
+		let (size, extension) = match self.buffer.iter().position(|&b| b == b';') {
+			Some(idx) => self.buffer.split_at(idx),
+			None => (&self.buffer[..], &[][..]),
+		};
+
+		if extension.len() > self.max_extension {
+			return Err(Error::PayloadTooLarge.into());
+		}
+
+		// Only bare hex digits, since `from_str_radix` would also take a sign another parser might not.
+		if size.is_empty() || !size.iter().all(u8::is_ascii_hexdigit) {
+			return Err(Error::ChunkError.into());
+		}
+
+		std::str::from_utf8(size)
 			.map_err(|_| Error::ChunkError)
 			.and_then(|chunk| usize::from_str_radix(chunk, 16).map_err(|_| Error::ChunkError))
 			.map_err(|e| e.into())
 	}
+
+	/// Reads a chunk size or trailer line, which has to end with CRLF unless bare LF is allowed.
+	fn read_framing_line(&mut self, max: usize) -> IoResult<usize> {
+		let n = read_line(self.stream.borrow_mut(), &mut self.buffer, max, &mut None)?;
+
+		if !self.rules.bare_lf && self.buffer.len() + 2 != n {
+			return Err(Error::ChunkError.into());
+		}
+
+		Ok(n)
+	}
+
+	/// Counts bytes against the size limit, before they're read.
+	fn count(&mut self, n: usize) -> IoResult<()> {
+		self.read = self.read.saturating_add(n);
+
+		if self.max_size.is_some_and(|max| self.read > max) {
+			return Err(Error::PayloadTooLarge.into());
+		}
+
+		Ok(())
+	}
 }
 
-pub(crate) struct ChunkedWriter<'r> {
-	stream: &'r mut Stream,
+pub(crate) struct ChunkedWriter<T> {
+	stream: T,
 	chunk_size: usize,
 	buffer: Vec<u8>,
 }
 
-impl<'r> ChunkedWriter<'r> {
-	fn new(stream: &'r mut Stream, chunk_size: usize) -> ChunkedWriter<'r> {
+impl<T: Transport> ChunkedWriter<T> {
+	fn new(stream: T, chunk_size: usize) -> ChunkedWriter<T> {
 		ChunkedWriter {
 			stream,
 			chunk_size,
@@ -166,15 +239,19 @@ impl<'r> ChunkedWriter<'r> {
 	}
 
 	fn get_ref(&self) -> &Stream {
-		&self.stream
+		self.stream.borrow()
 	}
 
-	fn get_mut(&mut self) -> &mut Stream {
-		&mut self.stream
+	/// Sends anything still buffered, followed by the terminating zero-length chunk.
+	fn finish(&mut self) -> IoResult<()> {
+		self.flush()?;
+
+		self.stream.write_all(b"0\r\n\r\n")?;
+		self.stream.flush()
 	}
 }
 
-impl<'r> Write for ChunkedWriter<'r> {
+impl<T: Transport> Write for ChunkedWriter<T> {
 	fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
 		self.buffer.write_all(buf)?;
 
@@ -182,7 +259,7 @@ impl<'r> Write for ChunkedWriter<'r> {
 			let rest = {
 				let (to_send, rest) = self.buffer.split_at_mut(self.chunk_size);
 
-				write_to_payload(&mut self.stream, to_send)?;
+				write_to_payload(self.stream.borrow_mut(), to_send)?;
 
 				rest.to_vec()
 			};
@@ -198,7 +275,7 @@ impl<'r> Write for ChunkedWriter<'r> {
 			return Ok(());
 		}
 
-		write_to_payload(&mut self.stream, &self.buffer)?;
+		write_to_payload(self.stream.borrow_mut(), &self.buffer)?;
 
 		self.buffer.clear();
 
@@ -206,12 +283,12 @@ impl<'r> Write for ChunkedWriter<'r> {
 	}
 }
 
-pub(crate) enum Chunky<'r> {
-	Read(ChunkedReader<'r>),
-	Write(ChunkedWriter<'r>),
+pub(crate) enum Chunky<T> {
+	Read(ChunkedReader<T>),
+	Write(ChunkedWriter<T>),
 }
 
-impl<'r> Read for Chunky<'r> {
+impl<T: Transport> Read for Chunky<T> {
 	fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
 		match self {
 			Chunky::Read(s) => s.read(buf),
@@ -220,7 +297,7 @@ impl<'r> Read for Chunky<'r> {
 	}
 }
 
-impl<'r> Write for Chunky<'r> {
+impl<T: Transport> Write for Chunky<T> {
 	fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
 		match self {
 			Chunky::Read(_) => Ok(0),// This should never be called.
@@ -236,7 +313,7 @@ impl<'r> Write for Chunky<'r> {
 	}
 }
 
-impl<'r> BufRead for Chunky<'r> {
+impl<T: Transport> BufRead for Chunky<T> {
 	fn fill_buf(&mut self) -> IoResult<&[u8]> {
 		match self {
 			Chunky::Read(s) => s.fill_buf(),
@@ -252,19 +329,21 @@ impl<'r> BufRead for Chunky<'r> {
 	}
 }
 
-implgets!(Chunky, 'r, (Read, Write));
+implgets!(Chunky, T, (Read, Write));
 
-pub(crate) enum Chunked<'r> {
-	Non(&'r mut Stream),
-	Is(Chunky<'r>),
+pub(crate) enum Chunked<T> {
+	Non(T),
+	Is(Chunky<T>),
+	// Body delimited by Content-Length.
+	Sized(Take<T>),
 }
 
-implread!(Chunked, 'r, (Non, Is));
-implbufread!(Chunked, 'r, (Non, Is));
-implwrite!(Chunked, 'r, (Non, Is));
+implread!(Chunked, T, (Non, Is, Sized));
+implbufread!(Chunked, T, (Non, Is, Sized));
+implwrite!(Chunked, T, (Non, Is, Sized .get_mut()));
 
-impl<'r> Chunked<'r> {
-	pub fn new(stream: &'r mut Stream, chunk_size: Option<usize>, chunked: bool) -> Chunked<'r> {
+impl<T: Transport> Chunked<T> {
+	pub fn new(stream: T, chunk_size: Option<usize>, chunked: bool) -> Chunked<T> {
 		if chunked {
 			if let Some(size) = chunk_size {
 				Chunked::Is(Chunky::Write(ChunkedWriter::new(stream, size)))
@@ -275,139 +354,347 @@ impl<'r> Chunked<'r> {
 			Chunked::Non(stream)
 		}
 	}
-}
 
-impl<'r> GetRefMut for Chunked<'r> {
-	fn get_ref(&self) -> &Stream {
+	/// Reader that stops after `length` bytes, leaving anything past the body on the stream.
+	pub fn sized(stream: T, length: usize) -> Chunked<T> {
+		Chunked::Sized(stream.take(length as u64))
+	}
+
+	/// Caps a chunked body being read: its size, and the length of each chunk's extensions.
+	/// Does nothing for other bodies.
+	pub fn set_limits(mut self, max_size: Option<usize>, max_extension: usize) -> Chunked<T> {
+		if let Chunked::Is(Chunky::Read(reader)) = &mut self {
+			reader.max_size = max_size;
+			reader.max_extension = max_extension;
+		}
+
+		self
+	}
+
+	/// Parses a chunked body being read by the rules its head was parsed with. Does nothing for other bodies.
+	pub fn set_rules(mut self, rules: HeadRules) -> Chunked<T> {
+		if let Chunked::Is(Chunky::Read(reader)) = &mut self {
+			reader.rules = rules;
+		}
+
+		self
+	}
+
+	/// Terminates a chunked body. Does nothing for other bodies.
+	pub fn finish(&mut self) -> IoResult<()> {
 		match self {
-			Chunked::Non(s) => &s,
-			Chunked::Is(s) => s.get_ref(),
+			Chunked::Is(Chunky::Write(s)) => s.finish(),
+			_ => Ok(()),
 		}
 	}
+}
 
-	fn get_mut(&mut self) -> &mut Stream {
+impl<T: Transport> GetRef for Chunked<T> {
+	fn get_ref(&self) -> &Stream {
 		match self {
-			Chunked::Non(s) => s,
-			Chunked::Is(s) => s.get_mut(),
+			Chunked::Non(s) => s.borrow(),
+			Chunked::Is(s) => s.get_ref(),
+			Chunked::Sized(s) => s.get_ref().borrow(),
 		}
 	}
 }
 
 
 
-#[cfg(feature = "compress")]
-pub(crate) enum Gzip<'r, Chunked> {
-	De(GzDecoder<&'r mut Chunked>),
-	En((&'r mut Chunked, GzEncoder<&'r [u8]>)),
+/// Content coding, as named in `Content-Encoding` and `Accept-Encoding`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Coding {
+	// Only ever decoded (or encoded) with compress.
+	#[cfg_attr(not(feature = "compress"), allow(dead_code))]
+	Gzip,
+	#[cfg(feature = "deflate")]
+	Deflate,
+	#[cfg(feature = "brotli")]
+	Brotli,
+	#[cfg(feature = "zstd")]
+	Zstd,
 }
 
-#[cfg(feature = "compress")]
-impl<'r> Read for Gzip<'r, Chunked<'r>> {
-	fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+impl Coding {
+	/// Supported codings, most compact first.
+	#[cfg(feature = "compress")]
+	const PREFERENCE: &'static [Coding] = &[
+		#[cfg(feature = "brotli")]
+		Coding::Brotli,
+		#[cfg(feature = "zstd")]
+		Coding::Zstd,
+		Coding::Gzip,
+		#[cfg(feature = "deflate")]
+		Coding::Deflate,
+	];
+
+	fn parse(name: &str) -> Option<Coding> {
+		match name.trim().to_ascii_lowercase().as_str() {
+			#[cfg(feature = "compress")]
+			"gzip" | "x-gzip" => Some(Coding::Gzip),
+			#[cfg(feature = "deflate")]
+			"deflate" => Some(Coding::Deflate),
+			#[cfg(feature = "brotli")]
+			"br" => Some(Coding::Brotli),
+			#[cfg(feature = "zstd")]
+			"zstd" => Some(Coding::Zstd),
+			_ => None,
+		}
+	}
+
+	pub(crate) fn name(self) -> &'static str {
 		match self {
-			Gzip::De(s) => s.read(buf),
-			Gzip::En((p, _)) => p.read(buf), // This should never be called.
+			Coding::Gzip => "gzip",
+			#[cfg(feature = "deflate")]
+			Coding::Deflate => "deflate",
+			#[cfg(feature = "brotli")]
+			Coding::Brotli => "br",
+			#[cfg(feature = "zstd")]
+			Coding::Zstd => "zstd",
 		}
 	}
 }
 
+/// Decodes one content coding off the layer underneath (the transfer, or another coding).
+#[cfg(feature = "compress")]
+pub(crate) enum Decoder<T: Transport> {
+	Gzip(Box<GzDecoder<Compressed<T>>>),
+	// HTTP's deflate is zlib wrapped.
+	#[cfg(feature = "deflate")]
+	Deflate(Box<ZlibDecoder<Compressed<T>>>),
+	#[cfg(feature = "brotli")]
+	Brotli(Box<Decompressor<Compressed<T>>>),
+	#[cfg(feature = "zstd")]
+	Zstd(Box<ZstdDecoder<'static, BufReader<Compressed<T>>>>),
+}
+
+#[cfg(feature = "compress")]
+implread!(Decoder, T, (Gzip, #[cfg(feature = "deflate")] Deflate, #[cfg(feature = "brotli")] Brotli, #[cfg(feature = "zstd")] Zstd));
+
 #[cfg(feature = "compress")]
-impl<'r> Write for Gzip<'r, Chunked<'r>> {
+impl<T: Transport> Write for Decoder<T> {
+	// These should never be called.
 	fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
-		match self {
-			Gzip::De(s) => s.write(buf), // This should never be called.
-			Gzip::En((p, s)) => {
-				let mut buffer = vec![];
+		self.inner().write(buf)
+	}
 
-				s.read_exact(&mut buffer)?;
+	fn flush(&mut self) -> IoResult<()> {
+		self.inner().flush()
+	}
+}
 
-				p.write(&buffer)
+#[cfg(feature = "compress")]
+impl<T: Transport> Decoder<T> {
+	fn new(stream: Compressed<T>, coding: Coding) -> IoResult<Decoder<T>> {
+		Ok(match coding {
+			Coding::Gzip => Decoder::Gzip(Box::new(GzDecoder::new(stream))),
+			#[cfg(feature = "deflate")]
+			Coding::Deflate => Decoder::Deflate(Box::new(ZlibDecoder::new(stream))),
+			#[cfg(feature = "brotli")]
+			Coding::Brotli => Decoder::Brotli(Box::new(Decompressor::new(stream, CHUNK_SIZE))),
+			#[cfg(feature = "zstd")]
+			Coding::Zstd => {
+				let mut decoder = ZstdDecoder::new(stream)?;
+				decoder.window_log_max(ZSTD_WINDOW_LOG_MAX)?;
+
+				Decoder::Zstd(Box::new(decoder))
 			},
-		}
+		})
 	}
 
-	fn flush(&mut self) -> IoResult<()> {
+	fn inner(&mut self) -> &mut Compressed<T> {
 		match self {
-			Gzip::De(s) => s.flush(),
-			Gzip::En((p, _)) => p.flush(),
+			Decoder::Gzip(s) => s.get_mut(),
+			#[cfg(feature = "deflate")]
+			Decoder::Deflate(s) => s.get_mut(),
+			#[cfg(feature = "brotli")]
+			Decoder::Brotli(s) => s.get_mut(),
+			#[cfg(feature = "zstd")]
+			Decoder::Zstd(s) => s.get_mut().get_mut(),
 		}
 	}
 }
 
 #[cfg(feature = "compress")]
-impl<'r> BufRead for Gzip<'r, Chunked<'r>> {
-	fn fill_buf(&mut self) -> IoResult<&[u8]> {
+impl<T: Transport> GetRef for Decoder<T> {
+	fn get_ref(&self) -> &Stream {
 		match self {
-			Gzip::De(s) => s.get_mut().fill_buf(),
-			Gzip::En((p, _)) => p.get_mut().fill_buf(),
+			Decoder::Gzip(s) => s.get_ref().get_ref(),
+			#[cfg(feature = "deflate")]
+			Decoder::Deflate(s) => s.get_ref().get_ref(),
+			#[cfg(feature = "brotli")]
+			Decoder::Brotli(s) => s.get_ref().get_ref(),
+			#[cfg(feature = "zstd")]
+			Decoder::Zstd(s) => s.get_ref().get_ref().get_ref(),
 		}
 	}
+}
 
-	fn consume(&mut self, amt: usize) {
-		match self {
-			Gzip::De(s) => s.get_mut().consume(amt),
-			Gzip::En((p, _)) => p.get_mut().consume(amt),
-		}
+/// Encodes as it's written, into the transfer layer.
+#[cfg(feature = "compress")]
+pub(crate) enum Encoder<T: Transport> {
+	Gzip(GzEncoder<Chunked<T>>),
+	#[cfg(feature = "deflate")]
+	Deflate(ZlibEncoder<Chunked<T>>),
+	// Boxing for size variation.
+	#[cfg(feature = "brotli")]
+	Brotli(Box<CompressorWriter<Chunked<T>>>),
+	#[cfg(feature = "zstd")]
+	Zstd(ZstdEncoder<'static, Chunked<T>>),
+}
+
+#[cfg(feature = "compress")]
+implwrite!(Encoder, T, (Gzip, #[cfg(feature = "deflate")] Deflate, #[cfg(feature = "brotli")] Brotli, #[cfg(feature = "zstd")] Zstd));
+
+#[cfg(feature = "compress")]
+impl<T: Transport> Read for Encoder<T> {
+	// This should never be called.
+	fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+		self.transfer().read(buf)
 	}
 }
 
 #[cfg(feature = "compress")]
-impl<'r> GetRefMut for Gzip<'r, Chunked<'r>> {
-	fn get_ref(&self) -> &Stream {
+impl<T: Transport> Encoder<T> {
+	/// Levels beyond what a codec supports are capped to its best.
+	fn new(stream: Chunked<T>, coding: Coding, level: u32) -> IoResult<Encoder<T>> {
+		Ok(match coding {
+			Coding::Gzip => Encoder::Gzip(GzEncoder::new(stream, Compression::new(level.min(9)))),
+			#[cfg(feature = "deflate")]
+			Coding::Deflate => Encoder::Deflate(ZlibEncoder::new(stream, Compression::new(level.min(9)))),
+			#[cfg(feature = "brotli")]
+			Coding::Brotli => Encoder::Brotli(Box::new(CompressorWriter::new(stream, CHUNK_SIZE, level.min(11), 22))),
+			#[cfg(feature = "zstd")]
+			Coding::Zstd => {
+				let mut encoder = ZstdEncoder::new(stream, level.min(22) as i32)?;
+
+				// Only levels past 19 go beyond the window decoders are held to.
+				if level > 19 {
+					encoder.window_log(ZSTD_WINDOW_LOG_MAX)?;
+				}
+
+				Encoder::Zstd(encoder)
+			},
+		})
+	}
+
+	fn transfer(&mut self) -> &mut Chunked<T> {
+		match self {
+			Encoder::Gzip(s) => s.get_mut(),
+			#[cfg(feature = "deflate")]
+			Encoder::Deflate(s) => s.get_mut(),
+			#[cfg(feature = "brotli")]
+			Encoder::Brotli(s) => s.get_mut(),
+			#[cfg(feature = "zstd")]
+			Encoder::Zstd(s) => s.get_mut(),
+		}
+	}
+
+	/// Writes what's left of the coding (e.g. the gzip trailer), handing the transfer layer back.
+	fn finish(self) -> IoResult<Chunked<T>> {
 		match self {
-			Gzip::De(s) => s.get_ref().get_ref(),
-			Gzip::En((p, _)) => p.get_ref(),
+			Encoder::Gzip(s) => s.finish(),
+			#[cfg(feature = "deflate")]
+			Encoder::Deflate(s) => s.finish(),
+			#[cfg(feature = "brotli")]
+			Encoder::Brotli(s) => Ok(s.into_inner()),
+			#[cfg(feature = "zstd")]
+			Encoder::Zstd(s) => s.finish(),
 		}
 	}
+}
 
-	fn get_mut(&mut self) -> &mut Stream {
+#[cfg(feature = "compress")]
+impl<T: Transport> GetRef for Encoder<T> {
+	fn get_ref(&self) -> &Stream {
 		match self {
-			Gzip::De(s) => s.get_mut().get_mut(),
-			Gzip::En((p, _)) => p.get_mut(),
+			Encoder::Gzip(s) => s.get_ref().get_ref(),
+			#[cfg(feature = "deflate")]
+			Encoder::Deflate(s) => s.get_ref().get_ref(),
+			#[cfg(feature = "brotli")]
+			Encoder::Brotli(s) => s.get_ref().get_ref(),
+			#[cfg(feature = "zstd")]
+			Encoder::Zstd(s) => s.get_ref().get_ref(),
 		}
 	}
 }
 
 
 
-pub(crate) enum Compressed<'r> {
-	Non(&'r mut Chunked<'r>),
+/// Content codings on top of the transfer layer, which they own.
+pub(crate) enum Compressed<T: Transport> {
+	Non(Chunked<T>),
+	#[cfg(feature = "compress")]
+	De(Decoder<T>),
 	#[cfg(feature = "compress")]
-	Is(Gzip<'r, Chunked<'r>>),
+	En(Encoder<T>),
 }
 
-implread!(Compressed, 'r, (Non, #[cfg(feature = "compress")] Is));
-implbufread!(Compressed, 'r, (Non, #[cfg(feature = "compress")] Is));
-implwrite!(Compressed, 'r, (Non, #[cfg(feature = "compress")] Is));
+implread!(Compressed, T, (Non, #[cfg(feature = "compress")] De, #[cfg(feature = "compress")] En));
+implwrite!(Compressed, T, (Non, #[cfg(feature = "compress")] De, #[cfg(feature = "compress")] En));
 
-impl<'r> Compressed<'r> {
+impl<T: Transport> Compressed<T> {
+	/// Decodes `codings`, listed in the order they were applied.
 	#[cfg(feature = "compress")]
-	pub fn new(stream: &'r mut Chunked<'r>, comp_level: Option<u32>, data: Option<&'r [u8]>, compressed: bool) -> Compressed<'r> {
-		if compressed {
-			if let Some(level) = comp_level {
-				Compressed::Is(Gzip::En((stream, GzEncoder::new(data.unwrap(), Compression::new(level)))))
-			} else {
-				Compressed::Is(Gzip::De(GzDecoder::new(stream)))
-			}
-		} else {
-			Compressed::Non(stream)
+	pub(crate) fn decoder(stream: Chunked<T>, codings: &[Coding]) -> IoResult<Compressed<T>> {
+		let mut compressed = Compressed::Non(stream);
+
+		// The last coding applied is the first one off.
+		for coding in codings.iter().rev() {
+			compressed = Compressed::De(Decoder::new(compressed, *coding)?);
+		}
+
+		Ok(compressed)
+	}
+
+	#[cfg(not(feature = "compress"))]
+	pub(crate) fn decoder(stream: Chunked<T>, _: &[Coding]) -> IoResult<Compressed<T>> {
+		Ok(Compressed::Non(stream))
+	}
+
+	/// Encodes with `coding` at the given level, if there's one.
+	#[cfg(feature = "compress")]
+	pub(crate) fn encoder(stream: Chunked<T>, coding: Option<(Coding, u32)>) -> IoResult<Compressed<T>> {
+		match coding {
+			Some((coding, level)) => Ok(Compressed::En(Encoder::new(stream, coding, level)?)),
+			None => Ok(Compressed::Non(stream)),
 		}
 	}
 
 	#[cfg(not(feature = "compress"))]
-	pub(crate) fn new(stream: &'r mut Chunked<'r>, _: Option<u32>, _: Option<&'r [u8]>, _: bool) -> Compressed<'r> {
-		Compressed::Non(stream)
+	pub(crate) fn encoder(stream: Chunked<T>, _: Option<(Coding, u32)>) -> IoResult<Compressed<T>> {
+		Ok(Compressed::Non(stream))
+	}
+
+	/// Transfer layer underneath any content coding.
+	pub(crate) fn transfer(&mut self) -> &mut Chunked<T> {
+		match self {
+			Compressed::Non(s) => s,
+			#[cfg(feature = "compress")]
+			Compressed::De(s) => s.inner().transfer(),
+			#[cfg(feature = "compress")]
+			Compressed::En(s) => s.transfer(),
+		}
+	}
+
+	/// Ends an outgoing body: writes what's left of the content coding, then terminates the transfer layer.
+	pub(crate) fn finish(self) -> IoResult<()> {
+		match self {
+			#[cfg(feature = "compress")]
+			Compressed::En(s) => s.finish()?.finish(),
+			mut s => s.transfer().finish(),
+		}
 	}
 }
 
-implgets!(Compressed, 'r, (Non, #[cfg(feature = "compress")] Is));
+implgets!(Compressed, T, (Non, #[cfg(feature = "compress")] De, #[cfg(feature = "compress")] En));
 
 
 
 // -----------------------------------------------------------------------------------------------------------
 
 #[cfg(feature = "client")]
-pub(crate) fn connect<A: ToSocketAddrs + Display>(host: A, deadline: Option<(Instant, Instant)>) -> Result<TcpStream, Error> {
+pub(crate) fn connect<A: ToSocketAddrs + Display>(host: A, timeout: Option<Duration>) -> Result<TcpStream, Error> {
 	let ips: Vec<SocketAddr> = host.to_socket_addrs()
 		.map_err(|e| Error::ConnectionFailed(format!("{}", e)))?
 		.collect();
@@ -418,10 +705,8 @@ pub(crate) fn connect<A: ToSocketAddrs + Display>(host: A, deadline: Option<(Ins
 
 	let sock_addr = ips[0];
 
-	if let Some((deadline, _)) = deadline {
-		let now = Instant::now();
-
-		Ok(TcpStream::connect_timeout(&sock_addr, deadline - now)?)
+	if let Some(timeout) = timeout {
+		Ok(TcpStream::connect_timeout(&sock_addr, timeout)?)
 	} else {
 		Ok(TcpStream::connect(sock_addr)?)
 	}
@@ -429,29 +714,184 @@ pub(crate) fn connect<A: ToSocketAddrs + Display>(host: A, deadline: Option<(Ins
 
 
 
-fn write_until(stream: &mut Compressed<'_>, req: &[u8], deadline: &mut Option<(Instant, Instant)>) -> Result<usize, Error> {
-	if let Some((line, reset)) = deadline {
-		// Having a deadline guarantees a deadline_reset.
+/// Chunk size for bodies sent without a known length.
+pub(crate) const CHUNK_SIZE: usize = 8 * 1024;
+
+/// Longest extensions (in bytes) a chunk read may carry, unless set otherwise.
+pub(crate) const MAX_CHUNK_EXTENSION: usize = 256;
+
+/// Largest zstd window (as a power of two) a decoder sets aside memory for, as the sender picks it.
+/// Senders of the zstd content coding keep to 8 MiB (RFC 9659, section 3).
+#[cfg(feature = "zstd")]
+const ZSTD_WINDOW_LOG_MAX: u32 = 23;
+
+
+
+/// Time reads get before a minimum rate starts being enforced.
+#[cfg(feature = "server")]
+const RATE_GRACE: Duration = Duration::from_secs(2);
+
+/// Absolute deadline, and/or a cap on how long any single read or write may block.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Deadline {
+	line: Option<Instant>,
+	// When the socket read/write timeouts were last armed.
+	read_reset: Instant,
+	write_reset: Instant,
+	read_idle: Option<Duration>,
+	write_idle: Option<Duration>,
+	// Slowest average reads allowed (bytes a second), and when what's been read so far stops covering for it.
+	rate: Option<(u64, Instant)>,
+	// When the last read returned. Time until the next one isn't the peer's to make up for.
+	paused: Option<Instant>,
+}
+
+impl Deadline {
+	/// Returns None when there's nothing to enforce.
+	pub(crate) fn new(line: Option<Instant>, read_idle: Option<Duration>, write_idle: Option<Duration>) -> Option<Deadline> {
+		if line.is_none() && read_idle.is_none() && write_idle.is_none() {
+			return None;
+		}
+
+		Some(Deadline::armed(line, read_idle, write_idle))
+	}
+
+	fn armed(line: Option<Instant>, read_idle: Option<Duration>, write_idle: Option<Duration>) -> Deadline {
+		let now = Instant::now();
+
+		Deadline {
+			line,
+			read_reset: now,
+			write_reset: now,
+			read_idle,
+			write_idle,
+			rate: None,
+			paused: None,
+		}.rearmed()
+	}
+
+	/// Also requires reads to average at least `min` bytes a second, after a short grace period.
+	/// Every `min` bytes read buys another second, and only time spent waiting in reads is counted.
+	#[cfg(feature = "server")]
+	pub(crate) fn with_min_rate(deadline: Option<Deadline>, min: Option<u64>) -> Option<Deadline> {
+		let min = match min.filter(|&min| min > 0) {
+			Some(min) => min,
+			None => return deadline,
+		};
+
+		let mut deadline = deadline.unwrap_or_else(|| Deadline::armed(None, None, None));
+		deadline.rate = Some((min, Instant::now() + RATE_GRACE));
+
+		Some(deadline)
+	}
+
+	/// Counts bytes read towards the minimum rate.
+	fn transferred(&mut self, n: usize) {
+		if let Some((min, covered)) = &mut self.rate {
+			*covered += Duration::from_secs_f64(n as f64 / *min as f64);
+			self.paused = Some(Instant::now());
+		}
+	}
+
+	/// Excuses the time since the last read from the minimum rate, as the reader was busy elsewhere.
+	fn resumed(&mut self) {
+		if let (Some((_, covered)), Some(paused)) = (&mut self.rate, self.paused.take()) {
+			*covered += paused.elapsed();
+		}
+	}
+
+	/// Whether the absolute deadline has passed.
+	#[cfg(feature = "client")]
+	pub(crate) fn passed(&self) -> bool {
+		self.line.is_some_and(|line| line <= Instant::now())
+	}
+
+	/// Time left before the absolute deadline, if there is one.
+	#[cfg(feature = "client")]
+	pub(crate) fn remaining(&self) -> Option<Duration> {
+		self.line.map(|line| line.saturating_duration_since(Instant::now()))
+	}
+
+	/// Makes the next read and write re-arm the socket timeouts, after something else changed them.
+	/// Resets are backdated so the very next read or write is due.
+	pub(crate) fn rearm(&mut self) {
+		let now = Instant::now();
+		let due = now.checked_sub(Duration::from_millis(250)).unwrap_or(now);
+
+		self.read_reset = due;
+		self.write_reset = due;
+	}
+
+	fn rearmed(mut self) -> Deadline {
+		self.rearm();
+
+		self
+	}
+}
+
+/// Deadline for a phase with its own budget, never running past the overall deadline.
+/// Budgets too large to be counted from now are none at all.
+#[cfg(feature = "server")]
+pub(crate) fn deadline(budget: Option<Duration>, overall: Option<Instant>) -> Option<Deadline> {
+	let line = match (budget.and_then(|budget| Instant::now().checked_add(budget)), overall) {
+		(Some(phase), Some(overall)) => Some(phase.min(overall)),
+		(phase, overall) => phase.or(overall),
+	};
+
+	Deadline::new(line, None, None)
+}
+
+/// Socket timeout to arm, if it's due to be re-armed (at most every 250ms).
+/// Reads and writes are also capped by their idle timeouts.
+fn time_left(deadline: &mut Option<Deadline>, read: bool) -> Result<Option<Duration>, Error> {
+	if let Some(deadline) = deadline {
+		let reset = if read {
+			&mut deadline.read_reset
+		} else {
+			&mut deadline.write_reset
+		};
+
 		if reset.elapsed() >= Duration::from_millis(250) {
 			let now = Instant::now();
 
-			if *line <= now {
-				return Err(Error::Io(IoError::new(ErrorKind::TimedOut, "Connection timed out")));
-			}
+			// Reads also stop once the minimum rate is no longer met.
+			let line = match (deadline.line, deadline.rate.filter(|_| read)) {
+				(Some(line), Some((_, covered))) => Some(line.min(covered)),
+				(line, rate) => line.or(rate.map(|(_, covered)| covered)),
+			};
 
-			stream
-				.get_ref()
-				.get_ref()
-				.set_write_timeout(Some(*line - now))?;
+			let left = match line {
+				Some(line) if line <= now => return Err(Error::Io(IoError::new(ErrorKind::TimedOut, "Connection timed out"))),
+				Some(line) => Some(line - now),
+				None => None,
+			};
 
-			*deadline = Some((*line, Instant::now()));
+			*reset = now;
+
+			let idle = if read { deadline.read_idle } else { deadline.write_idle };
+
+			return Ok(match (left, idle) {
+				(Some(left), Some(idle)) => Some(left.min(idle)),
+				(left, idle) => left.or(idle),
+			});
 		}
 	}
 
+	Ok(None)
+}
+
+fn write_until<T: Transport>(stream: &mut Compressed<T>, req: &[u8], deadline: &mut Option<Deadline>) -> Result<usize, Error> {
+	if let Some(left) = time_left(deadline, false)? {
+		stream
+			.get_ref()
+			.get_ref()
+			.set_write_timeout(Some(left))?;
+	}
+
 	Ok(stream.write(req)?)
 }
 
-pub(crate) fn write_all_until(stream: &mut Compressed<'_>, mut req: &[u8], deadline: &mut Option<(Instant, Instant)>) -> Result<(), Error> {
+pub(crate) fn write_all_until<T: Transport>(stream: &mut Compressed<T>, mut req: &[u8], deadline: &mut Option<Deadline>) -> Result<(), Error> {
 	while !req.is_empty() {
 		let n = write_until(stream, req, deadline)?;
 
@@ -467,50 +907,164 @@ pub(crate) fn write_all_until(stream: &mut Compressed<'_>, mut req: &[u8], deadl
 	Ok(())
 }
 
+/// Streams everything from reader to the stream, within the deadline.
+pub(crate) fn copy_until<T: Transport>(reader: &mut dyn Read, stream: &mut Compressed<T>, deadline: &mut Option<Deadline>) -> Result<(), Error> {
+	let mut buf = vec![0; CHUNK_SIZE];
 
+	loop {
+		let n = match reader.read(&mut buf) {
+			Ok(0) => return Ok(()),
+			Ok(n) => n,
+			Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+			Err(e) => return Err(e.into()),
+		};
 
-pub(crate) fn read_until(stream: &mut Compressed<'_>, buf: &mut [u8], deadline: &mut Option<(Instant, Instant)>) -> Result<usize, Error> {
-	if let Some((line, reset)) = deadline {
-		// Having a deadline guarantees a deadline_reset.
-		if reset.elapsed() >= Duration::from_millis(250) {
-			let now = Instant::now();
+		write_all_until(stream, &buf[..n], deadline)?;
+	}
+}
 
-			if *line <= now {
-				return Err(Error::Io(IoError::new(ErrorKind::TimedOut, "Connection timed out")));
-			}
+/// Writes a message head (or an interim response) straight to the stream, within the deadline.
+pub(crate) fn write_head_until(stream: &mut Stream, head: &[u8], deadline: &mut Option<Deadline>) -> Result<(), Error> {
+	let mut compressed = Compressed::Non(Chunked::new(stream, None, false));
 
-			stream
-				.get_ref()
-				.get_ref()
-				.set_read_timeout(Some(*line - now))?;
+	write_all_until(&mut compressed, head, deadline)
+}
 
-			*deadline = Some((*line, Instant::now()));
-		}
+
+
+pub(crate) fn read_until<T: Transport>(stream: &mut Compressed<T>, buf: &mut [u8], deadline: &mut Option<Deadline>) -> Result<usize, Error> {
+	if let Some(deadline) = deadline {
+		deadline.resumed();
+	}
+
+	if let Some(left) = time_left(deadline, true)? {
+		stream
+			.get_ref()
+			.get_ref()
+			.set_read_timeout(Some(left))?;
 	}
 
 	match stream.read(buf) {
-		Ok(size) => Ok(size),
+		Ok(size) => {
+			if let Some(deadline) = deadline {
+				deadline.transferred(size);
+			}
+
+			Ok(size)
+		},
 		Err(ref e) if is_close_notify(e) => Ok(0),
-		Err(e) => Err(Error::Io(e)),
+		Err(e) => Err(e.into()),
+	}
+}
+
+/// Reader over a body that keeps to a deadline, for parsers that consume it as it arrives.
+pub(crate) struct ReadUntil<'a, T: Transport> {
+	stream: &'a mut Compressed<T>,
+	deadline: &'a mut Option<Deadline>,
+}
+
+impl<'a, T: Transport> ReadUntil<'a, T> {
+	pub(crate) fn new(stream: &'a mut Compressed<T>, deadline: &'a mut Option<Deadline>) -> ReadUntil<'a, T> {
+		ReadUntil {
+			stream,
+			deadline,
+		}
 	}
 }
 
-pub(crate) fn read_to_end_until(stream: &mut Compressed<'_>, body: &mut Vec<u8>, content_length: Option<usize>, deadline: &mut Option<(Instant, Instant)>) -> Result<(), Error> {
+impl<'a, T: Transport> Read for ReadUntil<'a, T> {
+	fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+		match read_until(self.stream, buf, self.deadline) {
+			Ok(size) => Ok(size),
+			Err(Error::Io(e)) => Err(e),
+			Err(e) => Err(e.into()),
+		}
+	}
+}
+
+/// Body left on the connection, read as it's asked for (within its deadline, and up to `max` decoded bytes).
+pub(crate) struct Pending<T: Transport> {
+	body: Compressed<T>,
+	deadline: Option<Deadline>,
+	read: usize,
+	max: Option<usize>,
+}
+
+impl<T: Transport> Pending<T> {
+	pub(crate) fn new(body: Compressed<T>, deadline: Option<Deadline>, max: Option<usize>) -> Pending<T> {
+		Pending {
+			body,
+			deadline,
+			read: 0,
+			max,
+		}
+	}
+
+	/// Discards whatever the reader left, so the connection can carry another message.
+	/// Fails with `Error::PayloadTooLarge` if the body went past `max`, read or not.
+	#[cfg(feature = "server")]
+	pub(crate) fn drain(&mut self) -> Result<(), Error> {
+		let mut buf = [0; 1024];
+
+		while self.read(&mut buf)? != 0 {}
+
+		drain_until(&mut self.body, &mut self.deadline)
+	}
+}
+
+impl<T: Transport> Read for Pending<T> {
+	fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+		if self.max.is_some_and(|max| self.read > max) {
+			return Err(Error::PayloadTooLarge.into());
+		}
+
+		let size = ReadUntil::new(&mut self.body, &mut self.deadline).read(buf)?;
+
+		self.read += size;
+
+		if self.max.is_some_and(|max| self.read > max) {
+			return Err(Error::PayloadTooLarge.into());
+		}
+
+		Ok(size)
+	}
+}
+
+/// Reads and discards whatever is left of a body, including transfer framing a decoder stopped short of.
+pub(crate) fn drain_until<T: Transport>(stream: &mut Compressed<T>, deadline: &mut Option<Deadline>) -> Result<(), Error> {
 	let mut buf = [0; 1024];
 
-	let mut cur_len = 0;
+	while read_until(stream, &mut buf, deadline)? != 0 {}
+
+	let transfer = stream.transfer();
+
+	loop {
+		if let Some(left) = time_left(deadline, true)? {
+			transfer
+				.get_ref()
+				.get_ref()
+				.set_read_timeout(Some(left))?;
+		}
+
+		if transfer.read(&mut buf)? == 0 {
+			break;
+		}
+	}
+
+	Ok(())
+}
+
+/// Reads the rest of a (decoded) body, failing once it grows past `max` bytes.
+pub(crate) fn read_to_end_until<T: Transport>(stream: &mut Compressed<T>, body: &mut Vec<u8>, max: Option<usize>, deadline: &mut Option<Deadline>) -> Result<(), Error> {
+	let mut buf = [0; 1024];
 
 	loop {
 		let n = read_until(stream, &mut buf, deadline)?;
 
 		body.extend_from_slice(&buf[..n]);
 
-		if let Some(length) = content_length {
-			cur_len += n;
-
-			if length == cur_len {
-				break;
-			}
+		if max.is_some_and(|max| body.len() > max) {
+			return Err(Error::PayloadTooLarge);
 		}
 
 		if n == 0 {
@@ -523,110 +1077,243 @@ pub(crate) fn read_to_end_until(stream: &mut Compressed<'_>, body: &mut Vec<u8>,
 
 
 
-pub(crate) fn process_lines(stream: &mut Stream) -> Result<GeneralInfo, Error> {
+/// How strictly message heads are parsed, and how large they may grow.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct HeadRules {
+	// Lines may end with a bare LF, rather than CRLF.
+	pub(crate) bare_lf: bool,
+	// Most header fields.
+	pub(crate) max_fields: Option<usize>,
+	// Most bytes, from the start line to the closing empty line.
+	pub(crate) max_size: Option<usize>,
+}
+
+pub(crate) fn process_lines(stream: &mut Stream, rules: HeadRules, deadline: &mut Option<Deadline>) -> Result<GeneralInfo, Error> {
 	let mut buf = vec![];
+	let mut left = rules.max_size.unwrap_or(usize::MAX);
 
-	read_line(stream, &mut buf, 8 * 1024)?;
+	// Empty lines before the start line are ignored (RFC 7230 section 3.5).
+	while buf.is_empty() {
+		left -= read_head_line(stream, &mut buf, left, rules, deadline)?;
+	}
 
 	// Get status line (if one exists).
-	let status = parse_status_line(&mut buf)?;
-	let mut headers = HashMap::new();
+	let status = parse_status_line(&buf)?;
+	let mut headers = Headers::new();
 
 	loop {
-		read_line(stream, &mut buf, 8 * 1024)?;
+		left -= read_head_line(stream, &mut buf, left, rules, deadline)?;
 
 		// We've hit the body.
 		if buf.is_empty() {
 			break;
 		}
 
-		if let Some(parsed) = parse_header(&buf) {
-			headers.insert(parsed.0, parsed.1);
+		if rules.max_fields.is_some_and(|max| headers.len() >= max) {
+			return Err(Error::HeadersTooLarge);
 		}
+
+		let (name, value) = parse_header(&buf)?;
+
+		// Repeated fields are all kept.
+		headers.append(name, value);
 	}
 
+	check_framing(&headers)?;
+
 	Ok(GeneralInfo {
 		status,
 		headers,
 	})
 }
 
-fn parse_status_line(line: &mut Vec<u8>) -> Result<StatusInfo, Error> {
-	let mut split = line.split(|&b| b == b' ').filter(|x| !x.is_empty());
+fn parse_status_line(line: &[u8]) -> Result<StatusInfo, Error> {
+	if line.starts_with(b"HTTP/") { // server response
+		// The reason phrase is everything after the code, spaces included.
+		let mut split = line.splitn(3, |&b| b == b' ');
+		let version = String::from_utf8_lossy(split.next().unwrap_or_default()).into_owned();
 
-	let method = split.nth(0).unwrap();
+		let code = split.next().filter(|code| !code.is_empty() && code.iter().all(u8::is_ascii_digit));
 
-	if let Some(code) = split.nth(0) {
-		if let Ok(code) = std::str::from_utf8(code) {
-			if let Ok(code) = code.parse::<i32>() { // server response
-				if let Some(reason) = split.next() {
-					if let Ok(reason) = std::str::from_utf8(reason) {
-						return Ok(StatusInfo::Response(code, reason.to_string()));
-					}
+		if let Some(code) = code.and_then(|code| std::str::from_utf8(code).ok()?.parse::<i32>().ok()) {
+			let reason = split.next().map(String::from_utf8_lossy).unwrap_or_default();
+
+			return Ok(StatusInfo::Response(version, code, reason.into_owned()));
+		}
+
+		return Err(Error::MalformedHead(Malformed::InvalidStartLine));
+	}
+
+	let mut split = line.split(|&b| b == b' ').filter(|x| !x.is_empty());
+	let first = split.next().unwrap_or_default();
+
+	if !first.is_empty() && first.iter().all(|&b| is_token(b)) { // client request
+		if let (Some(resource), Some(version), None) = (split.next(), split.next(), split.next()) {
+			if let (Ok(resource), Ok(version)) = (std::str::from_utf8(resource), std::str::from_utf8(version)) {
+				if version.starts_with("HTTP/") {
+					return Ok(StatusInfo::Request(String::from_utf8_lossy(first).into_owned(), resource.to_string(), version.to_string()));
 				}
-			} else { // client request
-				// code is technically resource here.
-				// TODO: Clean this up.
-				return Ok(StatusInfo::Request(std::str::from_utf8(method).unwrap().to_string(), code.to_string()));
 			}
 		}
 	}
 
-	Err(Error::NoStatusLineInResponse)
+	Err(Error::MalformedHead(Malformed::InvalidStartLine))
 }
 
-fn parse_header(line: &[u8]) -> Option<(String, String)> {
-	if let Some(idx) = line.iter().position(|&x| x == b':') {
-		let header = &line[..idx];
-		let val = if line[idx..].starts_with(&[b' ']) {
-			&line[idx + 2..]
-		} else {
-			&line[idx + 1..]
-		};
+fn parse_header(line: &[u8]) -> Result<(String, String), Error> {
+	// Continuation of the previous field (obs-fold), which could hide a header from whoever checked it before us.
+	if line.starts_with(b" ") || line.starts_with(b"\t") {
+		return Err(Error::MalformedHead(Malformed::FoldedHeader));
+	}
 
-		if let Ok(header) = std::str::from_utf8(header) {
-			if let Ok(val) = std::str::from_utf8(val) {
-				return Some((header.to_string(), val.trim().to_string()));
+	let idx = line
+		.iter()
+		.position(|&x| x == b':')
+		.ok_or(Error::MalformedHead(Malformed::InvalidHeader))?;
+	let name = &line[..idx];
+
+	if name.ends_with(b" ") || name.ends_with(b"\t") {
+		return Err(Error::MalformedHead(Malformed::WhitespaceBeforeColon));
+	}
+
+	if name.is_empty() || !name.iter().all(|&b| is_token(b)) {
+		return Err(Error::MalformedHead(Malformed::InvalidHeader));
+	}
+
+	let value = &line[idx + 1..];
+
+	// Peers that take a bare CR as a line break would see another field in the value.
+	if value.iter().any(|&b| b == b'\r' || b == b'\n' || b == 0) {
+		return Err(Error::MalformedHead(Malformed::InvalidHeader));
+	}
+
+	let value = String::from_utf8_lossy(value);
+
+	Ok((String::from_utf8_lossy(name).to_string(), value.trim().to_string()))
+}
+
+/// Rejects heads whose body could be delimited in more than one way (RFC 7230, section 3.3.3),
+/// as peers disagreeing on where a message ends lets requests be smuggled past them.
+fn check_framing(headers: &Headers) -> Result<(), Error> {
+	let lengths: Vec<&str> = headers
+		.get_all("Content-Length")
+		.flat_map(|value| value.split(','))
+		.map(str::trim)
+		.collect();
+
+	match lengths.as_slice() {
+		[] => (),
+		[length] => {
+			if length.is_empty() || !length.bytes().all(|b| b.is_ascii_digit()) || length.parse::<usize>().is_err() {
+				return Err(Error::MalformedHead(Malformed::InvalidContentLength));
 			}
+		},
+		_ => return Err(Error::MalformedHead(Malformed::DuplicateContentLength)),
+	}
+
+	if headers.contains_key("Transfer-Encoding") {
+		if !lengths.is_empty() {
+			return Err(Error::MalformedHead(Malformed::ConflictingLength));
+		}
+
+		let codings: Vec<&str> = headers.list("Transfer-Encoding").collect();
+		let chunked = codings.iter().filter(|s| s.eq_ignore_ascii_case("chunked")).count();
+
+		if chunked != 1 || !codings.last().is_some_and(|s| s.eq_ignore_ascii_case("chunked")) {
+			return Err(Error::MalformedHead(Malformed::InvalidTransferEncoding));
 		}
 	}
 
-	None
+	Ok(())
+}
+
+fn is_token(b: u8) -> bool {
+	b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
 }
 
 
 
-pub(crate) fn check_encodings(headers: &HashMap<String, String>) -> (bool, bool) {
-	let mut compression = false;
-	let mut chunking = false;
+/// Content codings (in the order they were applied), and whether the body is chunked.
+/// Codings are left empty if any of them can't be decoded, so the body is passed on as it came.
+pub(crate) fn check_encodings(headers: &Headers) -> (Vec<Coding>, bool) {
+	let codings = headers
+		.list("Content-Encoding")
+		.filter(|s| !s.eq_ignore_ascii_case("identity"))
+		.map(Coding::parse)
+		.collect::<Option<Vec<Coding>>>()
+		.unwrap_or_default();
 
-	if let Some(content) = headers.get("Content-Encoding") {
-		compression = content
-			.split(',')
-			.map(|s| s.trim())
-			.any(|s| s.eq_ignore_ascii_case("gzip"));
-	}
+	let chunking = headers
+		.list("Transfer-Encoding")
+		.any(|s| s.eq_ignore_ascii_case("chunked"));
 
-	if let Some(transfer) = headers.get("Transfer-Encoding") {
-		chunking = transfer
-			.split(',')
-			.map(|s| s.trim())
-			.any(|s| s.eq_ignore_ascii_case("chunked"));
-	}
+	(codings, chunking)
+}
 
-	(compression, chunking)
+/// Accept-Encoding naming every supported coding.
+#[cfg(all(feature = "client", feature = "compress"))]
+pub(crate) fn accept_encoding() -> String {
+	Coding::PREFERENCE
+		.iter()
+		.map(|coding| coding.name())
+		.collect::<Vec<&str>>()
+		.join(", ")
 }
 
-#[cfg(feature = "server")]
-pub(crate) fn check_accept(headers: &HashMap<String, String>) -> bool {
-	if let Some(accept) = headers.get("Accept-Encoding") {
-		return accept
-			.split(',')
-			.map(|s| s.trim())
-			.any(|s| s.eq_ignore_ascii_case("gzip"));
+/// Coding to answer with (RFC 7231, section 5.3.4): the supported one the client prefers most, ties going to the most compact.
+/// None if the client accepts none of them, or prefers identity outright.
+#[cfg(all(feature = "server", feature = "compress"))]
+pub(crate) fn check_accept(headers: &Headers) -> Option<Coding> {
+	let mut listed: Vec<(Coding, f32)> = vec![];
+	let mut identity = None;
+	let mut wildcard = None;
+
+	for entry in headers.list("Accept-Encoding") {
+		let mut params = entry.split(';');
+		let name = params.next().unwrap_or("").trim();
+
+		// Unparseable weights are taken as a refusal.
+		let q = params
+			.filter_map(|param| param.split_once('='))
+			.find(|(key, _)| key.trim().eq_ignore_ascii_case("q"))
+			.map_or(1.0, |(_, value)| value.trim().parse::<f32>().unwrap_or(0.0));
+
+		if name == "*" {
+			wildcard = wildcard.or(Some(q));
+		} else if name.eq_ignore_ascii_case("identity") {
+			identity = identity.or(Some(q));
+		} else if let Some(coding) = Coding::parse(name) {
+			// Aliases (x-gzip) name the same coding, and a refusal under either name stands.
+			match listed.iter_mut().find(|(listed, _)| *listed == coding) {
+				Some((_, weight)) if q == 0.0 => *weight = 0.0,
+				Some(_) => (),
+				None => listed.push((coding, q)),
+			}
+		}
 	}
 
-	false
+	let mut best: Option<(Coding, f32)> = None;
+
+	for &coding in Coding::PREFERENCE {
+		let q = listed
+			.iter()
+			.find(|(listed, _)| *listed == coding)
+			.map(|(_, q)| *q)
+			.or(wildcard)
+			.unwrap_or(0.0);
+
+		if q > 0.0 && best.map_or(true, |(_, best)| q > best) {
+			best = Some((coding, q));
+		}
+	}
+
+	let (coding, q) = best?;
+
+	if identity.is_some_and(|identity| identity > q) {
+		None
+	} else {
+		Some(coding)
+	}
 }
 
 
@@ -634,10 +1321,45 @@ pub(crate) fn check_accept(headers: &HashMap<String, String>) -> bool {
 // -----------------------------------------------------------------------------------------------------------
 // Helper functions
 
-fn read_line(stream: &mut Stream, mut buf: &mut Vec<u8>, max: u64) -> Result<usize, Error> {
+/// Reads a line of at most `max` bytes, re-arming the socket timeout between reads so a slow peer can't outlast the deadline.
+/// Longer lines fail with `PayloadTooLarge`.
+fn read_line(stream: &mut Stream, buf: &mut Vec<u8>, max: usize, deadline: &mut Option<Deadline>) -> Result<usize, Error> {
 	buf.clear();
 
-	let n = stream.take(max).read_until(b'\n', &mut buf)?;
+	loop {
+		if let Some(left) = time_left(deadline, true)? {
+			stream.get_ref().set_read_timeout(Some(left))?;
+		}
+
+		let available = match stream.fill_buf() {
+			Ok(available) => available,
+			Err(ref e) if is_close_notify(e) => &[],
+			Err(e) => return Err(Error::Io(e)),
+		};
+
+		if available.is_empty() {
+			break;
+		}
+
+		let (found, len) = match available.iter().position(|&b| b == b'\n') {
+			Some(idx) => (true, idx + 1),
+			None => (false, available.len()),
+		};
+		let len = std::cmp::min(len, max - buf.len());
+
+		buf.extend_from_slice(&available[..len]);
+		stream.consume(len);
+
+		if found || buf.len() == max {
+			break;
+		}
+	}
+
+	let n = buf.len();
+
+	if n == max && !buf.ends_with(b"\n") {
+		return Err(Error::PayloadTooLarge);
+	}
 
 	if buf.ends_with(&[b'\r', b'\n']) {
 		buf.truncate(buf.len() - 2);
@@ -650,13 +1372,30 @@ fn read_line(stream: &mut Stream, mut buf: &mut Vec<u8>, max: u64) -> Result<usi
 	Ok(n)
 }
 
-fn read_line_ending(stream: &mut Stream) -> IoResult<bool> {
+/// Reads a line of a message head (of at most `left` bytes), which has to end with CRLF unless bare LF is allowed.
+fn read_head_line(stream: &mut Stream, buf: &mut Vec<u8>, left: usize, rules: HeadRules, deadline: &mut Option<Deadline>) -> Result<usize, Error> {
+	let n = match read_line(stream, buf, left.min(8 * 1024), deadline) {
+		Err(Error::PayloadTooLarge) => return Err(Error::HeadersTooLarge),
+		res => res?,
+	};
+
+	if !rules.bare_lf && buf.len() + 2 != n {
+		return Err(Error::MalformedHead(Malformed::BareLineFeed));
+	}
+
+	Ok(n)
+}
+
+/// Reads the CRLF ending chunk data, or a bare LF if it's allowed.
+fn read_line_ending(stream: &mut Stream, bare_lf: bool) -> IoResult<bool> {
 	let mut b = [0];
 
 	stream.read_exact(&mut b)?;
 
 	if &b == b"\r" {
 		stream.read_exact(&mut b)?;
+	} else if !bare_lf {
+		return Ok(false);
 	}
 
 	Ok(&b == b"\n")
@@ -0,0 +1,74 @@
+/// Decodes percent-escapes (and `+` as space, for query strings and forms).
+/// Malformed escapes are kept as-is, invalid UTF-8 is replaced.
+pub(crate) fn decode(input: &str, plus_as_space: bool) -> String {
+	let bytes = input.as_bytes();
+	let mut decoded = Vec::with_capacity(bytes.len());
+	let mut idx = 0;
+
+	while idx < bytes.len() {
+		match bytes[idx] {
+			b'%' => {
+				// Only hex digits, as `from_str_radix` also takes a sign.
+				let hex = bytes.get(idx + 1..idx + 3)
+					.filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+					.and_then(|hex| std::str::from_utf8(hex).ok())
+					.and_then(|hex| u8::from_str_radix(hex, 16).ok());
+
+				if let Some(byte) = hex {
+					decoded.push(byte);
+					idx += 3;
+
+					continue;
+				}
+
+				decoded.push(b'%');
+			},
+			b'+' if plus_as_space => decoded.push(b' '),
+			byte => decoded.push(byte),
+		}
+
+		idx += 1;
+	}
+
+	String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Parses `a=1&b=2&a=3` into decoded pairs, in order.
+/// Keys without a value get an empty one.
+pub(crate) fn parse(input: &str) -> Vec<(String, String)> {
+	input
+		.split('&')
+		.filter(|pair| !pair.is_empty())
+		.map(|pair| {
+			let mut split = pair.splitn(2, '=');
+			let key = split.next().unwrap_or("");
+			let value = split.next().unwrap_or("");
+
+			(decode(key, true), decode(value, true))
+		})
+		.collect()
+}
+
+/// Encodes pairs as `application/x-www-form-urlencoded`.
+pub(crate) fn encode(pairs: &[(String, String)]) -> String {
+	pairs
+		.iter()
+		.map(|(key, value)| format!("{}={}", escape(key), escape(value)))
+		.collect::<Vec<String>>()
+		.join("&")
+}
+
+/// Percent-escapes everything but alphanumerics and `*-._`, with spaces as `+`.
+fn escape(input: &str) -> String {
+	let mut escaped = String::with_capacity(input.len());
+
+	for byte in input.bytes() {
+		match byte {
+			b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'*' | b'-' | b'.' | b'_' => escaped.push(byte as char),
+			b' ' => escaped.push('+'),
+			byte => escaped.push_str(&format!("%{:02X}", byte)),
+		}
+	}
+
+	escaped
+}